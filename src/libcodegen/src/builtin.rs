@@ -0,0 +1,172 @@
+use libvm::consts::*;
+use libvm::vm_type::Type;
+
+/// A function implemented by the vm instead of in bytecode
+#[derive(Debug)]
+pub struct Builtin {
+    pub name: &'static str,
+    /// Types of the arguments.  A `String` parameter accepts strings of any
+    /// length.
+    pub params: &'static [Type],
+    pub return_type: Type,
+    /// The instruction emitted after the arguments are pushed
+    pub code: &'static [u8],
+}
+
+impl Builtin {
+    /// Does an argument of type `arg` fit the parameter at `index`
+    pub fn accepts(&self, index: usize, arg: &Type) -> bool {
+        match (&self.params[index], arg) {
+            (Type::String(_), Type::String(_)) => true,
+            (param, arg) => param == arg,
+        }
+    }
+}
+
+static BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "print_int",
+        params: &[Type::I32],
+        return_type: Type::Void,
+        code: &[VIRTUAL, 0x00],
+    },
+    Builtin {
+        name: "debug",
+        params: &[],
+        return_type: Type::Void,
+        code: &[VIRTUAL, 0x01],
+    },
+    Builtin {
+        name: "print_str",
+        params: &[Type::String(0)],
+        return_type: Type::Void,
+        code: &[VIRTUAL, 0x02],
+    },
+    Builtin {
+        name: "print_float",
+        params: &[Type::F32],
+        return_type: Type::Void,
+        code: &[VIRTUAL, 0x03],
+    },
+    Builtin {
+        name: "input_int",
+        params: &[],
+        return_type: Type::I32,
+        code: &[VIRTUAL, 0x10],
+    },
+    Builtin {
+        name: "assert",
+        params: &[Type::Bool],
+        return_type: Type::Void,
+        code: &[ASSERT],
+    },
+    Builtin {
+        name: "int_to_str",
+        params: &[Type::I32],
+        // "-2147483648" plus the length prefix
+        return_type: Type::String(13),
+        code: &[I2S],
+    },
+    Builtin {
+        name: "float_to_str",
+        params: &[Type::F32],
+        // The longest f32 is the negative smallest subnormal
+        return_type: Type::String(50),
+        code: &[F2S],
+    },
+    // Overloads are picked by the type of the first argument
+    Builtin {
+        name: "min",
+        params: &[Type::I32, Type::I32],
+        return_type: Type::I32,
+        code: &[MIN_I],
+    },
+    Builtin {
+        name: "min",
+        params: &[Type::F32, Type::F32],
+        return_type: Type::F32,
+        code: &[MIN_F],
+    },
+    Builtin {
+        name: "max",
+        params: &[Type::I32, Type::I32],
+        return_type: Type::I32,
+        code: &[MAX_I],
+    },
+    Builtin {
+        name: "max",
+        params: &[Type::F32, Type::F32],
+        return_type: Type::F32,
+        code: &[MAX_F],
+    },
+    Builtin {
+        name: "abs",
+        params: &[Type::I32],
+        return_type: Type::I32,
+        code: &[ABS_I],
+    },
+    Builtin {
+        name: "abs",
+        params: &[Type::F32],
+        return_type: Type::F32,
+        code: &[ABS_F],
+    },
+    // Bounds of the number types, pushed as big-endian constants
+    Builtin {
+        name: "i32_max",
+        params: &[],
+        return_type: Type::I32,
+        code: &[PUSH_I, 0x7f, 0xff, 0xff, 0xff],
+    },
+    Builtin {
+        name: "i32_min",
+        params: &[],
+        return_type: Type::I32,
+        code: &[PUSH_I, 0x80, 0x00, 0x00, 0x00],
+    },
+    Builtin {
+        name: "u32_max",
+        params: &[],
+        return_type: Type::U32,
+        code: &[PUSH_I, 0xff, 0xff, 0xff, 0xff],
+    },
+    Builtin {
+        name: "f32_max",
+        params: &[],
+        return_type: Type::F32,
+        code: &[PUSH_I, 0x7f, 0x7f, 0xff, 0xff],
+    },
+    Builtin {
+        name: "f32_min",
+        params: &[],
+        return_type: Type::F32,
+        code: &[PUSH_I, 0xff, 0x7f, 0xff, 0xff],
+    },
+];
+
+/// Look up a builtin by name
+/// # Examples
+/// ```
+/// # use libcodegen::builtin::*;
+/// # use libvm::vm_type::Type;
+/// let builtin = lookup("int_to_str").unwrap();
+/// assert_eq!(builtin.params, &[Type::I32]);
+/// assert!(lookup("main").is_none());
+/// ```
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+/// Look up the overload of a builtin whose first parameter accepts `first`
+/// # Examples
+/// ```
+/// # use libcodegen::builtin::*;
+/// # use libvm::vm_type::Type;
+/// assert_eq!(overload("max", &Type::F32).unwrap().return_type, Type::F32);
+/// assert!(overload("max", &Type::Bool).is_none());
+/// ```
+pub fn overload(name: &str, first: &Type) -> Option<&'static Builtin> {
+    BUILTINS
+        .iter()
+        .find(|builtin| builtin.name == name && builtin.accepts(0, first))
+}
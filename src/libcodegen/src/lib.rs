@@ -1,3 +1,7 @@
+pub mod builtin;
 pub mod opcode;
 pub use opcode::*;
+pub mod optimize;
+pub mod repl;
 pub mod serialize;
+pub use serialize::{compile_to_object, run_object};
@@ -1,33 +1,239 @@
+use crate::builtin;
+use crate::optimize;
 use libparser::ast::*;
-use libparser::parse_context::ParseContext;
+use libparser::parse_context::{Diagnostic, ParseContext};
+use libparser::parser::Parser;
 use libvm::consts::*;
 use libvm::function::Function;
 use libvm::module::Module;
+use libvm::vm::VmError;
 use libvm::vm_type;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::rc::Rc;
+use std::sync::Once;
 
 pub struct OpcodeGenerator<'a> {
     input: &'a str,
-    var_map: HashMap<String, (u8, vm_type::Type)>,
-    var_index: u8,
-    break_me: Vec<usize>,
+    /// Variable name to register index, type and whether it is mutable
+    var_map: HashMap<String, (u8, vm_type::Type, bool)>,
+    /// Variables declared in the block currently being generated
+    block_vars: HashSet<String>,
+    /// Variables that are assigned on every path to the current position
+    assigned: HashSet<String>,
+    /// Next free register, registers are addressed by a single byte
+    var_index: usize,
+    /// The label and pending `break` jumps of each enclosing loop, innermost
+    /// last
+    loops: Vec<(Option<String>, Vec<usize>)>,
+    /// Type of the values broken out of the innermost loop, `None` outside of
+    /// a `loop` expression and `Some(None)` before its first `break`
+    break_type: Option<Option<vm_type::Type>>,
+    /// Return type of the function being generated
+    return_type: vm_type::Type,
     out: Vec<u8>,
+    /// Offsets in `out` of instructions that can fail at runtime and their spans
+    lines: Vec<(usize, (usize, usize))>,
     module: Rc<RefCell<Module>>,
     functions: HashMap<String, (usize, Statement)>,
     context: ParseContext<'a>,
+    num_constants: usize,
+    max_constants: usize,
+    max_functions: usize,
 }
 
+/// Variables carried over between separately generated snippets (used by the
+/// REPL)
+#[derive(Default, Clone)]
+pub(crate) struct Scope {
+    vars: HashMap<String, (u8, vm_type::Type, bool)>,
+    var_index: usize,
+}
+
+/// Most bytes a `str` parameter or return value can hold, it's registers are
+/// this plus the 2 byte length prefix
+pub const STR_LEN: usize = 64;
+
 fn ast_type_to_vm_type(t: &Type) -> vm_type::Type {
     match t {
         Type::Int => vm_type::Type::I32,
+        Type::UInt => vm_type::Type::U32,
         Type::Float => vm_type::Type::F32,
+        Type::Bool => vm_type::Type::Bool,
         Type::Void => vm_type::Type::Void,
-        Type::Str => vm_type::Type::String(0),
+        Type::Str => vm_type::Type::String(STR_LEN + 2),
+    }
+}
+
+/// Value of a constant expression evaluated at compile time
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Folded {
+    Int(i32),
+    Float(f32),
+}
+
+/// Does every path through the block end in a return
+fn block_returns(block: &Block) -> bool {
+    block.body.iter().any(statement_returns)
+}
+
+/// Does every path through the statement end in a return
+fn statement_returns(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Return(..) => true,
+        Statement::If(_, block, Some(next), ..) => block_returns(block) && statement_returns(next),
+        Statement::Else(block, ..) | Statement::Block(block) => block_returns(block),
+        // A loop without a break can only be left by returning
+        Statement::Loop(_, block) => !block_breaks(block),
+        // The body runs at least once, unless it breaks first
+        Statement::DoWhile(block, _) => block_returns(block) && !block_breaks(block),
+        _ => false,
+    }
+}
+
+/// Does the block break out of the loop it is in
+fn block_breaks(block: &Block) -> bool {
+    block.body.iter().any(|stmt| statement_breaks(stmt, false))
+}
+
+/// The span a diagnostic about the statement points at
+fn statement_span(stmt: &Statement) -> Option<libparser::span::Span> {
+    match stmt {
+        Statement::Assign(span, _)
+        | Statement::Const(span, _)
+        | Statement::Mutate(span, _)
+        | Statement::Return(_, span)
+        | Statement::For(span, ..)
+        | Statement::FnDecl { name: span, .. } => Some(*span),
+        Statement::If(.., span, _) | Statement::Else(_, span, _) => Some(*span),
+        Statement::Expression(expr) => expr.span(),
+        Statement::Loop(_, block) | Statement::Block(block) => {
+            block.body.iter().find_map(statement_span)
+        }
+        Statement::DoWhile(block, cond) => block
+            .body
+            .iter()
+            .find_map(statement_span)
+            .or_else(|| cond.span()),
+        Statement::Break(.., span) => Some(*span),
+        Statement::Dummy => None,
+    }
+}
+
+/// Can the statement break out of the loop it is in.  Inside a nested loop
+/// only a labeled `break` can, so those are counted conservatively.
+fn statement_breaks(stmt: &Statement, nested: bool) -> bool {
+    let breaks = |block: &Block, nested| block.body.iter().any(|s| statement_breaks(s, nested));
+    match stmt {
+        Statement::Break(label, ..) => !nested || label.is_some(),
+        Statement::If(_, block, next, ..) => {
+            breaks(block, nested) || next.as_deref().is_some_and(|s| statement_breaks(s, nested))
+        }
+        Statement::Else(block, ..) | Statement::Block(block) => breaks(block, nested),
+        Statement::Loop(_, block) | Statement::For(.., block) | Statement::DoWhile(block, _) => {
+            breaks(block, true)
+        }
+        _ => false,
     }
 }
 
+/// Report an error returned by the vm at the source span it came from, or with
+/// a dummy span if it isn't located
+/// ```
+/// # use libcodegen::opcode::*;
+/// # use libparser::parse_context::ParseContext;
+/// # use libvm::vm::VmError;
+/// let context = ParseContext::new("10 / 0");
+/// report_runtime_error(&context, &VmError::At(Box::new(VmError::DivisionByZero), (3, 4)));
+/// assert_eq!(context.errors()[0].span.pos, (3, 4));
+///
+/// report_runtime_error(&context, &VmError::StackOverflow);
+/// assert!(context.errors()[1].span.is_dummy);
+/// ```
+pub fn report_runtime_error(context: &ParseContext, error: &VmError) {
+    match error {
+        VmError::At(error, (start, end)) => context.error(
+            libparser::span::Span::new(*start, *end),
+            format!("Runtime error: {:?}", error).as_str(),
+        ),
+        error => context.error(
+            libparser::span::Span::dummy(),
+            format!("Runtime error: {:?}", error).as_str(),
+        ),
+    }
+}
+
+/// Parse and generate a whole program.  Returns the module, or every error
+/// reported while parsing or the error that stopped code generation.  Code
+/// generation reports an error by panicking, that panic is caught and isn't
+/// printed by the panic hook.
+/// ```
+/// # use libcodegen::opcode::*;
+/// let module = compile("fn main() -> i32 { return 2 + 3 }").unwrap();
+/// assert_eq!(module.borrow().run_function("main", vec![]), Ok(vec![0, 0, 0, 5]));
+///
+/// let errors = compile("fn main() { let = 1 }").unwrap_err();
+/// assert_eq!(errors[0].message, "Expected identifier");
+/// ```
+pub fn compile(input: &str) -> Result<Rc<RefCell<Module>>, Vec<Diagnostic>> {
+    let context = ParseContext::new(input);
+    let mut parser = Parser::new(input, &context);
+    let block = parser.parse_checked()?;
+
+    let mut gen = OpcodeGenerator::new(input);
+    gen.catch(|gen| gen.gen_module(&block))?;
+    Ok(gen.gen())
+}
+
+thread_local! {
+    /// Is the current thread generating code inside `catch_silently`
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Catch a panic from `f` without the panic hook printing it, the panic is
+/// already reported as a diagnostic.  Panics on other threads are printed as
+/// usual.
+fn catch_silently<R>(f: impl FnOnce() -> R) -> std::thread::Result<R> {
+    static INSTALL_HOOK: Once = Once::new();
+    INSTALL_HOOK.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !CATCHING.with(Cell::get) {
+                hook(info)
+            }
+        }));
+    });
+    let catching = CATCHING.with(|catching| catching.replace(true));
+    let out = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    CATCHING.with(|c| c.set(catching));
+    out
+}
+
+/// Replace the escape sequences in the body of a string literal
+fn unescape(val: &str) -> String {
+    let mut out = String::with_capacity(val.len());
+    let mut chars = val.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('0') => out.push('\0'),
+            Some(c @ ('\\' | '"')) => out.push(c),
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
 impl OpcodeGenerator<'_> {
     /// Creates a new Opcode Generator
     /// ```
@@ -35,22 +241,212 @@ impl OpcodeGenerator<'_> {
     /// # static INPUT: &str = "";
     /// let gen = OpcodeGenerator::new(INPUT);
     /// ```
-    pub fn new(input: &str) -> OpcodeGenerator {
+    pub fn new(input: &str) -> OpcodeGenerator<'_> {
         OpcodeGenerator {
             input,
             var_map: HashMap::new(),
+            block_vars: HashSet::new(),
+            assigned: HashSet::new(),
             var_index: 0,
-            break_me: Vec::new(),
+            loops: Vec::new(),
+            break_type: None,
+            return_type: vm_type::Type::Void,
             out: Vec::new(),
+            lines: Vec::new(),
             module: Rc::new(RefCell::new(Default::default())),
             functions: HashMap::new(),
             context: ParseContext::new(input),
+            num_constants: 0,
+            max_constants: u16::MAX as usize,
+            max_functions: u16::MAX as usize,
         }
     }
 
+    /// Print diagnostics to stderr as they are reported
+    /// ```
+    /// # use libcodegen::opcode::*;
+    /// # static INPUT: &str = "";
+    /// let gen = OpcodeGenerator::new(INPUT).with_printing();
+    /// ```
+    pub fn with_printing(mut self) -> Self {
+        self.context = std::mem::take(&mut self.context).with_printing();
+        self
+    }
+
+    /// Limit the number of constants and functions the generated module may
+    /// contain
+    /// ```
+    /// # use libcodegen::opcode::*;
+    /// # static INPUT: &str = "";
+    /// let gen = OpcodeGenerator::new(INPUT).with_limits(16, 4);
+    /// ```
+    pub fn with_limits(mut self, max_constants: usize, max_functions: usize) -> Self {
+        self.max_constants = max_constants;
+        self.max_functions = max_functions;
+        self
+    }
+
+    /// Add a constant to the module and return its index, erroring if the
+    /// constant pool is full
+    fn new_const(&mut self, val: &str, span: libparser::span::Span) -> usize {
+        let index = self.module.borrow_mut().new_const(val);
+        self.num_constants += 1;
+        if self.num_constants > self.max_constants || index > u16::MAX as usize {
+            let message = format!("Too many constants (limit is {})", self.max_constants);
+            self.context.error(span, message.as_str());
+            panic!("{}", message)
+        }
+        index
+    }
+
+    /// Add a string constant and load it
+    fn gen_ldc(&mut self, val: &str, span: libparser::span::Span) {
+        let index = self.new_const(val, span);
+        self.out.push(LDC);
+        self.out.extend(&(index as u16).to_be_bytes());
+    }
+
+    /// Reserve `size` bytes of registers and return the index of the first
+    fn alloc_register(&mut self, size: usize, span: libparser::span::Span) -> u8 {
+        match u8::try_from(self.var_index) {
+            Ok(index) => {
+                self.var_index += size;
+                index
+            }
+            Err(_) => {
+                self.context.error(span, "too many local variables");
+                panic!("too many local variables")
+            }
+        }
+    }
+
+    /// Push a jump to `target` and return the position of it's operand so it
+    /// can be backpatched
+    fn gen_jump(&mut self, op: u8, target: usize) -> usize {
+        self.out.push(op);
+        let operand = self.out.len();
+        self.out.extend(&[0, 0]);
+        self.patch_jump(operand, target);
+        operand
+    }
+
+    /// Set the target of the jump whose operand is at `operand`
+    fn patch_jump(&mut self, operand: usize, target: usize) {
+        let target = u16::try_from(target).expect("Jump target out of range");
+        self.out[operand..operand + 2].copy_from_slice(&target.to_be_bytes());
+    }
+
     fn to_str(&self, span: &libparser::span::Span) -> String {
         String::from(&self.input[span.pos.0..span.pos.1])
     }
+
+    /// The text of a number literal without it's type suffix
+    fn number_str(&self, span: &libparser::span::Span) -> String {
+        let text = self.to_str(span);
+        ["i32", "u32", "f32"]
+            .iter()
+            .find_map(|suffix| text.strip_suffix(suffix))
+            .map(String::from)
+            .unwrap_or(text)
+    }
+
+    /// Error if a builtin is called with the wrong number of arguments
+    fn check_arity(
+        &self,
+        span: &libparser::span::Span,
+        exprs: &[(Expression, libparser::span::Span)],
+        expected: usize,
+    ) {
+        if exprs.len() != expected {
+            let message = format!(
+                "`{}` expects {} argument(s), found {}",
+                self.to_str(span),
+                expected,
+                exprs.len()
+            );
+            self.context.error(*span, message.as_str());
+            panic!("{}", message)
+        }
+    }
+
+    /// Evaluate an arithmetic expression made only of number literals.  Returns
+    /// `None` if it can't be evaluated at compile time, including integer
+    /// division by zero which is left to error at runtime.
+    fn fold(&self, expr: &Expression) -> Option<Folded> {
+        match expr {
+            Expression::Literal {
+                val,
+                kind: LiteralKind::Int,
+            } => self.number_str(val).parse().ok().map(Folded::Int),
+            Expression::Literal {
+                val,
+                kind: LiteralKind::Float,
+            } => self.number_str(val).parse().ok().map(Folded::Float),
+            Expression::Unary(Op::Minus, expr, _) => match &**expr {
+                // Parsed with the sign so `-2147483648` fits in an i32
+                Expression::Literal {
+                    val,
+                    kind: LiteralKind::Int,
+                } => format!("-{}", self.number_str(val))
+                    .parse()
+                    .ok()
+                    .map(Folded::Int),
+                expr => match self.fold(expr)? {
+                    Folded::Int(val) => Some(Folded::Int(val.wrapping_neg())),
+                    Folded::Float(val) => Some(Folded::Float(-val)),
+                },
+            },
+            Expression::Binary(lhs, op, rhs, _) => match (self.fold(lhs)?, self.fold(rhs)?) {
+                (Folded::Int(lhs), Folded::Int(rhs)) => match op {
+                    Op::Plus => Some(lhs.wrapping_add(rhs)),
+                    Op::Minus => Some(lhs.wrapping_sub(rhs)),
+                    Op::Star => Some(lhs.wrapping_mul(rhs)),
+                    Op::Slash if rhs != 0 => Some(lhs.wrapping_div(rhs)),
+                    Op::Mod if rhs != 0 => Some(lhs.wrapping_rem(rhs)),
+                    Op::Xor => Some(lhs ^ rhs),
+                    _ => None,
+                }
+                .map(Folded::Int),
+                (Folded::Float(lhs), Folded::Float(rhs)) => match op {
+                    Op::Plus => Some(lhs + rhs),
+                    Op::Minus => Some(lhs - rhs),
+                    Op::Star => Some(lhs * rhs),
+                    Op::Slash => Some(lhs / rhs),
+                    Op::Mod => Some(lhs % rhs),
+                    _ => None,
+                }
+                .map(Folded::Float),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Push a folded constant
+    fn gen_folded(&mut self, folded: Folded) -> vm_type::Type {
+        self.out.push(PUSH_I);
+        match folded {
+            Folded::Int(val) => {
+                self.out.extend(vm_type::encode_i32(val).iter());
+                vm_type::Type::I32
+            }
+            Folded::Float(val) => {
+                self.out.extend(vm_type::encode_f32(val).iter());
+                vm_type::Type::F32
+            }
+        }
+    }
+
+    /// Is the expression an integer literal equal to zero
+    fn is_zero_literal(&self, expr: &Expression) -> bool {
+        match expr {
+            Expression::Literal {
+                val,
+                kind: LiteralKind::Int,
+            } => self.number_str(val).parse::<i32>() == Ok(0),
+            _ => false,
+        }
+    }
     /// Clones the generated module and returns a reference to it.
     /// ```
     /// # use libcodegen::opcode::*;
@@ -64,6 +460,41 @@ impl OpcodeGenerator<'_> {
         Rc::clone(&self.module)
     }
 
+    /// Run a step of code generation, returning the reported errors if it
+    /// fails.  Code generation stops at the first error by panicking after
+    /// reporting it.
+    pub(crate) fn catch(&mut self, f: impl FnOnce(&mut Self)) -> Result<(), Vec<Diagnostic>> {
+        let generated = catch_silently(|| f(self));
+        if let Err(panic) = generated {
+            if self.context.errors().is_empty() {
+                let message = panic
+                    .downcast_ref::<String>()
+                    .map(String::as_str)
+                    .or_else(|| panic.downcast_ref::<&str>().copied())
+                    .unwrap_or("Code generation failed");
+                self.context.error(libparser::span::Span::dummy(), message);
+            }
+            return Err(self.context.errors());
+        }
+        Ok(())
+    }
+
+    /// Returns the variables declared so far
+    pub(crate) fn scope(&self) -> Scope {
+        Scope {
+            vars: self.var_map.clone(),
+            var_index: self.var_index,
+        }
+    }
+
+    /// Continue generating with the variables of a previous generator
+    pub(crate) fn set_scope(&mut self, scope: Scope) {
+        self.block_vars = scope.vars.keys().cloned().collect();
+        self.assigned = self.block_vars.clone();
+        self.var_map = scope.vars;
+        self.var_index = scope.var_index;
+    }
+
     /// Get current output buffer
     pub fn out(&self) -> Vec<u8> {
         self.out.clone()
@@ -105,32 +536,62 @@ impl OpcodeGenerator<'_> {
                         self.context.error(*span, "Function already exists");
                         panic!()
                     } else {
-                        let index = self.module.borrow_mut().new_const(name.clone().as_str());
+                        if self.functions.len() >= self.max_functions {
+                            let message =
+                                format!("Too many functions (limit is {})", self.max_functions);
+                            self.context.error(*span, message.as_str());
+                            panic!("{}", message)
+                        }
+                        if !matches!(return_type, Type::Void) && !block_returns(block) {
+                            self.context.error(*span, "missing return");
+                            panic!("missing return")
+                        }
+                        let index = self.new_const(name.as_str(), *span);
                         self.functions.insert(name.clone(), (index, stmt.clone()));
                         let args: Vec<vm_type::Type> = args
                             .iter()
                             .map(|v| match v {
                                 Ident::Typed(span, arg_type) => {
-                                    let arg_type = ast_type_to_vm_type(&arg_type);
-                                    self.var_map.insert(
-                                        self.to_str(&span),
-                                        (self.var_index, arg_type.clone()),
-                                    );
-                                    self.var_index += 4;
+                                    let arg_type = ast_type_to_vm_type(arg_type);
+                                    self.block_vars.insert(self.to_str(span));
+                                    self.assigned.insert(self.to_str(span));
+                                    let size = match arg_type {
+                                        vm_type::Type::Bool => 1,
+                                        vm_type::Type::String(size) => size,
+                                        _ => 4,
+                                    };
+                                    let index = self.alloc_register(size, *span);
+                                    self.var_map
+                                        .insert(self.to_str(span), (index, arg_type.clone(), true));
                                     arg_type
                                 }
                                 _ => unimplemented!(),
                             })
                             .collect();
                         self.gen_block(block, ast_type_to_vm_type(return_type));
-                        let instructions = self.out.clone();
+                        // Only void functions can reach the end of their body,
+                        // the others were checked for a missing return above
+                        if !block_returns(block) {
+                            self.out.push(RET_V);
+                        }
+                        let mut instructions = self.out.clone();
+                        optimize::thread_jumps(&mut instructions);
+                        let (instructions, new_index) = optimize::peephole_with_map(&instructions);
+                        let lines = self
+                            .lines
+                            .iter()
+                            .map(|(offset, span)| (new_index[*offset], *span))
+                            .collect();
+                        let locals = self.var_index;
                         self.reset();
                         let func = Function::new(
                             instructions,
                             args,
                             ast_type_to_vm_type(return_type),
                             Rc::clone(&self.module),
-                        );
+                        )
+                        .with_lines(lines)
+                        .with_locals(locals);
                         self.module.borrow_mut().push_fn(index, func);
                     }
                 }
@@ -142,8 +603,12 @@ impl OpcodeGenerator<'_> {
     /// Reset after generating a function
     fn reset(&mut self) {
         self.out.clear();
-        self.break_me.clear();
+        self.lines.clear();
+        self.loops.clear();
+        self.break_type = None;
         self.var_map.clear();
+        self.block_vars.clear();
+        self.assigned.clear();
         self.var_index = 0;
     }
 
@@ -172,32 +637,56 @@ impl OpcodeGenerator<'_> {
     /// ])
     /// ```
     pub fn gen_block(&mut self, block: &Block, return_type: vm_type::Type) {
-        for stmt in block.body.iter() {
+        self.return_type = return_type.clone();
+        for (i, stmt) in block.body.iter().enumerate() {
+            if i > 0
+                && matches!(
+                    block.body[i - 1],
+                    Statement::Return(..) | Statement::Break(..)
+                )
+            {
+                // Nothing after a return or break can run
+                if let Some(span) = block.body[i..].iter().find_map(statement_span) {
+                    self.context.warn(span, "Unreachable code");
+                }
+                break;
+            }
             match stmt {
                 Statement::Expression(expr) => {
                     self.gen_expr(expr);
                 }
-                Statement::Assign(name, expr) => {
+                Statement::Assign(name, expr) | Statement::Const(name, expr) => {
+                    let mutable = matches!(stmt, Statement::Assign(..));
                     let var_type = self.gen_expr(expr);
+                    let span = *name;
                     let name = self.to_str(name);
 
                     self.out.push(match var_type {
-                        vm_type::Type::I32 | vm_type::Type::F32 => STO_I,
+                        vm_type::Type::I32 | vm_type::Type::U32 => STO_I,
+                        vm_type::Type::F32 => STO_F,
                         vm_type::Type::String(_) => STO_V,
+                        vm_type::Type::Bool => STO_B,
                         _ => NOP,
                     });
 
-                    if let Some((index, _)) = self.var_map.get(&name) {
+                    self.assigned.insert(name.clone());
+                    let in_block = self.block_vars.contains(&name);
+                    let existing = self.var_map.get_mut(&name).filter(|_| in_block);
+                    if let Some((index, _, var_mutable)) = existing {
+                        *var_mutable = mutable;
                         self.out.push(*index);
                     } else {
-                        self.var_map
-                            .insert(name, (self.var_index, var_type.clone()));
-                        self.out.push(self.var_index);
-                        self.var_index += match var_type {
-                            vm_type::Type::I32 | vm_type::Type::F32 => 4,
-                            vm_type::Type::String(len) => len as u8,
+                        let size = match var_type {
+                            vm_type::Type::I32 | vm_type::Type::U32 | vm_type::Type::F32 => 4,
+                            vm_type::Type::String(len) => len,
+                            vm_type::Type::Bool => 1,
                             vm_type::Type::Void => 0,
                         }; // FIXME: Detect string len
+                        let index = self.alloc_register(size, span);
+                        self.block_vars.insert(name.clone());
+                        self.var_map
+                            .insert(name, (index, var_type.clone(), mutable));
+                        self.out.push(index);
                     }
                 }
                 Statement::Mutate(name, expr) => {
@@ -205,38 +694,107 @@ impl OpcodeGenerator<'_> {
                     let span = name;
                     let name = self.to_str(span);
 
-                    self.out.push(STO_I);
-                    if let Some((index, _)) = self.var_map.get(&name) {
+                    if let Some((index, var_type, mutable)) = self.var_map.get(&name) {
+                        if !*mutable {
+                            let message = format!("Cannot assign twice to const `{}`", name);
+                            self.context.error(*span, message.as_str());
+                            panic!("{}", message)
+                        }
+                        self.out.push(match var_type {
+                            vm_type::Type::Bool => STO_B,
+                            vm_type::Type::F32 => STO_F,
+                            _ => STO_I,
+                        });
                         self.out.push(*index);
+                        self.assigned.insert(name);
                     } else {
                         self.context.error(*span, "Variable is undefined");
                         panic!();
                     }
                 }
-                Statement::If(expr, block, _next) => {
-                    self.gen_expr(expr);
-                    self.out.push(IF_F);
-                    let set_me = self.out.len();
-                    self.out.push(0);
+                Statement::If(expr, block, next, span, _) => {
+                    let set_me = self.gen_branch_if_false(expr, *span, "if");
 
+                    let assigned = self.assigned.clone();
+                    self.gen_block(block, return_type.clone());
+                    let if_assigned = std::mem::replace(&mut self.assigned, assigned);
+                    if let Some(next) = next {
+                        // Skip over the else branch after the if branch
+                        let end_me = self.gen_jump(GOTO, 0);
+                        self.patch_jump(set_me, self.out.len());
+                        self.gen_block(
+                            &Block {
+                                body: vec![*next.clone()],
+                            },
+                            return_type.clone(),
+                        );
+                        self.patch_jump(end_me, self.out.len());
+                        // Only variables assigned in every branch that falls
+                        // through are known to be assigned
+                        if statement_returns(next) {
+                            self.assigned = if_assigned;
+                        } else if !block_returns(block) {
+                            self.assigned.retain(|name| if_assigned.contains(name));
+                        }
+                    } else {
+                        self.patch_jump(set_me, self.out.len());
+                    }
+                }
+                Statement::Else(block, ..) => {
+                    self.gen_block(block, return_type.clone());
+                }
+                Statement::Loop(label, block) => {
+                    let label = label.as_ref().map(|label| self.to_str(label));
+                    self.loops.push((label, Vec::new()));
+                    let start = self.out.len();
+                    let assigned = self.assigned.clone();
+                    let break_type = self.break_type.take();
                     self.gen_block(block, return_type.clone());
-                    *self.out.get_mut(set_me).unwrap() = self.out.len() as u8;
+                    self.break_type = break_type;
+                    // The loop may be left before anything in it is assigned
+                    self.assigned = assigned;
+                    self.gen_jump(GOTO, start);
+                    self.exit_loop();
                 }
-                Statement::Loop(block) => {
+                Statement::DoWhile(block, cond) => {
+                    // Lowered to `loop { block; if !cond { break } }`
+                    self.loops.push((None, Vec::new()));
                     let start = self.out.len();
+                    let assigned = self.assigned.clone();
+                    let break_type = self.break_type.take();
                     self.gen_block(block, return_type.clone());
-                    self.out.push(GOTO);
-                    self.out.push(start as u8);
-                    let end = self.out.len();
-                    for i in self.break_me.iter() {
-                        *self.out.get_mut(*i).unwrap() = end as u8;
+                    self.break_type = break_type;
+                    // The body always runs once unless it breaks out early
+                    if block_breaks(block) {
+                        self.assigned = assigned;
                     }
-                    self.break_me.clear();
+                    let span = cond.span().unwrap_or_else(libparser::span::Span::dummy);
+                    let exit = self.gen_branch_if_false(cond, span, "while");
+                    self.loops.last_mut().unwrap().1.push(exit);
+                    self.gen_jump(GOTO, start);
+                    self.exit_loop();
+                }
+                Statement::For(name, range, block) => {
+                    self.gen_for(name, range, block, &return_type)
+                }
+                Statement::Block(block) => {
+                    // Variables declared inside the block go out of scope at the end of it
+                    let var_map = self.var_map.clone();
+                    let block_vars = std::mem::take(&mut self.block_vars);
+                    self.gen_block(block, return_type.clone());
+                    self.var_map = var_map;
+                    self.block_vars = block_vars;
                 }
                 Statement::Return(expr, span) => {
                     let expr_type = self.gen_expr(expr);
-                    self.out.push(RET_I);
-                    if expr_type != return_type {
+                    match expr_type {
+                        vm_type::Type::Void => self.out.push(RET_V),
+                        vm_type::Type::String(_) => self.out.push(RET_S),
+                        vm_type::Type::Bool => self.out.extend(&[RET, 1]),
+                        _ => self.out.push(RET_I),
+                    }
+                    let is_str = |t: &vm_type::Type| matches!(t, vm_type::Type::String(_));
+                    if expr_type != return_type && !(is_str(&expr_type) && is_str(&return_type)) {
                         self.context.error(
                             *span,
                             format!("Expected {:?} found {:?}", return_type, expr_type).as_str(),
@@ -244,16 +802,420 @@ impl OpcodeGenerator<'_> {
                         panic!()
                     }
                 }
-                Statement::Break => {
-                    self.out.push(GOTO);
-                    self.out.push(0);
-                    self.break_me.push(self.out.len() - 1);
+                Statement::Break(label, value, span) => {
+                    let target = self.break_target(label.as_ref(), *span);
+                    if label.is_some() {
+                        // Only `loop` statements are labeled
+                        if value.is_some() {
+                            let message =
+                                "`break` with a value can only be used in a `loop` expression";
+                            self.context.error(*span, message);
+                            panic!("{}", message)
+                        }
+                    } else {
+                        self.gen_break_value(value.as_ref(), *span);
+                    }
+                    let operand = self.gen_jump(GOTO, 0);
+                    self.loops[target].1.push(operand);
                 }
                 _ => unimplemented!(),
             }
         }
     }
 
+    /// Generate a `for` loop as a counting `loop`.  The end of the range is
+    /// evaluated once and kept in a hidden register.
+    fn gen_for(
+        &mut self,
+        name: &libparser::span::Span,
+        range: &Expression,
+        block: &Block,
+        return_type: &vm_type::Type,
+    ) {
+        let (start, end, span) = match range {
+            Expression::Range(start, end, span) => (start, end, span),
+            _ => {
+                self.context.error(*name, "Expected a range");
+                panic!("Expected a range")
+            }
+        };
+        let var_map = self.var_map.clone();
+        let block_vars = std::mem::take(&mut self.block_vars);
+        let assigned = self.assigned.clone();
+        let break_type = self.break_type.take();
+
+        let index = self.alloc_register(4, *name);
+        let end_index = self.alloc_register(4, *name);
+        for (bound, reg) in [(start, index), (end, end_index)] {
+            let bound = self.gen_expr(bound);
+            if bound != vm_type::Type::I32 {
+                let message = format!("Range bounds must be I32, found {:?}", bound);
+                self.context.error(*span, message.as_str());
+                panic!("{}", message)
+            }
+            self.out.extend(&[STO_I, reg]);
+        }
+        let name = self.to_str(name);
+        self.var_map
+            .insert(name.clone(), (index, vm_type::Type::I32, false));
+        self.assigned.insert(name);
+
+        self.loops.push((None, Vec::new()));
+        let start = self.out.len();
+        self.out.extend(&[LOAD_I, index, LOAD_I, end_index, CMP_I]);
+        let exit = self.gen_jump(IF_GE, 0);
+        self.gen_block(block, return_type.clone());
+        self.out.extend(&[LOAD_I, index, PUSH_I]);
+        self.out.extend(&vm_type::encode_i32(1));
+        self.out.extend(&[ADD_I, STO_I, index]);
+        self.gen_jump(GOTO, start);
+
+        self.patch_jump(exit, self.out.len());
+        self.exit_loop();
+        self.break_type = break_type;
+        self.var_map = var_map;
+        self.block_vars = block_vars;
+        self.assigned = assigned;
+    }
+
+    /// Generate a `loop` expression.  Each `break` leaves its value on the
+    /// stack and jumps past the end of the loop.
+    fn gen_loop_expr(&mut self, block: &Block) -> vm_type::Type {
+        self.loops.push((None, Vec::new()));
+        let break_type = self.break_type.replace(None);
+        let assigned = self.assigned.clone();
+
+        let start = self.out.len();
+        let return_type = self.return_type.clone();
+        self.gen_block(block, return_type);
+        self.gen_jump(GOTO, start);
+        self.exit_loop();
+        // The loop may be left before anything in it is assigned
+        self.assigned = assigned;
+        // A loop that is never broken out of can only be left by returning
+        std::mem::replace(&mut self.break_type, break_type)
+            .flatten()
+            .unwrap_or(vm_type::Type::Void)
+    }
+
+    /// Leave the innermost loop, pointing it's `break`s at the current position
+    fn exit_loop(&mut self) {
+        let (_, breaks) = self.loops.pop().expect("Not in a loop");
+        let end = self.out.len();
+        for i in breaks {
+            self.patch_jump(i, end);
+        }
+    }
+
+    /// The index in `loops` of the loop a `break` exits
+    fn break_target(
+        &mut self,
+        label: Option<&libparser::span::Span>,
+        span: libparser::span::Span,
+    ) -> usize {
+        let target = match label {
+            Some(label) => {
+                let name = self.to_str(label);
+                let target = self
+                    .loops
+                    .iter()
+                    .rposition(|(other, _)| other.as_ref() == Some(&name));
+                if target.is_none() {
+                    let message = format!("Use of undeclared label `{}`", name);
+                    self.context.error(*label, message.as_str());
+                    panic!("{}", message)
+                }
+                target
+            }
+            None => self.loops.len().checked_sub(1),
+        };
+        match target {
+            Some(target) => target,
+            None => {
+                self.context.error(span, "`break` outside of a loop");
+                panic!("`break` outside of a loop")
+            }
+        }
+    }
+
+    /// Generate the value of a `break` and check that it matches the other
+    /// `break`s of the same loop
+    fn gen_break_value(&mut self, value: Option<&Expression>, span: libparser::span::Span) {
+        let value = match (value, &self.break_type) {
+            (None, None) => return,
+            (Some(value), Some(_)) => value,
+            (Some(_), None) => {
+                let message = "`break` with a value can only be used in a `loop` expression";
+                self.context.error(span, message);
+                panic!("{}", message)
+            }
+            (None, Some(_)) => {
+                let message = "`break` in a `loop` expression must have a value";
+                self.context.error(span, message);
+                panic!("{}", message)
+            }
+        };
+        let value_type = self.gen_expr(value);
+        match self.break_type.as_mut().unwrap() {
+            Some(vm_type::Type::String(len)) => {
+                if let vm_type::Type::String(value_len) = value_type {
+                    // The loop's value must fit the longest string broken with
+                    *len = (*len).max(value_len);
+                    return;
+                }
+            }
+            Some(expected) if *expected == value_type => return,
+            expected @ None => {
+                *expected = Some(value_type);
+                return;
+            }
+            _ => {}
+        }
+        let message = format!(
+            "`break` values must have the same type, expected {:?} found {:?}",
+            self.break_type.clone().flatten().unwrap(),
+            value_type
+        );
+        self.context.error(span, message.as_str());
+        panic!("{}", message)
+    }
+
+    /// Push the instruction for a binary operator whose operands have already
+    /// been generated and return the type of the result
+    fn gen_binary_op(
+        &mut self,
+        lhs: vm_type::Type,
+        op: &Op,
+        rhs: vm_type::Type,
+        span: libparser::span::Span,
+    ) -> vm_type::Type {
+        self.lines.push((self.out.len(), span.pos));
+        if let (vm_type::Type::String(l), vm_type::Type::String(r)) = (&lhs, &rhs) {
+            match op {
+                Op::Eq | Op::NotEq => {
+                    self.out.push(if *op == Op::Eq { EQ_V } else { NE_V });
+                    return vm_type::Type::Bool;
+                }
+                Op::Plus => {}
+                _ => {
+                    self.context.error(
+                        span,
+                        format!("{:?} is not supported for {:?}", op, lhs).as_str(),
+                    );
+                    panic!()
+                }
+            }
+            self.out.push(CONCAT);
+            // Both sizes include the 2 byte length prefix
+            return vm_type::Type::String((l + r).saturating_sub(2));
+        }
+
+        if lhs != rhs {
+            let message = format!("{:?} is not compatible with {:?}", lhs, rhs);
+            self.context.error(span, message.as_str());
+            panic!("{}", message)
+        }
+
+        let is_bitwise = matches!(op, Op::Shl | Op::Shr | Op::Xor);
+        if (lhs == vm_type::Type::Bool && *op != Op::Eq && *op != Op::NotEq)
+            || (lhs == vm_type::Type::F32 && is_bitwise)
+        {
+            self.context.error(
+                span,
+                format!("{:?} is not supported for {:?}", op, lhs).as_str(),
+            );
+            panic!()
+        }
+
+        self.out.push(match op {
+            Op::Eq if lhs == vm_type::Type::Bool => BEQ,
+            Op::NotEq if lhs == vm_type::Type::Bool => BNE,
+            Op::Eq if lhs == vm_type::Type::F32 => EQ_F,
+            Op::NotEq if lhs == vm_type::Type::F32 => NE_F,
+            Op::Plus if lhs == vm_type::Type::F32 => ADD_F,
+            Op::Minus if lhs == vm_type::Type::F32 => SUB_F,
+            Op::Star if lhs == vm_type::Type::F32 => MUL_F,
+            Op::Slash if lhs == vm_type::Type::F32 => DIV_F,
+            Op::Mod if lhs == vm_type::Type::F32 => MOD_F,
+            Op::Lt if lhs == vm_type::Type::F32 => LT_F,
+            Op::Gt if lhs == vm_type::Type::F32 => GT_F,
+            Op::LtEq if lhs == vm_type::Type::F32 => LE_F,
+            Op::GtEq if lhs == vm_type::Type::F32 => GE_F,
+            Op::Plus if lhs == vm_type::Type::U32 => ADD_U,
+            Op::Minus if lhs == vm_type::Type::U32 => SUB_U,
+            Op::Star if lhs == vm_type::Type::U32 => MUL_U,
+            Op::Slash if lhs == vm_type::Type::U32 => DIV_U,
+            Op::Mod if lhs == vm_type::Type::U32 => MOD_U,
+            Op::Lt if lhs == vm_type::Type::U32 => LT_U,
+            Op::Gt if lhs == vm_type::Type::U32 => GT_U,
+            Op::LtEq if lhs == vm_type::Type::U32 => LE_U,
+            Op::GtEq if lhs == vm_type::Type::U32 => GE_U,
+            Op::Shr if lhs == vm_type::Type::U32 => SHR_U,
+            Op::Shl => SHL_I,
+            Op::Shr => SHR_I,
+            Op::Xor => XOR_I,
+            Op::Lt => LT_I,
+            Op::Gt => GT_I,
+            Op::LtEq => LE_I,
+            Op::GtEq => GE_I,
+            Op::Plus => ADD_I,
+            Op::Minus => SUB_I,
+            Op::Star => MUL_I,
+            Op::Slash => DIV_I,
+            Op::Mod => MOD_I,
+            Op::Eq => EQ,
+            Op::NotEq => NE,
+            _ => unimplemented!(),
+        });
+        match op {
+            Op::Eq | Op::NotEq | Op::Lt | Op::Gt | Op::LtEq | Op::GtEq => vm_type::Type::Bool,
+            _ => lhs,
+        }
+    }
+
+    /// Generate a call to a builtin, picking the overload that accepts the
+    /// first argument
+    fn gen_builtin_call(
+        &mut self,
+        mut builtin: &'static builtin::Builtin,
+        span: &libparser::span::Span,
+        exprs: &[(Expression, libparser::span::Span)],
+    ) -> vm_type::Type {
+        let ident = self.to_str(span);
+        self.check_arity(span, exprs, builtin.params.len());
+        for (i, (expr, arg_span)) in exprs.iter().enumerate() {
+            let arg = self.gen_expr(expr);
+            if i == 0 {
+                builtin = builtin::overload(&ident, &arg).unwrap_or(builtin);
+            }
+            if !builtin.accepts(i, &arg) {
+                let message = format!(
+                    "`{}` expects {:?}, found {:?}",
+                    ident, builtin.params[i], arg
+                );
+                self.context.error(*arg_span, message.as_str());
+                panic!("{}", message)
+            }
+        }
+        self.lines.push((self.out.len(), span.pos));
+        self.out.extend(builtin.code);
+        builtin.return_type.clone()
+    }
+
+    /// Generate `format(fmt, args..)` by converting each argument to a string
+    /// and concatenating it between the pieces of `fmt` around it's `{}`
+    /// placeholders
+    fn gen_format(
+        &mut self,
+        span: &libparser::span::Span,
+        exprs: &[(Expression, libparser::span::Span)],
+    ) -> vm_type::Type {
+        let fmt = match exprs.first() {
+            Some((
+                Expression::Literal {
+                    val,
+                    kind: LiteralKind::String,
+                },
+                _,
+            )) => {
+                let text = self.to_str(val);
+                unescape(&text[1..text.len() - 1])
+            }
+            _ => {
+                self.context
+                    .error(*span, "`format` expects a string literal first");
+                panic!("`format` expects a string literal first")
+            }
+        };
+        let pieces: Vec<&str> = fmt.split("{}").collect();
+        let args = &exprs[1..];
+        if pieces.len() - 1 != args.len() {
+            let message = format!(
+                "`format` has {} placeholder(s) but {} argument(s)",
+                pieces.len() - 1,
+                args.len()
+            );
+            self.context.error(*span, message.as_str());
+            panic!("{}", message)
+        }
+
+        let mut len = pieces.iter().map(|piece| piece.len()).sum::<usize>();
+        let mut started = false;
+        if !pieces[0].is_empty() || args.is_empty() {
+            self.gen_ldc(pieces[0], *span);
+            started = true;
+        }
+        for ((arg, arg_span), piece) in args.iter().zip(&pieces[1..]) {
+            match self.gen_expr(arg) {
+                vm_type::Type::I32 => {
+                    self.out.push(I2S);
+                    len += 11;
+                }
+                vm_type::Type::F32 => {
+                    self.out.push(F2S);
+                    len += 48;
+                }
+                // A string returned by a function has an unknown size of 0
+                vm_type::Type::String(size) => len += size.saturating_sub(2),
+                arg_type => {
+                    let message = format!("`format` can't format {:?}", arg_type);
+                    self.context.error(*arg_span, message.as_str());
+                    panic!("{}", message)
+                }
+            }
+            if started {
+                self.out.push(CONCAT);
+            }
+            started = true;
+            if !piece.is_empty() {
+                self.gen_ldc(piece, *span);
+                self.out.push(CONCAT);
+            }
+        }
+        // Plus the 2 byte length prefix
+        vm_type::Type::String(len + 2)
+    }
+
+    /// Generate the condition of an `if` followed by a jump taken when the
+    /// condition is false.  Integer comparisons are lowered to `CMP_I` and the
+    /// matching `IF_*` jump instead of computing a bool and testing it.
+    /// Returns the position of the jump target so it can be backpatched.
+    fn gen_branch_if_false(
+        &mut self,
+        expr: &Expression,
+        span: libparser::span::Span,
+        keyword: &str,
+    ) -> usize {
+        if let Expression::Binary(lhs, op, rhs, span) = expr {
+            let branch = match op {
+                Op::Lt => Some(IF_GE),
+                Op::Gt => Some(IF_LE),
+                Op::LtEq => Some(IF_GT),
+                Op::GtEq => Some(IF_LT),
+                Op::Eq if !self.is_zero_literal(rhs) => Some(IF_NE),
+                Op::NotEq if !self.is_zero_literal(rhs) => Some(IF_EQ),
+                _ => None,
+            };
+            if let Some(branch) = branch {
+                let lhs = self.gen_expr(lhs);
+                let rhs = self.gen_expr(rhs);
+                if lhs == vm_type::Type::I32 && rhs == vm_type::Type::I32 {
+                    self.out.push(CMP_I);
+                    return self.gen_jump(branch, 0);
+                }
+                self.gen_binary_op(lhs, op, rhs, *span);
+                return self.gen_jump(IF_F, 0);
+            }
+        }
+        let cond = self.gen_expr(expr);
+        if cond != vm_type::Type::Bool {
+            let message = format!("`{}` condition must be Bool, found {:?}", keyword, cond);
+            self.context.error(span, message.as_str());
+            panic!("{}", message)
+        }
+        self.gen_jump(IF_F, 0)
+    }
+
     /// Generate an expression (inside a block)
     /// ```
     /// # use libcodegen::opcode::*;
@@ -262,7 +1224,7 @@ impl OpcodeGenerator<'_> {
     /// # use libvm::consts::*;
     ///  
     /// static INPUT: &str = r"
-    ///     5 + 5
+    ///     5 + input_int()
     /// ";
     /// let parse_context = libparser::parse_context::ParseContext::new(INPUT);
     /// let mut parser = libparser::parser::Parser::new(INPUT, &parse_context);
@@ -277,101 +1239,120 @@ impl OpcodeGenerator<'_> {
     /// let out = gen.out();
     /// assert_eq!(out, vec![
     ///     PUSH_I, 0, 0, 0, 5,
-    ///     PUSH_I, 0, 0, 0, 5,
+    ///     VIRTUAL, 0x10,
     ///     ADD_I
     /// ])
     /// ```
     pub fn gen_expr(&mut self, expr: &Expression) -> vm_type::Type {
         match expr {
-            Expression::Binary(lhs, op, rhs, span) => {
+            Expression::Binary(lhs, op, rhs, span)
+                if (*op == Op::Eq || *op == Op::NotEq) && self.is_zero_literal(rhs) =>
+            {
+                let lhs = self.gen_expr(lhs);
+                if lhs != vm_type::Type::I32 {
+                    self.context.error(
+                        *span,
+                        format!("{:?} is not compatible with {:?}", lhs, vm_type::Type::I32)
+                            .as_str(),
+                    );
+                    panic!()
+                }
+                self.out.push(if *op == Op::Eq { EQZ } else { NEZ });
+                vm_type::Type::Bool
+            }
+            Expression::Binary(lhs, op, rhs, span) if *op == Op::And || *op == Op::Or => {
+                // Keep the lhs as the result if it decides the expression,
+                // otherwise drop it and use the rhs
                 let lhs = self.gen_expr(lhs);
+                self.out.push(DUP_B);
+                let set_me = self.gen_jump(if *op == Op::And { IF_F } else { IF_T }, 0);
+                self.out.push(POP_B);
                 let rhs = self.gen_expr(rhs);
+                self.patch_jump(set_me, self.out.len());
 
-                if lhs != rhs {
+                if lhs != vm_type::Type::Bool || rhs != vm_type::Type::Bool {
                     self.context.error(
                         *span,
-                        format!("{:?} is not compatible with {:?}", lhs, rhs).as_str(),
+                        format!("{:?} is not supported for {:?} and {:?}", op, lhs, rhs).as_str(),
                     );
                     panic!()
                 }
+                vm_type::Type::Bool
+            }
+            Expression::Binary(..) | Expression::Unary(..) if self.fold(expr).is_some() => {
+                let folded = self.fold(expr).unwrap();
+                self.gen_folded(folded)
+            }
+            Expression::Binary(lhs, op, rhs, span) => {
+                let lhs = self.gen_expr(lhs);
+                let rhs = self.gen_expr(rhs);
 
-                self.out.push(match op {
-                    Op::Plus if lhs == vm_type::Type::F32 => ADD_F,
-                    Op::Minus if lhs == vm_type::Type::F32 => SUB_F,
-                    Op::Star if lhs == vm_type::Type::F32 => MUL_F,
-                    Op::Slash if lhs == vm_type::Type::F32 => DIV_F,
-                    Op::Mod if lhs == vm_type::Type::F32 => MOD_F,
-                    Op::Lt if lhs == vm_type::Type::F32 => LT_F,
-                    Op::Gt if lhs == vm_type::Type::F32 => GT_F,
-                    Op::LtEq if lhs == vm_type::Type::F32 => LE_F,
-                    Op::GtEq if lhs == vm_type::Type::F32 => GE_F,
-                    Op::Lt => LT_I,
-                    Op::Gt => GT_I,
-                    Op::LtEq => LE_I,
-                    Op::GtEq => GE_I,
-                    Op::Plus => ADD_I,
-                    Op::Minus => SUB_I,
-                    Op::Star => MUL_I,
-                    Op::Slash => DIV_I,
-                    Op::Mod => MOD_I,
-                    Op::Eq => EQ,
-                    Op::NotEq => NE,
-                    _ => unimplemented!(),
-                });
-                lhs
-            }
-            Expression::FunctionCall(ident_span, exprs) => match self.to_str(ident_span).as_str() {
-                "print_int" => {
-                    self.gen_expr(exprs.get(0).unwrap());
-                    self.out.push(VIRTUAL);
-                    self.out.push(0);
-                    vm_type::Type::Void
-                }
-                "debug" => {
-                    self.out.push(VIRTUAL);
-                    self.out.push(1);
-                    vm_type::Type::Void
-                }
-                "print_float" => {
-                    self.gen_expr(exprs.get(0).unwrap());
-                    self.out.push(VIRTUAL);
-                    self.out.push(3);
-                    vm_type::Type::Void
-                }
-                "print_str" => {
-                    self.gen_expr(exprs.get(0).unwrap());
-                    self.out.push(VIRTUAL);
-                    self.out.push(2);
-                    vm_type::Type::Void
-                }
-                ident => {
-                    for expr in exprs.iter() {
-                        self.gen_expr(expr);
-                    }
-                    if let Some((index, stmt)) = self.functions.get(ident) {
-                        self.out.push(CALL);
-                        self.out.push(*index as u8);
-                        if let Statement::FnDecl { return_type, .. } = stmt {
-                            ast_type_to_vm_type(return_type)
-                        } else {
-                            vm_type::Type::Void
+                self.gen_binary_op(lhs, op, rhs, *span)
+            }
+            Expression::FunctionCall(ident_span, exprs, _) => {
+                match self.to_str(ident_span).as_str() {
+                    "format" => self.gen_format(ident_span, exprs),
+                    "print" => {
+                        for (expr, _) in exprs.iter() {
+                            let expr_type = self.gen_expr(expr);
+                            self.out.push(VIRTUAL);
+                            self.out.push(match expr_type {
+                                vm_type::Type::I32 => 0,
+                                vm_type::Type::String(_) => 2,
+                                vm_type::Type::F32 => 3,
+                                vm_type::Type::Bool => 4,
+                                vm_type::Type::U32 => 5,
+                                vm_type::Type::Void => {
+                                    self.context.error(*ident_span, "Can't print Void");
+                                    panic!("Can't print Void")
+                                }
+                            });
                         }
-                    } else {
-                        self.context.error(*ident_span, "Unknown function");
-                        panic!() // TODO: Fix this message
+                        vm_type::Type::Void
                     }
+                    ident => match builtin::lookup(ident) {
+                        Some(builtin) => self.gen_builtin_call(builtin, ident_span, exprs),
+                        None => {
+                            for (expr, _) in exprs.iter() {
+                                self.gen_expr(expr);
+                            }
+                            if let Some((index, stmt)) = self.functions.get(ident) {
+                                self.lines.push((self.out.len(), ident_span.pos));
+                                self.out.push(CALL);
+                                self.out.extend(&(*index as u16).to_be_bytes());
+                                if let Statement::FnDecl { return_type, .. } = stmt {
+                                    ast_type_to_vm_type(return_type)
+                                } else {
+                                    vm_type::Type::Void
+                                }
+                            } else {
+                                self.context.error(*ident_span, "Unknown function");
+                                panic!() // TODO: Fix this message
+                            }
+                        }
+                    },
                 }
-            },
+            }
             Expression::Ident { val } => {
                 let ident = self.to_str(val);
-                if let Some((index, var_type)) = self.var_map.get(&ident) {
+                if self.var_map.contains_key(&ident) && !self.assigned.contains(&ident) {
+                    self.context.error(*val, "possibly uninitialized variable");
+                    panic!("possibly uninitialized variable")
+                }
+                if let Some((index, var_type, _)) = self.var_map.get(&ident) {
                     match var_type {
-                        vm_type::Type::I32 | vm_type::Type::F32 => {
+                        vm_type::Type::I32 | vm_type::Type::U32 => {
                             self.out.push(LOAD_I);
                         }
+                        vm_type::Type::F32 => {
+                            self.out.push(LOAD_F);
+                        }
                         vm_type::Type::String(_) => {
                             self.out.push(LOAD_V);
                         }
+                        vm_type::Type::Bool => {
+                            self.out.push(LOAD_B);
+                        }
                         vm_type::Type::Void => {}
                     }
                     self.out.push(*index);
@@ -385,47 +1366,64 @@ impl OpcodeGenerator<'_> {
                 match *kind {
                     LiteralKind::Int => {
                         self.out.push(PUSH_I);
-                        let num = self.to_str(val);
-                        let num = num.parse::<i32>().unwrap(); // TODO: Match literal kind
-                        let x = num as u32;
-                        let b1: u8 = ((x >> 24) & 0xff) as u8;
-                        let b2: u8 = ((x >> 16) & 0xff) as u8;
-                        let b3: u8 = ((x >> 8) & 0xff) as u8;
-                        let b4: u8 = (x & 0xff) as u8;
-                        self.out.push(b1);
-                        self.out.push(b2);
-                        self.out.push(b3);
-                        self.out.push(b4);
+                        let num = self.number_str(val);
+                        let num = num.parse::<i32>().unwrap();
+                        self.out.extend(vm_type::encode_i32(num).iter());
                         vm_type::Type::I32
                     }
+                    LiteralKind::Bool => {
+                        self.out.push(PUSH_B);
+                        self.out.push((self.to_str(val) == "true") as u8);
+                        vm_type::Type::Bool
+                    }
+                    LiteralKind::UInt => {
+                        self.out.push(PUSH_I);
+                        let num = self.number_str(val);
+                        let num = match num.parse::<u32>() {
+                            Ok(num) => num,
+                            Err(_) => {
+                                let msg = "integer literal out of range for u32";
+                                self.context.error(*val, msg);
+                                panic!("{}", msg)
+                            }
+                        };
+                        self.out.extend(num.to_be_bytes().iter());
+                        vm_type::Type::U32
+                    }
                     LiteralKind::String => {
+                        let span = *val;
                         let val = self.to_str(val);
-                        let c_index = self.module.borrow_mut().new_const(&val[1..val.len() - 1]);
-                        self.out.push(LDC);
-                        self.out.push(c_index as u8);
-                        vm_type::Type::String(val.len() - 1)
+                        let val = unescape(&val[1..val.len() - 1]);
+                        self.gen_ldc(&val, span);
+                        // Plus the 2 byte length prefix
+                        vm_type::Type::String(val.len() + 2)
                     }
                     LiteralKind::Float => {
+                        // TODO: Perhaps split this kind of thing into a separate utility library
                         self.out.push(PUSH_I);
-                        let num = self.to_str(val);
-                        let num = num.parse::<f32>().unwrap(); // TODO: Match literal kind
-                        unsafe {
-                            // TODO: Perhaps split this kind of thing into a separate utility library
-                            let x = std::mem::transmute::<f32, [u8; 4]>(num);
-                            self.out.push(x[0]);
-                            self.out.push(x[1]);
-                            self.out.push(x[2]);
-                            self.out.push(x[3]);
-                        }
+                        let num = self.number_str(val);
+                        let num = num.parse::<f32>().unwrap();
+                        self.out.extend(vm_type::encode_f32(num).iter());
                         vm_type::Type::F32
                     }
                 }
             }
+            Expression::Range(_, _, span) => {
+                self.context
+                    .error(*span, "Ranges can only be used in `for` loops");
+                panic!("Ranges can only be used in `for` loops")
+            }
             Expression::Unary(op, expr, span) => {
                 let expr = self.gen_expr(expr);
-                let instruction = match *op {
-                    Op::Minus => NEG_I,
-                    Op::Not => NOT,
+                let instruction = match (op, &expr) {
+                    (Op::Minus, vm_type::Type::I32) => NEG_I,
+                    (Op::Minus, vm_type::Type::F32) => NEG_F,
+                    (Op::Not, vm_type::Type::Bool) => NOT,
+                    (Op::Minus, _) | (Op::Not, _) => {
+                        let message = format!("{:?} is not supported for {:?}", op, expr);
+                        self.context.error(*span, message.as_str());
+                        panic!("{}", message)
+                    }
                     _ => {
                         self.context
                             .error(*span, "Only '-' or '!' in unary expressions");
@@ -435,7 +1433,1260 @@ impl OpcodeGenerator<'_> {
                 self.out.push(instruction);
                 expr
             }
+            Expression::Unit(_) => vm_type::Type::Void,
+            Expression::Loop(block, _) => self.gen_loop_expr(block),
+            Expression::Field(_, span) => {
+                self.context.error(*span, "fields not yet supported");
+                panic!("fields not yet supported")
+            }
+            Expression::MethodCall(_, span, _) => {
+                self.context.error(*span, "methods not yet supported");
+                panic!("methods not yet supported")
+            }
             Expression::Dummy => panic!(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libparser::parse_context::Severity;
+    use libvm::vm::Vm;
+
+    /// Generate the body of a function returning `return_type`
+    fn gen_body(input: &str, return_type: vm_type::Type) -> Vec<u8> {
+        let context = ParseContext::new(input);
+        let mut parser = Parser::new(input, &context);
+        let mut gen = OpcodeGenerator::new(input);
+        gen.gen_block(&parser.parse_block(), return_type);
+        gen.out()
+    }
+
+    /// Generate a module
+    fn gen_module(input: &str) -> Rc<RefCell<Module>> {
+        let context = ParseContext::new(input);
+        let mut parser = Parser::new(input, &context);
+        let mut gen = OpcodeGenerator::new(input);
+        gen.gen_module(&parser.parse());
+        gen.gen()
+    }
+
+    #[test]
+    fn test_input_int() {
+        static INPUT: &str = r"
+            let x = input_int()
+            return x
+        ";
+        let program = gen_body(INPUT, vm_type::Type::I32);
+        assert_eq!(&program[..2], &[VIRTUAL, 0x10]);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default())
+            .with_reader(Box::new(std::io::Cursor::new("42\n")));
+        assert_eq!(vm.run(), vm_type::encode_i32(42).to_vec());
+    }
+
+    #[test]
+    fn test_literal_encoding() {
+        let program = gen_body("return 123456", vm_type::Type::I32);
+        assert_eq!(program[0], PUSH_I);
+        assert_eq!(&program[1..5], &vm_type::encode_i32(123456));
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        let out = vm.run();
+        assert_eq!(
+            vm_type::decode_i32([out[0], out[1], out[2], out[3]]),
+            123456
+        );
+
+        let program = gen_body("return 2.5", vm_type::Type::F32);
+        assert_eq!(&program[1..5], &vm_type::encode_f32(2.5));
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        let out = vm.run();
+        assert_eq!(vm_type::decode_f32([out[0], out[1], out[2], out[3]]), 2.5);
+    }
+
+    #[test]
+    fn test_bool_equality() {
+        static INPUT: &str = r"
+            let same = (1 > 2) == (3 > 4)
+        ";
+        let program = gen_body(INPUT, Default::default());
+        assert_eq!(&program[program.len() - 3..], &[BEQ, STO_B, 0]);
+
+        static BRANCH: &str = r"
+            let same = (1 > 2) == (3 > 4)
+            if same != (5 > 6) {
+                return 1
+            }
+            return 0
+        ";
+        let program = gen_body(BRANCH, vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vec![0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_compare_zero() {
+        static INPUT: &str = r"
+            let x = 0
+            if x == 0 {
+                return 1
+            }
+            return 0
+        ";
+        let program = gen_body(INPUT, vm_type::Type::I32);
+        assert_eq!(&program[7..10], &[LOAD_I, 0, EQZ]);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vec![0, 0, 0, 1]);
+
+        static NONZERO: &str = r"
+            let x = 3
+            if x != 0 {
+                return 1
+            }
+            return 0
+        ";
+        let program = gen_body(NONZERO, vm_type::Type::I32);
+        assert_eq!(&program[7..10], &[LOAD_I, 0, NEZ]);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vec![0, 0, 0, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many functions (limit is 2)")]
+    fn test_function_limit() {
+        static INPUT: &str = r"
+            fn a() {}
+            fn b() {}
+            fn c() {}
+        ";
+        let context = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &context);
+        let mut gen = OpcodeGenerator::new(INPUT).with_limits(16, 2);
+        gen.gen_module(&parser.parse());
+    }
+
+    #[test]
+    fn test_concat() {
+        let program = gen_body(r#"print_str("foo" + "bar")"#, vm_type::Type::Void);
+        assert_eq!(&program[6..9], &[CONCAT, VIRTUAL, 0x02]);
+    }
+
+    #[test]
+    fn test_nested_block() {
+        static INPUT: &str = r"
+            let x = 1
+            {
+                let x = 5
+                let y = 2
+                x = x * y
+            }
+            {
+                x = x * 10
+                x = x + 2
+            }
+            return x
+        ";
+        let program = gen_body(INPUT, vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(12).to_vec());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_nested_block_scope() {
+        gen_body("{ let y = 2 }\n return y", vm_type::Type::I32);
+    }
+
+    #[test]
+    #[should_panic(expected = "missing return")]
+    fn test_missing_return() {
+        gen_module("fn f(x: i32) -> i32 {\n if x > 0 { return 1 }\n }");
+    }
+
+    #[test]
+    fn test_if_else_return() {
+        static INPUT: &str = r"
+            fn sign(x: i32) -> i32 {
+                if x > 0 {
+                    return 1
+                } else if x == 0 {
+                    return 0
+                } else {
+                    return 2
+                }
+            }
+        ";
+        let module = gen_module(INPUT);
+        let module = module.borrow();
+        let func = module.get_fn(0);
+        assert_eq!(
+            func.run(vm_type::encode_i32(5).to_vec()),
+            vm_type::encode_i32(1)
+        );
+        assert_eq!(
+            func.run(vm_type::encode_i32(0).to_vec()),
+            vm_type::encode_i32(0)
+        );
+        assert_eq!(
+            func.run(vm_type::encode_i32(-3).to_vec()),
+            vm_type::encode_i32(2)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "possibly uninitialized variable")]
+    fn test_uninitialized() {
+        gen_module("fn f(a: i32) -> i32 {\n if a > 0 { let x = 1 }\n return x\n }");
+    }
+
+    #[test]
+    fn test_assigned_both_branches() {
+        static INPUT: &str = r"
+            fn f(a: i32) -> i32 {
+                if a > 0 {
+                    let x = 1
+                } else {
+                    let x = 2
+                }
+                return x
+            }
+        ";
+        let module = gen_module(INPUT);
+        let func = module.borrow().get_fn(0).clone();
+        assert_eq!(
+            func.run(vm_type::encode_i32(0).to_vec()),
+            vm_type::encode_i32(2)
+        );
+    }
+
+    #[test]
+    fn test_print() {
+        static INPUT: &str = r#"print(1, 2.5, "a", 3)"#;
+        let context = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &context);
+        let mut gen = OpcodeGenerator::new(INPUT);
+        gen.gen_block(&parser.parse_block(), vm_type::Type::Void);
+        let program = gen.out();
+        let calls: Vec<u8> = program
+            .windows(2)
+            .filter(|w| w[0] == VIRTUAL)
+            .map(|w| w[1])
+            .collect();
+        assert_eq!(calls, vec![0, 3, 2, 0]);
+        let mut vm = Vm::new(&program, Vec::new(), gen.gen());
+        vm.run();
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "`print_int` expects 1 argument(s), found 2")]
+    fn test_builtin_arity() {
+        gen_body("print_int(1, 2)", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_float_equality() {
+        let program = gen_body(
+            "let a = 1.5 == 1.5\n let b = 1.5 != 2.5",
+            vm_type::Type::Void,
+        );
+        assert!(program.contains(&EQ_F));
+        assert!(program.contains(&NE_F));
+        assert!(!program.contains(&EQ));
+    }
+
+    #[test]
+    fn test_run_function() {
+        static INPUT: &str = r"
+            fn double(x: i32) -> i32 {
+                return x * 2
+            }
+        ";
+        let module = gen_module(INPUT);
+        let out = module
+            .borrow()
+            .run_function("double", vm_type::encode_i32(5).to_vec());
+        assert_eq!(out, Ok(vm_type::encode_i32(10).to_vec()));
+    }
+
+    #[test]
+    fn test_call_params() {
+        static INPUT: &str = r"
+            fn scale(a: i32, b: f32) -> f32 {
+                return b * 2.0
+            }
+        ";
+        let module = gen_module(INPUT);
+        let mut stack = vec![9, 9];
+        stack.extend(&vm_type::encode_i32(3));
+        stack.extend(&vm_type::encode_f32(1.25));
+        let out = module
+            .borrow()
+            .call(0, &mut stack, 0, &mut std::io::stdout())
+            .unwrap();
+        assert_eq!(out, vm_type::encode_f32(2.5).to_vec());
+        assert_eq!(stack, vec![9, 9]);
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let program = gen_body("return 2 + 3 * 4", vm_type::Type::I32);
+        assert_eq!(program, vec![PUSH_I, 0, 0, 0, 14, RET_I]);
+
+        let program = gen_body("return 1.5 * 2.0 - 0.5", vm_type::Type::F32);
+        assert_eq!(&program[1..5], &vm_type::encode_f32(2.5));
+        assert_eq!(program.len(), 6);
+
+        // Division by zero is left to the VM
+        let program = gen_body("return 1 / 0", vm_type::Type::I32);
+        assert!(program.contains(&DIV_I));
+    }
+
+    #[test]
+    fn test_negative_literal() {
+        let program = gen_body("return -5", vm_type::Type::I32);
+        assert_eq!(program, vec![PUSH_I, 0xff, 0xff, 0xff, 0xfb, RET_I]);
+        assert!(!program.contains(&NEG_I));
+
+        let program = gen_body("return -2147483648", vm_type::Type::I32);
+        assert_eq!(&program[1..5], &vm_type::encode_i32(i32::MIN));
+
+        let program = gen_body("return -2.5", vm_type::Type::F32);
+        assert_eq!(&program[1..5], &vm_type::encode_f32(-2.5));
+        assert!(!program.contains(&NEG_F));
+    }
+
+    #[test]
+    fn test_let_mutable() {
+        let program = gen_body("let y = 1\n y = 2\n return y", vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(2).to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot assign twice to const `x`")]
+    fn test_const_mutate() {
+        gen_body("const x = 1\n x = 2", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_compare_branch() {
+        static INPUT: &str = r"
+            let a = 1
+            let b = 2
+            if a < b {
+                return 1
+            }
+            return 0
+        ";
+        let program = gen_body(INPUT, vm_type::Type::I32);
+        assert_eq!(&program[14..20], &[LOAD_I, 0, LOAD_I, 4, CMP_I, IF_GE]);
+
+        for op in ["<", ">", "<=", ">=", "==", "!="].iter() {
+            for (a, b) in [(1, 2), (2, 2), (3, 2), (0, 7)].iter() {
+                let input = format!(
+                    "let a = {}\n let b = {}\n if a {} b {{ return 1 }}\n return 0",
+                    a, b, op
+                );
+                let expected = match *op {
+                    "<" => a < b,
+                    ">" => a > b,
+                    "<=" => a <= b,
+                    ">=" => a >= b,
+                    "==" => a == b,
+                    _ => a != b,
+                };
+                let program = gen_body(&input, vm_type::Type::I32);
+                assert!(program.contains(&CMP_I));
+                let mut vm = Vm::new(&program, Vec::new(), Default::default());
+                assert_eq!(
+                    vm.run(),
+                    vm_type::encode_i32(expected as i32).to_vec(),
+                    "{}",
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_void_return() {
+        static INPUT: &str = r"
+            fn f() -> void {
+                return
+            }
+            fn g() -> void {
+                return ()
+            }
+            fn main() {
+                f()
+                g()
+            }
+        ";
+        let module = gen_module(INPUT);
+        let module = module.borrow();
+        assert_eq!(module.get_fn(0).program(), &vec![RET_V]);
+        assert_eq!(module.get_fn(0).return_type(), &vm_type::Type::Void);
+        assert_eq!(module.get_fn(3).program(), &vec![RET_V]);
+        module.run_main();
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        static INPUT: &str = "let r = (1 < 2) && (3 < 4)";
+        let program = gen_body(INPUT, Default::default());
+        let mut vm = Vm::new(
+            &program[..program.len() - 2],
+            Vec::new(),
+            Default::default(),
+        );
+        vm.run();
+        assert_eq!(vm.stack(), &[1]);
+
+        // Evaluating the rhs would fail to read an int from the empty reader
+        static SHORT: &str = "let r = (1 > 2) && (input_int() == 1)";
+        let program = gen_body(SHORT, Default::default());
+        let mut vm = Vm::new(
+            &program[..program.len() - 2],
+            Vec::new(),
+            Default::default(),
+        )
+        .with_reader(Box::new(std::io::empty()));
+        vm.run();
+        assert_eq!(vm.stack(), &[0]);
+
+        static OR: &str = "let r = (1 < 2) || (input_int() == 1)";
+        let program = gen_body(OR, Default::default());
+        let mut vm = Vm::new(
+            &program[..program.len() - 2],
+            Vec::new(),
+            Default::default(),
+        )
+        .with_reader(Box::new(std::io::empty()));
+        vm.run();
+        assert_eq!(vm.stack(), &[1]);
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        static INPUT: &str = r#"print_str("a\nb\t\"c\"\\")"#;
+        let context = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &context);
+        let mut gen = OpcodeGenerator::new(INPUT);
+        gen.gen_block(&parser.parse_block(), vm_type::Type::Void);
+        let program = gen.out();
+        let module = gen.gen();
+        assert_eq!(module.borrow().constant(0), b"a\nb\t\"c\"\\");
+        let mut vm = Vm::new(&program, Vec::new(), module);
+        vm.run();
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn test_runtime_error_span() {
+        static INPUT: &str = "fn main() -> i32 {\n    let a = 1\n    return a + 10 / 0\n}";
+        let module = gen_module(INPUT);
+        let err = module.borrow().run_function("main", vec![]).unwrap_err();
+        assert!(matches!(&err, VmError::At(err, _) if **err == VmError::DivisionByZero));
+
+        let context = ParseContext::new(INPUT);
+        report_runtime_error(&context, &err);
+        let span = context.errors()[0].span;
+        assert_eq!(&INPUT[span.pos.0..span.pos.1], "10 / 0");
+        assert_eq!(INPUT[..span.pos.0].matches('\n').count(), 2);
+    }
+
+    #[test]
+    fn test_run_typed() {
+        let module = gen_module("fn half(x: f32) -> f32 {\n return x / 2.0\n}");
+        let args = vm_type::encode_f32(5.0).to_vec();
+        let out = module.borrow().get_fn(0).run_typed(args);
+        assert_eq!(out, vm_type::Value::Float(2.5));
+    }
+
+    #[test]
+    fn test_u32() {
+        let module = gen_module("fn f() -> u32 {\n return 4000000000u32 + 1u32\n}");
+        let out = module.borrow().get_fn(0).run_typed(vec![]);
+        assert_eq!(out, vm_type::Value::UInt(4000000001));
+
+        let module = gen_module("fn f(x: u32) -> u32 {\n return x >> 28u32\n}");
+        let out = module
+            .borrow()
+            .get_fn(0)
+            .run_typed(4000000000u32.to_be_bytes().to_vec());
+        assert_eq!(out, vm_type::Value::UInt(14));
+    }
+
+    #[test]
+    fn test_int_to_str() {
+        let program = gen_body("return int_to_str(42)", vm_type::Type::String(0));
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vec![0, 2, b'4', b'2']);
+
+        let module = gen_module("fn main() {\n let x = -7\n print_str(\"x=\" + int_to_str(x))\n }");
+        let mut out = Vec::new();
+        let result = module
+            .borrow()
+            .run_function_with_output("main", vec![], &mut out);
+        assert_eq!(result, Ok(vec![]));
+        assert_eq!(out, b"x=-7\n");
+    }
+
+    #[test]
+    fn test_bool_and_str_signatures() {
+        static INPUT: &str = "
+            fn pick(flag: bool, x: i32) -> str {
+                if flag { return \"yes\" }
+                return int_to_str(x)
+            }
+            fn is_big(x: i32) -> bool {
+                return x > 10
+            }
+        ";
+        let module = gen_module(INPUT);
+        let module = module.borrow();
+        let out = module.get_fn(0).run_typed(vec![1, 0, 0, 0, 7]);
+        assert_eq!(out, vm_type::Value::Str("yes".into()));
+        let out = module.get_fn(0).run_typed(vec![0, 0, 0, 0, 7]);
+        assert_eq!(out, vm_type::Value::Str("7".into()));
+        assert_eq!(
+            module.run_function("is_big", vec![0, 0, 0, 11]),
+            Ok(vec![1])
+        );
+    }
+
+    #[test]
+    fn test_else_if_span() {
+        static INPUT: &str = "
+            let x = 3
+            if x == 1 {
+                print_int(1)
+            } else if x == 2 {
+                print_int(2)
+            } else if x {
+                print_int(3)
+            } else {
+                print_int(4)
+            }
+        ";
+        let context = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &context);
+        let block = parser.parse_block();
+        let mut gen = OpcodeGenerator::new(INPUT);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            gen.gen_block(&block, vm_type::Type::Void)
+        }));
+        assert!(result.is_err());
+        let errors = gen.context.errors();
+        assert_eq!(errors[0].message, "`if` condition must be Bool, found I32");
+        let (start, end) = errors[0].span.pos;
+        assert_eq!(&INPUT[start..end], "else if");
+        assert_eq!(INPUT[..start].matches("else if").count(), 1);
+    }
+
+    #[test]
+    fn test_format() {
+        static INPUT: &str = "
+            fn f() -> str {
+                return format(\"{} + {} = {}\", 1, 2, 3)
+            }
+            fn g(x: f32) -> str {
+                return format(\"{}{}!\", \"x is \", x)
+            }
+        ";
+        let module = gen_module(INPUT);
+        let module = module.borrow();
+        let out = module.get_fn(0).run_typed(vec![]);
+        assert_eq!(out, vm_type::Value::Str("1 + 2 = 3".into()));
+        let g = module.functions().values().find(|f| f.params().len() == 1);
+        let out = g.unwrap().run_typed(vm_type::encode_f32(2.5).to_vec());
+        assert_eq!(out, vm_type::Value::Str("x is 2.5!".into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "`format` has 2 placeholder(s) but 1 argument(s)")]
+    fn test_format_mismatch() {
+        gen_body("print_str(format(\"{} {}\", 1))", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_xor() {
+        let module = gen_module("fn f(x: i32) -> i32 {\n return x ^ 3\n}");
+        let out = module.borrow().get_fn(0).run_typed(vec![0, 0, 0, 6]);
+        assert_eq!(out, vm_type::Value::Int(5));
+
+        // `^` binds looser than shifts and tighter than comparisons
+        static INPUT: &str =
+            "fn f(x: i32) -> i32 {\n if x ^ 1 << 2 == 1 { return 1 }\n return 0\n}";
+        let module = gen_module(INPUT);
+        let out = module.borrow().get_fn(0).run_typed(vec![0, 0, 0, 5]);
+        assert_eq!(out, vm_type::Value::Int(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "I32 is not compatible with U32")]
+    fn test_u32_mismatch() {
+        gen_body("let x = 1 + 1u32", vm_type::Type::Void);
+    }
+
+    #[test]
+    #[should_panic(expected = "integer literal out of range for u32")]
+    fn test_u32_out_of_range() {
+        gen_body("let x = 5000000000u32", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_bool_literals() {
+        let program = gen_body("let a = true\n let b = false", vm_type::Type::Void);
+        assert_eq!(&program[..2], &[PUSH_B, 1]);
+        assert_eq!(&program[4..6], &[PUSH_B, 0]);
+
+        static INPUT: &str = "
+            fn f() -> i32 {
+                if true {
+                    return 1
+                }
+                return 2
+            }
+            fn g() -> i32 {
+                if false {
+                    return 1
+                }
+                return 2
+            }
+        ";
+        let module = gen_module(INPUT);
+        assert_eq!(
+            module.borrow().run_function("f", vec![]),
+            Ok(vec![0, 0, 0, 1])
+        );
+        assert_eq!(
+            module.borrow().run_function("g", vec![]),
+            Ok(vec![0, 0, 0, 2])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "`print_int` expects I32, found String(4)")]
+    fn test_builtin_arg_type() {
+        gen_body("print_int(\"hi\")", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_assert() {
+        static INPUT: &str = "
+            fn pass() {
+                assert(1 == 1)
+            }
+            fn fail() {
+                assert(1 == 2)
+            }
+        ";
+        let module = gen_module(INPUT);
+        assert_eq!(module.borrow().run_function("pass", vec![]), Ok(vec![]));
+        let out = module.borrow().run_function("fail", vec![]);
+        match out {
+            Err(VmError::At(err, (start, end))) => {
+                assert_eq!(*err, VmError::AssertionFailed);
+                assert_eq!(&INPUT[start..end], "assert");
+            }
+            out => panic!("Expected assertion failure, found {:?}", out),
+        }
+    }
+
+    #[test]
+    fn test_dup_let() {
+        let program = gen_body("let x = 3\n return x * 2", vm_type::Type::I32);
+        let program = optimize::peephole(&program);
+        assert_eq!(&program[5..8], &[DUP_I, STO_I, 0]);
+        assert!(!program.contains(&LOAD_I));
+
+        // Storing then loading gives the same result
+        let unoptimized = vec![
+            PUSH_I, 0, 0, 0, 3, STO_I, 0, LOAD_I, 0, PUSH_I, 0, 0, 0, 2, MUL_I, RET_I,
+        ];
+        let optimized = Vm::new(&program, Vec::new(), Default::default()).run();
+        let expected = Vm::new(&unoptimized, Vec::new(), Default::default()).run();
+        assert_eq!(optimized, expected);
+        assert_eq!(optimized, vec![0, 0, 0, 6]);
+
+        // Only the first load right after the store is replaced
+        let program = gen_body("let x = 3\n let y = 1\n return x", vm_type::Type::I32);
+        let program = optimize::peephole(&program);
+        assert_eq!(program.iter().filter(|op| **op == DUP_I).count(), 0);
+    }
+
+    #[test]
+    fn test_literal_suffix() {
+        let program = gen_body("let x = 0f32\n let y = 5i32", vm_type::Type::Void);
+        let mut expected = vec![PUSH_I];
+        expected.extend(&vm_type::encode_f32(0.0));
+        expected.extend(&[STO_F, 0, PUSH_I]);
+        expected.extend(&vm_type::encode_i32(5));
+        assert_eq!(&program[..expected.len()], &expected[..]);
+
+        let program = gen_body("let x = 2f32\n return x * 1.5", vm_type::Type::F32);
+        let out = Vm::new(&program, Vec::new(), Default::default()).run();
+        assert_eq!(out, vm_type::encode_f32(3.0));
+    }
+
+    #[test]
+    fn test_for_range() {
+        static INPUT: &str = "
+            let digits = 0
+            for i in 0..3 {
+                digits = digits * 10 + i + 1
+            }
+            return digits
+        ";
+        let program = gen_body(INPUT, vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(123));
+
+        static BREAK: &str = "
+            let n = 0
+            for i in 0..10 {
+                if i == 4 {
+                    break
+                }
+                n = n + 1
+            }
+            return n
+        ";
+        let program = gen_body(BREAK, vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(4));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_for_scope() {
+        gen_body("for i in 0..3 {\n }\n print_int(i)", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        static INPUT: &str = "let x = 1\n return x\n print_int(x)\n return 2";
+        let context = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &context);
+        let mut gen = OpcodeGenerator::new(INPUT);
+        gen.gen_block(&parser.parse_block(), vm_type::Type::I32);
+
+        let diagnostics = gen.context.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].message, "Unreachable code");
+        assert_eq!(
+            &INPUT[diagnostics[0].span.pos.0..],
+            "print_int(x)\n return 2"
+        );
+
+        let program = gen.out();
+        assert_eq!(
+            program,
+            vec![PUSH_I, 0, 0, 0, 1, STO_I, 0, LOAD_I, 0, RET_I]
+        );
+    }
+
+    #[test]
+    fn test_loop_expression() {
+        let program = gen_body("let x = loop { break 5 }\n return x", vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(5));
+
+        static COUNT: &str = "
+            let n = 1
+            let doubled = loop {
+                if n > 20 {
+                    break n * 2
+                }
+                n = n * 3
+            }
+            return 1 + doubled
+        ";
+        let program = gen_body(COUNT, vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(55));
+    }
+
+    #[test]
+    #[should_panic(expected = "`break` values must have the same type, expected I32 found F32")]
+    fn test_loop_expression_mismatch() {
+        gen_body(
+            "let x = loop {\n if true { break 1 }\n break 2.0\n }",
+            vm_type::Type::Void,
+        );
+    }
+
+    #[test]
+    fn test_unary_types() {
+        let mut gen = OpcodeGenerator::new("!(1 == 1)");
+        let context = ParseContext::new("!(1 == 1)");
+        let mut parser = Parser::new("!(1 == 1)", &context);
+        assert_eq!(
+            gen.gen_expr(&parser.parse_expression()),
+            vm_type::Type::Bool
+        );
+
+        let program = gen_body("let x = 2.5\n return -x", vm_type::Type::F32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_f32(-2.5));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not is not supported for I32")]
+    fn test_not_int() {
+        gen_body("let x = !(1)", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_min_max_abs() {
+        for (input, expected) in [("max(3, 7)", 7), ("min(3, 7)", 3), ("abs(-4)", 4)] {
+            let program = gen_body(&format!("return {}", input), vm_type::Type::I32);
+            let mut vm = Vm::new(&program, Vec::new(), Default::default());
+            assert_eq!(vm.run(), vm_type::encode_i32(expected));
+        }
+        let program = gen_body("let x = -1.5\n return max(abs(x), 1.0)", vm_type::Type::F32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_f32(1.5));
+    }
+
+    #[test]
+    #[should_panic(expected = "`min` expects I32, found F32")]
+    fn test_min_mixed_types() {
+        gen_body("let x = min(1, 2.0)", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_argument_error_span() {
+        static INPUT: &str = "let x = max(1, \"two\")";
+        let context = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &context);
+        let block = parser.parse_block();
+        let mut gen = OpcodeGenerator::new(INPUT);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            gen.gen_block(&block, vm_type::Type::Void)
+        }));
+        assert!(result.is_err());
+        let errors = gen.context.errors();
+        assert_eq!(errors[0].message, "`max` expects I32, found String(5)");
+        let (start, end) = errors[0].span.pos;
+        assert_eq!(&INPUT[start..end], "\"two\"");
+    }
+
+    #[test]
+    fn test_zeroed_locals() {
+        static INPUT: &str = "
+            fn f(a: i32) -> i32 {
+                if a > 0 {
+                    let x = 1
+                }
+                let y = 2
+                return y
+            }
+        ";
+        let module = gen_module(INPUT);
+        assert_eq!(module.borrow().get_fn_by_name("f").unwrap().locals(), 12);
+        let out = module
+            .borrow()
+            .run_function("f", vm_type::encode_i32(0).to_vec());
+        assert_eq!(out, Ok(vm_type::encode_i32(2).to_vec()));
+    }
+
+    #[test]
+    fn test_long_if_body() {
+        let input = format!(
+            "fn f(a: i32) -> i32 {{\n let n = 0\n if a > 0 {{\n{} }}\n return n\n}}",
+            "n = n + 1\n".repeat(60)
+        );
+        let module = gen_module(&input);
+        assert!(module.borrow().get_fn_by_name("f").unwrap().program().len() > 255);
+        for (a, expected) in [(0, 0), (1, 60)] {
+            let out = module
+                .borrow()
+                .run_function("f", vm_type::encode_i32(a).to_vec());
+            assert_eq!(out, Ok(vm_type::encode_i32(expected).to_vec()));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "fields not yet supported")]
+    fn test_field_unsupported() {
+        gen_body("let x = 1\n let y = x.y", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_run_args() {
+        let module = gen_module("fn add(a: i32, b: i32) -> i32 {\n return a + b\n}");
+        let module = module.borrow();
+        let add = module.get_fn_by_name("add").unwrap();
+        assert_eq!(
+            add.run_args(&[vm_type::Value::Int(2), vm_type::Value::Int(3)]),
+            Ok(vm_type::Value::Int(5))
+        );
+        assert_eq!(
+            add.run_args(&[vm_type::Value::Int(2)]),
+            Err(VmError::ArgumentCount {
+                expected: 2,
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_compile() {
+        let module = compile("fn main() { print_int(1) }").unwrap();
+        module.borrow().run_main();
+
+        let errors = compile("fn main() {\n print_int(true)\n}").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "`print_int` expects I32, found Bool");
+    }
+
+    #[test]
+    fn test_module_stats() {
+        static INPUT: &str = r"
+            fn double(x: i32) -> i32 { return x * 2 }
+            fn main() -> i32 { return double(2) }
+        ";
+        let module = gen_module(INPUT);
+        let module = module.borrow();
+        let double = module.function_index("double").unwrap();
+        let main = module.function_index("main").unwrap();
+        let stats = module.stats();
+        assert_eq!(stats.functions, 2);
+        // `LOAD_I, PUSH_I, MUL_I, RET_I` and `PUSH_I, CALL, RET_I`
+        assert_eq!(stats.bytecode, 9 + 9);
+        // The two function names with their length prefixes
+        assert_eq!(stats.constants, 2 + 6 + 2 + 4);
+        assert_eq!(stats.instructions, vec![(double, 4), (main, 3)]);
+    }
+
+    #[test]
+    fn test_unknown_function_span() {
+        static INPUT: &str = "fn main() {\n foo (1, 2)\n}";
+        let errors = compile(INPUT).unwrap_err();
+        assert_eq!(errors[0].message, "Unknown function");
+        let (start, end) = errors[0].span.pos;
+        assert_eq!(&INPUT[start..end], "foo");
+    }
+
+    #[test]
+    fn test_break_before_newline() {
+        // The call on the next line isn't a break value, just unreachable
+        assert!(compile("fn main() {\n loop {\n break\n print_int(1)\n }\n }").is_ok());
+    }
+
+    #[test]
+    fn test_float_registers() {
+        let program = gen_body(
+            "let x = 1.5\n print_int(1)\n return x * 2.0",
+            vm_type::Type::F32,
+        );
+        let disassembly = disassemble(&program);
+        assert!(disassembly.contains("sto_f"));
+        assert!(disassembly.contains("load_f"));
+        assert!(!disassembly.contains("sto_i"));
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_f32(3.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "too many local variables")]
+    fn test_too_many_locals() {
+        let input: String = (0..70).map(|i| format!("let x{} = {}\n", i, i)).collect();
+        gen_body(&input, vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_loop_labels() {
+        // The inner `break` only leaves the inner loop
+        static NESTED: &str = "
+            let outer = 0
+            let inner = 0
+            loop {
+                if outer == 3 { break }
+                outer = outer + 1
+                loop {
+                    inner = inner + 1
+                    break
+                }
+            }
+            return outer * 10 + inner
+        ";
+        let program = gen_body(NESTED, vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(33));
+
+        static LABELED: &str = "
+            let n = 0
+            'outer: loop {
+                loop {
+                    n = n + 1
+                    if n == 5 { break 'outer }
+                }
+            }
+            return n
+        ";
+        let program = gen_body(LABELED, vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "Use of undeclared label `'outer`")]
+    fn test_undeclared_label() {
+        gen_body("loop { break 'outer }", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_nested_break() {
+        let program = gen_body("loop { break; loop { break } }", vm_type::Type::Void);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), Vec::<u8>::new());
+
+        // The outer `break` is generated before the inner loop and must not be
+        // patched to the inner loop's exit
+        static EARLY: &str = "
+            let n = 0
+            loop {
+                n = n + 1
+                if n > 1 { break }
+                loop { break }
+                n = n + 10
+            }
+            return n
+        ";
+        let program = gen_body(EARLY, vm_type::Type::I32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(12));
+    }
+
+    #[test]
+    fn test_do_while() {
+        static ONCE: &str = "
+            let i = 0
+            let runs = 0
+            do {
+                print_int(i)
+                runs = runs + 1
+                i = i + 1
+            } while i < 0
+            return runs
+        ";
+        let program = gen_body(ONCE, vm_type::Type::I32);
+        let mut out = Vec::new();
+        let mut vm =
+            Vm::new(&program, Vec::new(), Default::default()).with_output(Box::new(&mut out));
+        assert_eq!(vm.run(), vm_type::encode_i32(1));
+        drop(vm);
+        assert_eq!(out, b"0\n");
+
+        let program = gen_body(
+            "let i = 0\n do { i = i + 1 } while i < 10\n return i",
+            vm_type::Type::I32,
+        );
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), vm_type::encode_i32(10));
+    }
+
+    #[test]
+    #[should_panic(expected = "`while` condition must be Bool, found I32")]
+    fn test_do_while_condition() {
+        gen_body("do { print_int(1) } while 1", vm_type::Type::Void);
+    }
+
+    #[test]
+    fn test_string_equality() {
+        for (input, expected) in [
+            ("return \"ab\" == \"ab\"", 1),
+            ("return \"ab\" == \"ac\"", 0),
+            ("return \"ab\" == \"abc\"", 0),
+            ("return \"ab\" != \"ac\"", 1),
+            ("let s = \"a\" + \"b\"\n return s == \"ab\"", 1),
+            ("return true == (1 < 2)", 1),
+        ] {
+            let module = gen_module(&format!("fn f() -> bool {{\n {}\n}}", input));
+            let out = module.borrow().run_function("f", vec![]);
+            assert_eq!(out, Ok(vec![expected]), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_implicit_return() {
+        let module =
+            gen_module("fn f() {}\n fn g(x: i32) {\n if x > 0 { return }\n print_int(x)\n }");
+        let module = module.borrow();
+        let f = module.get_fn_by_name("f").unwrap();
+        assert_eq!(f.program(), &vec![RET_V]);
+        assert_eq!(f.try_run(vec![]), Ok(vec![]));
+
+        let g = module.get_fn_by_name("g").unwrap();
+        assert_eq!(g.program().last(), Some(&RET_V));
+        assert_eq!(g.try_run(vm_type::encode_i32(0).to_vec()), Ok(vec![]));
+        assert_eq!(module.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_type_bounds() {
+        for (input, expected) in [
+            ("return i32_max()", i32::MAX),
+            ("return i32_min()", i32::MIN),
+            ("return max(i32_max() - 1, 0)", i32::MAX - 1),
+        ] {
+            let program = gen_body(input, vm_type::Type::I32);
+            let mut vm = Vm::new(&program, Vec::new(), Default::default());
+            assert_eq!(vm.run(), vm_type::encode_i32(expected), "{}", input);
+        }
+        for (input, expected) in [
+            ("return f32_max()", f32::MAX),
+            ("return f32_min()", f32::MIN),
+        ] {
+            let program = gen_body(input, vm_type::Type::F32);
+            let mut vm = Vm::new(&program, Vec::new(), Default::default());
+            assert_eq!(vm.run(), vm_type::encode_f32(expected), "{}", input);
+        }
+        let program = gen_body("return u32_max()", vm_type::Type::U32);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        assert_eq!(vm.run(), u32::MAX.to_be_bytes());
+
+        let module = compile("fn main() { print_int(i32_max()) }").unwrap();
+        let mut out = Vec::new();
+        let result = module
+            .borrow()
+            .run_function_with_output("main", vec![], &mut out);
+        assert_eq!(result, Ok(vec![]));
+        assert_eq!(out, b"2147483647\n");
+        let errors = compile("fn main() { print_int(f32_max()) }").unwrap_err();
+        assert_eq!(errors[0].message, "`print_int` expects I32, found F32");
+    }
+
+    #[test]
+    fn test_string_param_registers() {
+        static INPUT: &str = r#"
+            fn check(s: str, n: i32) -> bool {
+                let m = n + 1
+                let t = s + "!"
+                return s == "hello" && n == 5 && m == 6 && t == "hello!"
+            }
+            fn main() -> bool {
+                return check("hello", 5)
+            }
+        "#;
+        let module = gen_module(INPUT);
+        let module = module.borrow();
+        assert_eq!(module.run_function("main", vec![]), Ok(vec![1]));
+        let check = module.get_fn_by_name("check").unwrap();
+        // `s`, `n`, `m` and `t` which holds `s` and one more byte
+        let param = STR_LEN + 2;
+        assert_eq!(check.locals(), param + 4 + 4 + param + 1);
+
+        let input = format!(
+            "{}\n fn long() -> bool {{ return check(\"{}\", 5) }}",
+            INPUT,
+            "a".repeat(STR_LEN + 1)
+        );
+        let err = gen_module(&input)
+            .borrow()
+            .run_function("long", vec![])
+            .unwrap_err();
+        let too_long = VmError::StringTooLong {
+            max: STR_LEN,
+            found: STR_LEN + 1,
+        };
+        assert!(matches!(err, VmError::At(err, _) if *err == too_long));
+
+        let long = vm_type::Value::Str("a".repeat(STR_LEN + 1));
+        assert_eq!(
+            check.run_args(&[long, vm_type::Value::Int(5)]),
+            Err(VmError::ArgumentType {
+                index: 0,
+                expected: vm_type::Type::String(STR_LEN + 2)
+            })
+        );
+    }
+
+    #[test]
+    fn test_string_return_registers() {
+        static INPUT: &str = r#"
+            fn greet() -> str { return "hello" }
+            fn main() -> bool {
+                let s = greet()
+                let n = 5
+                print_str(s)
+                return s == "hello" && n == 5
+            }
+        "#;
+        let module = gen_module(INPUT);
+        let module = module.borrow();
+        let mut out = Vec::new();
+        let result = module.run_function_with_output("main", vec![], &mut out);
+        assert_eq!(result, Ok(vec![1]));
+        assert_eq!(out, b"hello\n");
+        // `s` has room for any string `greet` can return
+        let main = module.get_fn_by_name("main").unwrap();
+        assert_eq!(main.locals(), STR_LEN + 2 + 4);
+
+        let input = format!(
+            "fn long() -> str {{ return \"{}\" }}\n fn main() -> str {{ return long() }}",
+            "a".repeat(STR_LEN + 1)
+        );
+        let err = gen_module(&input)
+            .borrow()
+            .run_function("main", vec![])
+            .unwrap_err();
+        let too_long = VmError::StringTooLong {
+            max: STR_LEN,
+            found: STR_LEN + 1,
+        };
+        assert!(matches!(err, VmError::At(err, _) if *err == too_long));
+    }
+
+    #[test]
+    fn test_format_string_param() {
+        static INPUT: &str = r#"
+            fn greet(name: str) -> str {
+                return format("hi {}", name)
+            }
+            fn shout(name: str) -> str {
+                return format("{}!", greet(name)) + greet(name)
+            }
+        "#;
+        let module = gen_module(INPUT);
+        let module = module.borrow();
+        let args = [vm_type::Value::Str("bob".into())];
+        let greet = module.get_fn_by_name("greet").unwrap();
+        assert_eq!(
+            greet.run_args(&args),
+            Ok(vm_type::Value::Str("hi bob".into()))
+        );
+        let shout = module.get_fn_by_name("shout").unwrap();
+        assert_eq!(
+            shout.run_args(&args),
+            Ok(vm_type::Value::Str("hi bob!hi bob".into()))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "missing return")]
+    fn test_do_while_break_missing_return() {
+        gen_module("fn f(a: i32) -> i32 {\n do { if a > 0 { break } return 1 } while true\n }");
+    }
+
+    #[test]
+    fn test_returning_else_assigned() {
+        static INPUT: &str =
+            "fn f(a: i32) -> i32 {\n if a > 0 { let x = 1 } else { return 0 }\n return x\n }";
+        let module = gen_module(INPUT);
+        let module = module.borrow();
+        assert_eq!(
+            module.run_function("f", vm_type::encode_i32(3).to_vec()),
+            Ok(vm_type::encode_i32(1).to_vec())
+        );
+        assert_eq!(
+            module.run_function("f", vm_type::encode_i32(0).to_vec()),
+            Ok(vm_type::encode_i32(0).to_vec())
+        );
+    }
+}
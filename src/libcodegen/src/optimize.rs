@@ -0,0 +1,175 @@
+use libvm::consts::*;
+
+/// Returns the index of every instruction in the program
+/// ```
+/// # use libcodegen::optimize::*;
+/// # use libvm::consts::*;
+/// let program = [PUSH_I, 0, 0, 0, 1, GOTO, 0, 0];
+/// assert_eq!(instruction_starts(&program), vec![0, 5]);
+/// ```
+pub fn instruction_starts(program: &[u8]) -> Vec<usize> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < program.len() {
+        out.push(i);
+        i += 1 + operand_len(program[i]);
+    }
+    out
+}
+
+/// The target of the jump at `i`, `None` if it's operand is cut off
+fn jump_target(program: &[u8], i: usize) -> Option<usize> {
+    match program.get(i + 1..i + 3) {
+        Some(&[hi, lo]) => Some(u16::from_be_bytes([hi, lo]) as usize),
+        _ => None,
+    }
+}
+
+/// Rewrite jumps that land on an unconditional `GOTO` to jump straight to the
+/// final target of the chain.
+/// ```
+/// # use libcodegen::optimize::*;
+/// # use libvm::consts::*;
+/// let mut program = vec![GOTO, 0, 3, GOTO, 0, 6, RET_V];
+/// thread_jumps(&mut program);
+/// assert_eq!(program, vec![GOTO, 0, 6, GOTO, 0, 6, RET_V]);
+/// ```
+pub fn thread_jumps(program: &mut [u8]) {
+    let starts = instruction_starts(program);
+    let goto_target = |program: &[u8], i: usize| {
+        if starts.binary_search(&i).is_ok() && program.get(i) == Some(&GOTO) {
+            jump_target(program, i)
+        } else {
+            None
+        }
+    };
+    for &i in starts.iter() {
+        let mut target = match jump_target(program, i) {
+            Some(target) if is_jump(program[i]) => target,
+            _ => continue,
+        };
+        // Bound the walk so a cycle of gotos can't loop forever
+        for _ in 0..starts.len() {
+            match goto_target(program, target) {
+                Some(next) if next != target => target = next,
+                _ => break,
+            }
+        }
+        program[i + 1..i + 3].copy_from_slice(&(target as u16).to_be_bytes());
+    }
+}
+
+/// Remove `NOP`s and replace a `STO_I n` immediately followed by `LOAD_I n`
+/// (or the `f32` versions) with `DUP_I` and the store.  Jump targets are
+/// rewritten to account for the removed bytes.
+/// ```
+/// # use libcodegen::optimize::*;
+/// # use libvm::consts::*;
+/// let program = vec![NOP, STO_I, 0, LOAD_I, 0, GOTO, 0, 1];
+/// assert_eq!(peephole(&program), vec![DUP_I, STO_I, 0, GOTO, 0, 0]);
+/// ```
+pub fn peephole(program: &[u8]) -> Vec<u8> {
+    peephole_with_map(program).0
+}
+
+/// Like `peephole` but also returns the new offset of every old instruction
+pub(crate) fn peephole_with_map(program: &[u8]) -> (Vec<u8>, Vec<usize>) {
+    let starts = instruction_starts(program);
+    let targets: Vec<usize> = starts
+        .iter()
+        .filter(|&&i| is_jump(program[i]))
+        .filter_map(|&i| jump_target(program, i))
+        .collect();
+
+    let mut out = Vec::with_capacity(program.len());
+    // New index of each old index
+    let mut new_index = vec![0; program.len() + 1];
+    // Position of each jump operand in `out` along with it's old target
+    let mut jumps = Vec::new();
+    let mut i = 0;
+    while i < program.len() {
+        new_index[i] = out.len();
+        let len = 1 + operand_len(program[i]);
+        let next = i + len;
+        match program[i] {
+            NOP => {}
+            op @ (STO_I | STO_F)
+                if program.get(next) == Some(if op == STO_I { &LOAD_I } else { &LOAD_F })
+                    && program.get(next + 1) == program.get(i + 1)
+                    && !targets.contains(&next) =>
+            {
+                out.extend(&[DUP_I, op, program[i + 1]]);
+                i = next + 2;
+                continue;
+            }
+            op if is_jump(op) && jump_target(program, i).is_some() => {
+                out.push(op);
+                jumps.push((out.len(), jump_target(program, i).unwrap()));
+                out.extend(&[0, 0]);
+            }
+            _ => out.extend(&program[i..next.min(program.len())]),
+        }
+        i = next;
+    }
+    new_index[program.len()] = out.len();
+
+    for (operand, target) in jumps {
+        let target = new_index.get(target).copied().unwrap_or(target) as u16;
+        out[operand..operand + 2].copy_from_slice(&target.to_be_bytes());
+    }
+    (out, new_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libvm::vm::Vm;
+
+    #[test]
+    fn test_thread_jumps() {
+        // 0: push_i 7, 5: goto 10, 10: goto 14, 14: ret_i
+        let program = vec![
+            PUSH_I, 0, 0, 0, 7, GOTO, 0, 10, NOP, NOP, GOTO, 0, 14, NOP, RET_I,
+        ];
+        let mut threaded = program.clone();
+        thread_jumps(&mut threaded);
+        assert_eq!(&threaded[5..8], &[GOTO, 0, 14]);
+
+        let before = Vm::new(&program, Vec::new(), Default::default()).run();
+        let after = Vm::new(&threaded, Vec::new(), Default::default()).run();
+        assert_eq!(before, vec![0, 0, 0, 7]);
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_thread_jump_cycle() {
+        let mut program = vec![GOTO, 0, 3, GOTO, 0, 0];
+        thread_jumps(&mut program);
+        assert_eq!(program[0], GOTO);
+        assert_eq!(program[3], GOTO);
+    }
+
+    #[test]
+    fn test_peephole() {
+        // 0: push_i 3, 5: sto_i 0, 7: load_i 0, 9: nop, 10: push_i 1, 15: sub_i,
+        // 16: sto_i 0, 18: load_i 0, 20: nez, 21: if_f 27, 24: goto 7, 27: load_i 0,
+        // 29: ret_i
+        let program = vec![
+            PUSH_I, 0, 0, 0, 3, STO_I, 0, LOAD_I, 0, NOP, PUSH_I, 0, 0, 0, 1, SUB_I, STO_I, 0,
+            LOAD_I, 0, NEZ, IF_F, 0, 27, GOTO, 0, 7, LOAD_I, 0, RET_I,
+        ];
+        let optimized = peephole(&program);
+        assert_eq!(
+            instruction_starts(&optimized).len(),
+            instruction_starts(&program).len() - 1
+        );
+        assert!(instruction_starts(&optimized)
+            .iter()
+            .all(|&i| optimized[i] != NOP));
+
+        let before = Vm::new(&program, Vec::new(), Default::default()).run();
+        let after = Vm::new(&optimized, Vec::new(), Default::default()).run();
+        assert_eq!(before, vec![0, 0, 0, 0]);
+        assert_eq!(before, after);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::opcode::{OpcodeGenerator, Scope};
+use libparser::ast::Statement;
+use libparser::parse_context::{Diagnostic, ParseContext};
+use libparser::parser::Parser;
+use libvm::vm::{Vm, VmError};
+
+/// Why a line given to [`Repl::eval_line`](struct.Repl.html#method.eval_line)
+/// couldn't be evaluated
+#[derive(Debug, PartialEq)]
+pub enum ReplError {
+    /// The line failed to parse or generate
+    Compile(Vec<Diagnostic>),
+    /// The line failed while running
+    Runtime(VmError),
+}
+
+/// Evaluates input line by line, keeping variables and their registers between
+/// lines
+#[derive(Default)]
+pub struct Repl {
+    scope: Scope,
+    regs: Vec<u8>,
+}
+
+impl Repl {
+    /// Creates a new repl with no variables
+    pub fn new() -> Repl {
+        Default::default()
+    }
+
+    /// Parse, generate and run a line.  Returns the bytes left on the stack if
+    /// the line is an expression and `None` if it is a statement.  A line that
+    /// fails leaves the variables as they were before it.
+    /// ```
+    /// # use libcodegen::repl::*;
+    /// let mut repl = Repl::new();
+    /// assert_eq!(repl.eval_line("let x = 2"), Ok(None));
+    /// assert_eq!(repl.eval_line("x * 4"), Ok(Some(vec![0, 0, 0, 8])));
+    /// assert!(matches!(repl.eval_line("x * true"), Err(ReplError::Compile(_))));
+    /// ```
+    pub fn eval_line(&mut self, input: &str) -> Result<Option<Vec<u8>>, ReplError> {
+        let context = ParseContext::new(input);
+        let mut parser = Parser::new(input, &context);
+        let block = parser.parse_checked().map_err(ReplError::Compile)?;
+        let is_expression = matches!(block.body.as_slice(), [Statement::Expression(_)]);
+
+        let mut gen = OpcodeGenerator::new(input);
+        gen.set_scope(self.scope.clone());
+        gen.catch(|gen| gen.gen_block(&block, Default::default()))
+            .map_err(ReplError::Compile)?;
+        let program = gen.out();
+
+        let mut vm = Vm::new(&program, self.regs.clone(), gen.gen());
+        vm.try_run().map_err(ReplError::Runtime)?;
+        self.regs = vm.regs().to_vec();
+        self.scope = gen.scope();
+
+        if is_expression {
+            Ok(Some(vm.stack().to_vec()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libvm::vm_type;
+
+    #[test]
+    fn test_eval_line() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval_line("let x = 2"), Ok(None));
+        assert_eq!(
+            repl.eval_line("x + 3"),
+            Ok(Some(vm_type::encode_i32(5).to_vec()))
+        );
+        assert_eq!(repl.eval_line("let y = 2.5"), Ok(None));
+        assert_eq!(repl.eval_line("x = x * 10"), Ok(None));
+        assert_eq!(
+            repl.eval_line("x"),
+            Ok(Some(vm_type::encode_i32(20).to_vec()))
+        );
+        assert_eq!(
+            repl.eval_line("y"),
+            Ok(Some(vm_type::encode_f32(2.5).to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_eval_line_errors() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval_line("let x = 2"), Ok(None));
+        match repl.eval_line("x + z") {
+            Err(ReplError::Compile(errors)) => {
+                assert_eq!(errors[0].message, "Variable doesn't exist")
+            }
+            result => panic!("Expected a compile error, found {:?}", result),
+        }
+        assert!(matches!(
+            repl.eval_line("x + true"),
+            Err(ReplError::Compile(_))
+        ));
+        assert!(matches!(
+            repl.eval_line("let ="),
+            Err(ReplError::Compile(_))
+        ));
+        assert_eq!(
+            repl.eval_line("x = x / 0"),
+            Err(ReplError::Runtime(VmError::DivisionByZero))
+        );
+        // The session keeps it's variables after an error
+        assert_eq!(
+            repl.eval_line("x + 1"),
+            Ok(Some(vm_type::encode_i32(3).to_vec()))
+        );
+    }
+}
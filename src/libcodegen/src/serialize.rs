@@ -1,5 +1,10 @@
+use crate::opcode::compile;
+use libparser::parse_context::Diagnostic;
 use libvm::module::Module;
+pub use libvm::module::{FORMAT_MAJOR, FORMAT_MINOR};
+use libvm::vm::VmError;
 use std::cell::RefCell;
+use std::io::Write;
 use std::rc::Rc;
 
 pub struct ObjBuilder {
@@ -15,6 +20,11 @@ impl ObjBuilder {
         }
     }
 
+    /// Push a length or index as a 4 byte big-endian number
+    fn push_u32(&mut self, val: usize) {
+        self.out.extend((val as u32).to_be_bytes().iter());
+    }
+
     pub fn gen(&mut self) -> &[u8] {
         // Magic
         self.out.push(0xBB);
@@ -22,20 +32,181 @@ impl ObjBuilder {
         self.out.push(0xBB);
         self.out.push(0xBB);
 
-        self.out.push(0x00); // TODO: Major version
-        self.out.push(0x00); // TODO: Minor version
+        self.out.push(FORMAT_MAJOR);
+        self.out.push(FORMAT_MINOR);
 
-        self.out.push(self.module.borrow().constants().len() as u8); // Constants len
-        self.out.extend(self.module.borrow().constants().iter());
+        let module = Rc::clone(&self.module);
+        let module = module.borrow();
+        self.push_u32(module.constants().len()); // Constants len
+        self.out.extend(module.constants().iter());
 
-        for (i, func) in self.module.borrow().functions().iter() {
-            self.out.push(*i as u8);
-            self.out.push(func.params().len() as u8); // Params Len
+        for (i, func) in module.functions().iter() {
+            self.push_u32(*i);
+            self.push_u32(func.params().len()); // Params Len
             self.out
-                .extend(func.params().iter().map(|v| v.serialize()).flatten());
-            self.out.push(func.program().len() as u8); // Program Len
+                .extend(func.params().iter().flat_map(|v| v.serialize()));
+            self.out.extend(func.return_type().serialize());
+            self.push_u32(func.locals()); // Register bytes of locals
+            self.push_u32(func.program().len()); // Program Len
             self.out.extend(func.program().iter());
         }
         self.out.as_slice()
     }
 }
+
+/// Compile a program and serialize it to an object file
+/// ```
+/// # use libcodegen::serialize::*;
+/// let bytes = compile_to_object("fn main() { print_int(1) }").unwrap();
+/// assert_eq!(bytes[..4], [0xBB, 0xBB, 0xBB, 0xBB]);
+/// ```
+pub fn compile_to_object(input: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let module = compile(input)?;
+    Ok(ObjBuilder::new(module).gen().to_vec())
+}
+
+/// Load an object file and run it's `main` function, printing to `output`
+/// ```
+/// # use libcodegen::serialize::*;
+/// # use libvm::module::LoadError;
+/// # use libvm::vm::VmError;
+/// let result = run_object(&[0, 1, 2], &mut std::io::stdout());
+/// assert_eq!(result, Err(VmError::Load(LoadError::BadMagic)));
+/// ```
+pub fn run_object(bytes: &[u8], output: &mut dyn Write) -> Result<(), VmError> {
+    let module = Module::load(bytes).map_err(VmError::Load)?;
+    let result = module
+        .borrow()
+        .run_function_with_output("main", Vec::new(), output);
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::OpcodeGenerator;
+    use libparser::parse_context::ParseContext;
+    use libparser::parser::Parser;
+    use libvm::module::LoadError;
+    use libvm::vm_type;
+
+    #[test]
+    fn test_float_param_round_trip() {
+        static INPUT: &str = r"
+            fn half(x: f32) -> f32 {
+                return x / 2.0
+            }
+        ";
+        let context = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &context);
+        let mut gen = OpcodeGenerator::new(INPUT);
+        gen.gen_module(&parser.parse());
+
+        let bytes = ObjBuilder::new(gen.gen()).gen().to_vec();
+        let path = std::env::temp_dir().join("vimib_float_param_round_trip.o");
+        std::fs::write(&path, &bytes).unwrap();
+        let read = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let module = Module::load(&read).unwrap();
+        let module = module.borrow();
+        assert_eq!(module.constants(), gen.gen().borrow().constants());
+        let func = module.get_fn(0);
+        assert_eq!(func.params(), &vec![vm_type::Type::F32]);
+        assert_eq!(func.return_type(), &vm_type::Type::F32);
+
+        let mut stack = 2.5f32.to_be_bytes().to_vec();
+        let out = module
+            .call(0, &mut stack, 0, &mut std::io::stdout())
+            .unwrap();
+        assert_eq!(out, 1.25f32.to_be_bytes().to_vec());
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_peek_header() {
+        static INPUT: &str = r#"
+            fn a() {
+                print_str("hi")
+            }
+            fn b(x: i32) -> i32 {
+                return x
+            }
+        "#;
+        let context = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &context);
+        let mut gen = OpcodeGenerator::new(INPUT);
+        gen.gen_module(&parser.parse());
+
+        let bytes = ObjBuilder::new(gen.gen()).gen().to_vec();
+        let header = Module::peek_header(&bytes).unwrap();
+        assert_eq!(header.functions, 2);
+        assert_eq!(header.constants, 3);
+        assert_eq!(
+            Module::peek_header(&bytes[..bytes.len() - 1]),
+            Err(LoadError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn test_long_program_round_trip() {
+        let input = format!("fn main() {{\n{}}}", "print_int(1)\n".repeat(60));
+        let context = ParseContext::new(&input);
+        let mut parser = Parser::new(&input, &context);
+        let mut gen = OpcodeGenerator::new(&input);
+        gen.gen_module(&parser.parse());
+        let program = gen.gen().borrow().get_main().program().clone();
+        assert!(program.len() > 255);
+
+        let bytes = ObjBuilder::new(gen.gen()).gen().to_vec();
+        let module = Module::load(&bytes).unwrap();
+        assert_eq!(module.borrow().get_main().program(), &program);
+    }
+
+    #[test]
+    fn test_version_check() {
+        static INPUT: &str = "fn main() {}";
+        let context = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &context);
+        let mut gen = OpcodeGenerator::new(INPUT);
+        gen.gen_module(&parser.parse());
+
+        let mut bytes = ObjBuilder::new(gen.gen()).gen().to_vec();
+        assert_eq!(&bytes[4..6], &[FORMAT_MAJOR, FORMAT_MINOR]);
+        bytes[5] = FORMAT_MINOR + 1;
+        assert!(Module::load(&bytes).is_ok());
+        bytes[4] = FORMAT_MAJOR + 1;
+        assert_eq!(
+            Module::load(&bytes),
+            Err(LoadError::UnsupportedVersion {
+                found: FORMAT_MAJOR + 1,
+                expected: FORMAT_MAJOR
+            })
+        );
+    }
+
+    #[test]
+    fn test_object_round_trip() {
+        static INPUT: &str = r#"
+            fn greet(name: str) -> str {
+                return "Hello, " + name
+            }
+            fn main() {
+                assert(greet("World") == "Hello, World")
+                print_str(greet("World"))
+            }
+        "#;
+        let bytes = compile_to_object(INPUT).unwrap();
+        assert_eq!(Module::load(&bytes).unwrap().borrow().verify(), Ok(()));
+        let mut out = Vec::new();
+        assert_eq!(run_object(&bytes, &mut out), Ok(()));
+        assert_eq!(String::from_utf8(out).unwrap(), "Hello, World\n");
+
+        let bytes = compile_to_object("fn main() {\n assert(1 > 2)\n}").unwrap();
+        let result = run_object(&bytes, &mut Vec::new());
+        assert_eq!(result, Err(VmError::AssertionFailed));
+
+        let errors = compile_to_object("fn main() {\n print_int(true)\n}").unwrap_err();
+        assert_eq!(errors[0].message, "`print_int` expects I32, found Bool");
+    }
+}
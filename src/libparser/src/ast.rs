@@ -1,12 +1,18 @@
 use crate::lexer::{self, TokenKind};
 use crate::span::Span;
+use std::convert::TryFrom;
 
 /// Type of a literal is unsized
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum LiteralKind {
     String,
     Int,
+    /// An unsigned integer written with the `u32` suffix
+    UInt,
     Float,
+    /// `true` or `false`
+    Bool,
 }
 
 impl From<lexer::LiteralKind> for LiteralKind {
@@ -15,12 +21,14 @@ impl From<lexer::LiteralKind> for LiteralKind {
         match kind {
             lexer::LiteralKind::Float => LiteralKind::Float,
             lexer::LiteralKind::Int => LiteralKind::Int,
+            lexer::LiteralKind::UInt => LiteralKind::UInt,
             lexer::LiteralKind::String => LiteralKind::String,
         }
     }
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Op {
     Star,
     Slash,
@@ -34,12 +42,28 @@ pub enum Op {
     Lt,
     Gt,
     Not,
+    And,
+    Or,
+    Shl,
+    Shr,
+    /// Bitwise exclusive or
+    Xor,
 }
 
-impl From<TokenKind> for Op {
-    /// Converts from lexer token kind to ast op. Panics if token kind is not an op.
-    fn from(token_kind: TokenKind) -> Op {
-        match token_kind {
+impl TryFrom<TokenKind> for Op {
+    type Error = TokenKind;
+
+    /// Converts from lexer token kind to ast op.  Returns the token kind back
+    /// if it is not an op.
+    /// ```
+    /// # use libparser::ast::Op;
+    /// # use libparser::lexer::TokenKind;
+    /// # use std::convert::TryFrom;
+    /// assert_eq!(Op::try_from(TokenKind::AndAnd), Ok(Op::And));
+    /// assert_eq!(Op::try_from(TokenKind::Comma), Err(TokenKind::Comma));
+    /// ```
+    fn try_from(token_kind: TokenKind) -> Result<Op, TokenKind> {
+        Ok(match token_kind {
             TokenKind::Star => Op::Star,
             TokenKind::Slash => Op::Slash,
             TokenKind::Plus => Op::Plus,
@@ -52,40 +76,104 @@ impl From<TokenKind> for Op {
             TokenKind::Lt => Op::Lt,
             TokenKind::Gt => Op::Gt,
             TokenKind::Not => Op::Not,
-            _ => panic!("Not an operator"),
-        }
+            TokenKind::AndAnd => Op::And,
+            TokenKind::OrOr => Op::Or,
+            TokenKind::LtLt => Op::Shl,
+            TokenKind::GtGt => Op::Shr,
+            TokenKind::Caret => Op::Xor,
+            kind => return Err(kind),
+        })
     }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Expression {
-    Literal { val: Span, kind: LiteralKind },
+    Literal {
+        val: Span,
+        kind: LiteralKind,
+    },
+    /// The span covers both operands
     Binary(Box<Expression>, Op, Box<Expression>, Span),
+    /// The span covers the operator and operand
     Unary(Op, Box<Expression>, Span),
-    Ident { val: Span },
-    FunctionCall(Span, Vec<Expression>),
+    Ident {
+        val: Span,
+    },
+    /// The callee's name, each argument with the span of it's source text and
+    /// the span of the whole call
+    FunctionCall(Span, Vec<(Expression, Span)>, Span),
+    /// `start..end`, only used by `for` loops
+    Range(Box<Expression>, Box<Expression>, Span),
+    /// The unit value `()`
+    Unit(Span),
+    /// `loop { ... }` in value position, the span is the `loop` keyword
+    Loop(Block, Span),
+    /// `object.field`, the span is the field name
+    Field(Box<Expression>, Span),
+    /// `object.method(args)`, the span is the method name
+    MethodCall(Box<Expression>, Span, Vec<(Expression, Span)>),
     Dummy,
 }
 
+impl Expression {
+    /// The span of the expression's source text.  Parentheses around the whole
+    /// expression aren't part of it, and a `loop` expression only covers it's
+    /// keyword.
+    /// # Examples
+    /// ```
+    /// # use libparser::parse_context::ParseContext;
+    /// # use libparser::parser::Parser;
+    /// static INPUT: &str = "f(1) * -x";
+    /// let context = ParseContext::new(INPUT);
+    /// let span = Parser::new(INPUT, &context).parse_expression().span().unwrap();
+    /// assert_eq!(span.pos, (0, 9));
+    /// ```
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Expression::Literal { val, .. } | Expression::Ident { val } => Some(*val),
+            Expression::Binary(.., span)
+            | Expression::Unary(.., span)
+            | Expression::FunctionCall(.., span)
+            | Expression::Unit(span)
+            | Expression::Loop(_, span) => Some(*span),
+            Expression::Range(start, end, _) => Some(Span::merge(start.span()?, end.span()?)),
+            Expression::Field(object, name) => Some(Span::merge(object.span()?, *name)),
+            Expression::MethodCall(object, name, args) => {
+                let end = args.last().map_or(*name, |(_, span)| *span);
+                Some(Span::merge(object.span()?, end))
+            }
+            Expression::Dummy => None,
+        }
+    }
+}
+
 /// Parser type (not literal kind)
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Type {
     Str,
     Int,
+    UInt,
     Float,
+    Bool,
     Void,
 }
 
 /// Identifier (for parameters and assignment)
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Ident {
     Typed(Span, Type),
     Untyped(Span),
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Statement {
     Assign(Span, Expression),
+    /// An immutable binding
+    Const(Span, Expression),
     FnDecl {
         name: Span,
         return_type: Type,
@@ -94,15 +182,132 @@ pub enum Statement {
     },
     Return(Expression, Span),
     Mutate(Span, Expression),
-    If(Expression, Block, Option<Box<Statement>>),
-    Else(Block),
-    Loop(Block),
-    Break,
+    /// The first span is the `if` keyword, or `else if` for a later arm of a
+    /// chain, and the second is the arm's braces
+    If(Expression, Block, Option<Box<Statement>>, Span, Span),
+    /// The first span is the `else` keyword and the second is it's braces
+    Else(Block, Span, Span),
+    /// `loop { ... }` with an optional label, the label's span includes the
+    /// leading `'`
+    Loop(Option<Span>, Block),
+    /// `do { ... } while cond`, the body runs before the condition is tested
+    DoWhile(Block, Expression),
+    /// `for ident in range { ... }`
+    For(Span, Expression, Block),
+    /// A nested block with it's own scope
+    Block(Block),
+    /// `break` with an optional label of the loop to exit and an optional
+    /// value for a `loop` expression, the span is the `break` keyword
+    Break(Option<Span>, Option<Expression>, Span),
     Expression(Expression),
     Dummy,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Block {
     pub body: Vec<Statement>,
 }
+
+/// Walks the ast.  Each method defaults to visiting the node's children so
+/// implementors only override the nodes they care about.
+/// # Examples
+/// ```
+/// # use libparser::ast::*;
+/// # use libparser::parser::Parser;
+/// # use libparser::parse_context::ParseContext;
+/// struct CallCounter(usize);
+///
+/// impl Visitor for CallCounter {
+///     fn visit_expr(&mut self, expr: &Expression) {
+///         if let Expression::FunctionCall(..) = expr {
+///             self.0 += 1;
+///         }
+///         walk_expr(self, expr);
+///     }
+/// }
+///
+/// static INPUT: &str = "f(g())";
+/// let context = ParseContext::new(INPUT);
+/// let mut parser = Parser::new(INPUT, &context);
+/// let mut counter = CallCounter(0);
+/// counter.visit_block(&parser.parse());
+/// assert_eq!(counter.0, 2);
+/// ```
+pub trait Visitor: Sized {
+    fn visit_block(&mut self, block: &Block) {
+        walk_block(self, block);
+    }
+
+    fn visit_stmt(&mut self, stmt: &Statement) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expression) {
+        walk_expr(self, expr);
+    }
+}
+
+/// Visit each statement in a block
+pub fn walk_block<V: Visitor>(visitor: &mut V, block: &Block) {
+    for stmt in block.body.iter() {
+        visitor.visit_stmt(stmt);
+    }
+}
+
+/// Visit the blocks, statements and expressions directly inside a statement
+pub fn walk_stmt<V: Visitor>(visitor: &mut V, stmt: &Statement) {
+    match stmt {
+        Statement::Assign(_, expr)
+        | Statement::Const(_, expr)
+        | Statement::Return(expr, _)
+        | Statement::Mutate(_, expr)
+        | Statement::Expression(expr) => visitor.visit_expr(expr),
+        Statement::FnDecl { block, .. }
+        | Statement::Else(block, ..)
+        | Statement::Loop(_, block)
+        | Statement::Block(block) => visitor.visit_block(block),
+        Statement::For(_, expr, block) => {
+            visitor.visit_expr(expr);
+            visitor.visit_block(block);
+        }
+        Statement::DoWhile(block, expr) => {
+            visitor.visit_block(block);
+            visitor.visit_expr(expr);
+        }
+        Statement::If(expr, block, next, ..) => {
+            visitor.visit_expr(expr);
+            visitor.visit_block(block);
+            if let Some(next) = next {
+                visitor.visit_stmt(next);
+            }
+        }
+        Statement::Break(_, Some(expr), _) => visitor.visit_expr(expr),
+        Statement::Break(_, None, _) | Statement::Dummy => {}
+    }
+}
+
+/// Visit the expressions directly inside an expression
+pub fn walk_expr<V: Visitor>(visitor: &mut V, expr: &Expression) {
+    match expr {
+        Expression::Binary(lhs, _, rhs, _) | Expression::Range(lhs, rhs, _) => {
+            visitor.visit_expr(lhs);
+            visitor.visit_expr(rhs);
+        }
+        Expression::Unary(_, expr, _) | Expression::Field(expr, _) => visitor.visit_expr(expr),
+        Expression::MethodCall(object, _, args) => {
+            visitor.visit_expr(object);
+            for (arg, _) in args.iter() {
+                visitor.visit_expr(arg);
+            }
+        }
+        Expression::Loop(block, _) => visitor.visit_block(block),
+        Expression::FunctionCall(_, args, _) => {
+            for (arg, _) in args.iter() {
+                visitor.visit_expr(arg);
+            }
+        }
+        Expression::Literal { .. } | Expression::Ident { .. } | Expression::Unit(_) => {}
+        Expression::Dummy => {}
+    }
+}
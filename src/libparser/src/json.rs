@@ -0,0 +1,61 @@
+use crate::ast::Block;
+use serde_json::Value;
+
+/// Serialize a parsed block to pretty printed JSON.  Spans are replaced by the
+/// source text they cover in `input` so the output is readable on it's own.
+/// # Examples
+/// ```
+/// # use libparser::parse_context::ParseContext;
+/// # use libparser::parser::Parser;
+/// # use libparser::json::ast_to_json;
+/// static INPUT: &str = "print_int(2)";
+/// let context = ParseContext::new(INPUT);
+/// let block = Parser::new(INPUT, &context).parse_block();
+/// assert!(ast_to_json(INPUT, &block).contains("\"print_int\""));
+/// ```
+pub fn ast_to_json(input: &str, block: &Block) -> String {
+    let mut value = serde_json::to_value(block).expect("The AST is always serializable");
+    resolve_spans(input, &mut value);
+    serde_json::to_string_pretty(&value).unwrap()
+}
+
+/// Replace every serialized span in `value` with it's source text
+fn resolve_spans(input: &str, value: &mut Value) {
+    match value {
+        Value::Object(map) if map.len() == 2 && map.contains_key("is_dummy") => {
+            let start = map["pos"][0].as_u64().unwrap_or(0) as usize;
+            let end = map["pos"][1].as_u64().unwrap_or(0) as usize;
+            *value = if map["is_dummy"] == Value::Bool(true) {
+                Value::Null
+            } else {
+                Value::String(String::from(&input[start..end]))
+            };
+        }
+        Value::Object(map) => map.values_mut().for_each(|v| resolve_spans(input, v)),
+        Value::Array(values) => values.iter_mut().for_each(|v| resolve_spans(input, v)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_context::ParseContext;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_ast_to_json() {
+        static INPUT: &str = "let x = 1";
+        let context = ParseContext::new(INPUT);
+        let block = Parser::new(INPUT, &context).parse_block();
+        let json: Value = serde_json::from_str(&ast_to_json(INPUT, &block)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "body": [{
+                    "Assign": ["x", { "Literal": { "val": "1", "kind": "Int" } }]
+                }]
+            })
+        );
+    }
+}
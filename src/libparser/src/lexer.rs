@@ -1,11 +1,15 @@
 use crate::parse_context::ParseContext;
 use crate::span::Span;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::str::Chars;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum LiteralKind {
     Float,
     Int,
+    /// An integer with the `u32` suffix
+    UInt,
     String,
 }
 
@@ -15,21 +19,34 @@ pub enum TokenKind {
     Whitespace,
 
     Identifier,
+    /// A loop label, `'name`
+    Label,
 
     Literal(LiteralKind),
 
     /// Keywords
     Let,
+    Const,
     Fn,
     If,
     Else,
     Break,
     Loop,
+    Do,
+    While,
+    For,
+    In,
     Return,
+    True,
+    False,
 
     /// Types
     I32,
+    U32,
     F32,
+    Str,
+    Bool,
+    Void,
 
     /// Delimiter
     OpenParen,
@@ -65,6 +82,9 @@ pub enum TokenKind {
     OrOr,
     NotEqual,
     Arrow,
+    DotDot,
+    LtLt,
+    GtGt,
 
     Semi,
     Eof,
@@ -76,14 +96,25 @@ pub enum TokenKind {
 fn keyword(text: &str) -> Option<TokenKind> {
     match text {
         "let" => Some(TokenKind::Let),
+        "const" => Some(TokenKind::Const),
         "fn" => Some(TokenKind::Fn),
         "if" => Some(TokenKind::If),
         "else" => Some(TokenKind::Else),
         "break" => Some(TokenKind::Break),
         "loop" => Some(TokenKind::Loop),
+        "do" => Some(TokenKind::Do),
+        "while" => Some(TokenKind::While),
+        "for" => Some(TokenKind::For),
+        "in" => Some(TokenKind::In),
         "return" => Some(TokenKind::Return),
+        "true" => Some(TokenKind::True),
+        "false" => Some(TokenKind::False),
         "i32" => Some(TokenKind::I32),
+        "u32" => Some(TokenKind::U32),
         "f32" => Some(TokenKind::F32),
+        "str" => Some(TokenKind::Str),
+        "bool" => Some(TokenKind::Bool),
+        "void" => Some(TokenKind::Void),
         _ => None,
     }
 }
@@ -120,29 +151,37 @@ fn is_whitespace(c: char) -> bool {
 
 /// Is the first char of identifier
 fn is_ident_first(c: char) -> bool {
-    match c {
-        'A'..='Z' | 'a'..='z' | '_' => true,
-        _ => false,
-    }
+    matches!(c, 'A'..='Z' | 'a'..='z' | '_')
 }
 
 /// Is an identifier char
 fn is_ident(c: char) -> bool {
-    match c {
-        'A'..='Z' | 'a'..='z' | '_' | '0'..='9' => true,
-        _ => false,
-    }
+    matches!(c, 'A'..='Z' | 'a'..='z' | '_' | '0'..='9')
 }
 
 #[derive(Clone)]
 struct Tokenizer<'a> {
     input: &'a str,
     pos: usize,
+    keep_comments: bool,
+    /// Number of tokens produced so far
+    produced: usize,
 }
 
 impl<'a> Tokenizer<'a> {
-    fn new(input: &str) -> Tokenizer {
-        Tokenizer { input, pos: 0 }
+    fn new(input: &str) -> Tokenizer<'_> {
+        Tokenizer::at(input, 0)
+    }
+
+    /// Tokenize `input` starting at the byte `offset`, spans stay relative to
+    /// the start of `input`
+    fn at(input: &str, offset: usize) -> Tokenizer<'_> {
+        Tokenizer {
+            input: &input[offset..],
+            pos: offset,
+            keep_comments: false,
+            produced: 0,
+        }
     }
 }
 
@@ -155,9 +194,12 @@ impl<'a> Iterator for Tokenizer<'a> {
         let token_len = Cursor::new(self.input).next_token();
         self.pos += token_len.len;
         self.input = &self.input[token_len.len..];
-        if token_len.kind == TokenKind::Whitespace || token_len.kind == TokenKind::Comment {
+        if token_len.kind == TokenKind::Whitespace
+            || (token_len.kind == TokenKind::Comment && !self.keep_comments)
+        {
             self.next()
         } else {
+            self.produced += 1;
             Some(Token {
                 kind: token_len.kind,
                 span: Span::new(self.pos - token_len.len, self.pos),
@@ -172,7 +214,7 @@ struct Cursor<'a> {
 }
 
 impl<'a> Cursor<'a> {
-    pub fn new(input: &'a str) -> Cursor {
+    pub fn new(input: &'a str) -> Cursor<'a> {
         Cursor {
             chars: input.chars(),
             len: input.len(),
@@ -183,7 +225,6 @@ impl<'a> Cursor<'a> {
         self.chars().nth(n).unwrap_or('\0')
     }
 
-    #[allow(dead_code)]
     pub fn is_eof(&self) -> bool {
         self.chars.as_str().is_empty()
     }
@@ -218,6 +259,7 @@ impl<'a> Cursor<'a> {
                     loop {
                         match self.peek(0) {
                             '\n' => break,
+                            '\0' if self.is_eof() => break,
                             _ => {
                                 self.next();
                             }
@@ -229,6 +271,8 @@ impl<'a> Cursor<'a> {
                     self.next();
                     loop {
                         match self.peek(0) {
+                            // Unterminated block comment
+                            '\0' if self.is_eof() => break,
                             '*' => match self.peek(1) {
                                 '/' => break,
                                 _ => {
@@ -250,13 +294,23 @@ impl<'a> Cursor<'a> {
 
             // String literal
             '"' => {
-                while self.peek(0) != '"' {
-                    self.next();
+                while self.peek(0) != '"' && !self.is_eof() {
+                    if self.next() == Some('\\') {
+                        self.next();
+                    }
                 }
                 self.next();
                 TokenKind::Literal(LiteralKind::String)
             }
 
+            // Loop label
+            '\'' if is_ident_first(self.peek(0)) => {
+                while is_ident(self.peek(0)) {
+                    self.next();
+                }
+                TokenKind::Label
+            }
+
             // Number Literal
             '0'..='9' => {
                 let mut has_dot = false;
@@ -264,7 +318,8 @@ impl<'a> Cursor<'a> {
                     match self.peek(0) {
                         '.' => {
                             if let Some(c) = self.chars().nth(1) {
-                                if is_ident_first(c) {
+                                // A method call or a range
+                                if is_ident_first(c) || c == '.' {
                                     break;
                                 }
                             }
@@ -277,7 +332,15 @@ impl<'a> Cursor<'a> {
                         _ => break,
                     }
                 }
-                TokenKind::Literal(if has_dot {
+                // Keep trailing identifier chars in the literal so `123abc`
+                // is reported as one bad number
+                let mut suffix = String::new();
+                while is_ident(self.peek(0)) {
+                    suffix.extend(self.next());
+                }
+                TokenKind::Literal(if suffix == "u32" && !has_dot {
+                    LiteralKind::UInt
+                } else if suffix == "f32" || has_dot {
                     LiteralKind::Float
                 } else {
                     LiteralKind::Int
@@ -309,7 +372,14 @@ impl<'a> Cursor<'a> {
             ']' => TokenKind::CloseBracket,
 
             ',' => TokenKind::Comma,
-            '.' => TokenKind::Dot,
+            '.' => {
+                if self.peek(0) == '.' {
+                    self.next();
+                    TokenKind::DotDot
+                } else {
+                    TokenKind::Dot
+                }
+            }
             '?' => TokenKind::Question,
             ':' => TokenKind::Colon,
             '+' => TokenKind::Plus,
@@ -350,7 +420,10 @@ impl<'a> Cursor<'a> {
                 }
             }
             '<' => {
-                if self.peek(0) == '=' {
+                if self.peek(0) == '<' {
+                    self.next();
+                    TokenKind::LtLt
+                } else if self.peek(0) == '=' {
                     self.next();
                     TokenKind::LtEqual
                 } else {
@@ -358,7 +431,10 @@ impl<'a> Cursor<'a> {
                 }
             }
             '>' => {
-                if self.peek(0) == '=' {
+                if self.peek(0) == '>' {
+                    self.next();
+                    TokenKind::GtGt
+                } else if self.peek(0) == '=' {
                     self.next();
                     TokenKind::GtEqual
                 } else {
@@ -398,20 +474,141 @@ fn eof() -> Token {
     }
 }
 
-/// Lexer has a tokenizer and a parse context for error handling
+/// Report malformed tokens as they are produced
+fn check(input: &str, context: &ParseContext, token: Token) {
+    let text = &input[token.span.pos.0..token.span.pos.1];
+    let digits = match token.kind {
+        TokenKind::Literal(LiteralKind::Int) => text.strip_suffix("i32").unwrap_or(text),
+        TokenKind::Literal(LiteralKind::UInt) => text.strip_suffix("u32").unwrap_or(text),
+        TokenKind::Literal(LiteralKind::Float) => text.strip_suffix("f32").unwrap_or(text),
+        _ => return,
+    };
+    if digits.ends_with("i32") || digits.ends_with("u32") {
+        context.error(token.span, "Integer suffix on a float literal");
+    } else if !digits.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        context.error(token.span, "Invalid number literal");
+    }
+}
+
+/// Tokenize the whole input, skipping whitespace and comments, up to and
+/// including `Eof`.  Malformed tokens are returned as is without reporting an
+/// error.
+/// # Examples
+/// ```
+/// # use libparser::lexer::*;
+/// let kinds: Vec<TokenKind> = tokenize("1 + 2").iter().map(|t| t.kind).collect();
+/// assert_eq!(kinds.len(), 4);
+/// assert_eq!(kinds[3], TokenKind::Eof);
+/// ```
+pub fn tokenize(input: &str) -> Vec<Token> {
+    Tokenizer::new(input)
+        .chain(std::iter::once(eof()))
+        .collect()
+}
+
+/// Like `tokenize` but keeps `Comment` tokens, which is useful for highlighting
+/// # Examples
+/// ```
+/// # use libparser::lexer::*;
+/// let tokens = tokenize_with_trivia("// hi\nlet");
+/// assert_eq!(tokens[0].kind, TokenKind::Comment);
+/// assert_eq!(tokens[1].kind, TokenKind::Let);
+/// ```
+pub fn tokenize_with_trivia(input: &str) -> Vec<Token> {
+    let mut tokens = Tokenizer::new(input);
+    tokens.keep_comments = true;
+    tokens.chain(std::iter::once(eof())).collect()
+}
+
+/// Lexer has a tokenizer and a parse context for error handling.  Tokens that
+/// have been peeked are kept in a lookahead buffer so each token is only
+/// tokenized once.
 pub struct Lexer<'a> {
-    tokens: Tokenizer<'a>,
+    input: &'a str,
+    tokens: RefCell<Tokenizer<'a>>,
+    lookahead: RefCell<VecDeque<Token>>,
+    /// End of the last token returned by `next`
+    prev_end: usize,
     pub context: &'a ParseContext<'a>,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str, context: &'a ParseContext<'a>) -> Lexer<'a> {
+        Lexer::at(input, 0, context)
+    }
+
+    /// Create a lexer that starts tokenizing at a byte offset into `input`, for
+    /// resuming after an edit.  Spans are still offsets into all of `input`.
+    /// # Panics
+    /// If `offset` isn't on a char boundary of `input`
+    /// # Examples
+    /// ```
+    /// # use libparser::lexer::*;
+    /// let context = Default::default();
+    /// let mut lexer = Lexer::at("let x = 1", 6, &context);
+    /// assert_eq!(lexer.next().kind, TokenKind::Equal);
+    /// ```
+    pub fn at(input: &'a str, offset: usize, context: &'a ParseContext<'a>) -> Lexer<'a> {
         Lexer {
-            tokens: Tokenizer::new(input),
+            input,
+            tokens: RefCell::new(Tokenizer::at(input, offset)),
+            lookahead: RefCell::new(VecDeque::new()),
+            prev_end: offset,
             context,
         }
     }
 
+    /// The byte offset lexing continues from, the start of the next token or
+    /// whitespace before it
+    /// # Examples
+    /// ```
+    /// # use libparser::lexer::*;
+    /// let context = Default::default();
+    /// let mut lexer = Lexer::new("let x = 1", &context);
+    /// lexer.next();
+    /// assert_eq!(lexer.position(), 3);
+    /// lexer.peek(0);
+    /// assert_eq!(lexer.position(), 4);
+    /// ```
+    pub fn position(&self) -> usize {
+        match self.lookahead.borrow().front() {
+            Some(token) => token.span.pos.0,
+            None => self.tokens.borrow().pos,
+        }
+    }
+
+    /// Tokenize ahead until the lookahead buffer holds at least `n + 1` tokens
+    /// or the input runs out
+    fn fill(&self, n: usize) {
+        let mut lookahead = self.lookahead.borrow_mut();
+        let mut tokens = self.tokens.borrow_mut();
+        while lookahead.len() <= n {
+            match tokens.next() {
+                Some(token) => {
+                    check(self.input, self.context, token);
+                    lookahead.push_back(token)
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Create a lexer that keeps `Comment` tokens (still skipping whitespace).
+    /// Useful for tooling such as formatters that can't lose comments.
+    /// # Examples
+    /// ```
+    /// # use libparser::lexer::*;
+    /// let context = Default::default();
+    /// let mut lexer = Lexer::new_with_trivia("// hi\nlet", &context);
+    /// assert_eq!(lexer.next().kind, TokenKind::Comment);
+    /// assert_eq!(lexer.next().kind, TokenKind::Let);
+    /// ```
+    pub fn new_with_trivia(input: &'a str, context: &'a ParseContext<'a>) -> Lexer<'a> {
+        let mut lexer = Lexer::new(input, context);
+        lexer.tokens.get_mut().keep_comments = true;
+        lexer
+    }
+
     /// Get next token and consume it
     /// # Examples
     /// ```
@@ -424,7 +621,87 @@ impl<'a> Lexer<'a> {
     /// ```
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Token {
-        self.tokens.next().unwrap_or_else(eof)
+        self.fill(0);
+        let token = self.lookahead.get_mut().pop_front().unwrap_or_else(eof);
+        if token.kind != TokenKind::Eof {
+            self.prev_end = token.span.pos.1;
+        }
+        token
+    }
+
+    /// The byte offset just past the last consumed token
+    /// # Examples
+    /// ```
+    /// # use libparser::lexer::*;
+    /// let context = Default::default();
+    /// let mut lexer = Lexer::new("234 + ident", &context);
+    /// lexer.next();
+    /// lexer.next();
+    /// assert_eq!(lexer.prev_end(), 5);
+    /// ```
+    pub fn prev_end(&self) -> usize {
+        self.prev_end
+    }
+
+    /// Is there a line break between the last token returned by `next` and the
+    /// next token
+    /// # Examples
+    /// ```
+    /// # use libparser::lexer::*;
+    /// let context = Default::default();
+    /// let mut lexer = Lexer::new("break\n x", &context);
+    /// assert!(!lexer.newline_before_next());
+    /// lexer.next();
+    /// assert!(lexer.newline_before_next());
+    /// ```
+    pub fn newline_before_next(&self) -> bool {
+        let next = self.peek(0);
+        next.kind != TokenKind::Eof
+            && self
+                .input
+                .get(self.prev_end..next.span.pos.0)
+                .is_some_and(|between| between.contains('\n'))
+    }
+
+    /// Consume the lexer and iterate over the remaining tokens up to and
+    /// including `Eof`
+    /// # Examples
+    /// ```
+    /// # use libparser::lexer::*;
+    /// let context = Default::default();
+    /// let lexer = Lexer::new("234 + ident", &context);
+    /// let kinds: Vec<TokenKind> = lexer.tokens().map(|t| t.kind).collect();
+    /// assert_eq!(kinds.last(), Some(&TokenKind::Eof));
+    /// ```
+    pub fn tokens(self) -> impl Iterator<Item = Token> + 'a {
+        let (input, context) = (self.input, self.context);
+        let mut tokens = self.lookahead.into_inner().into_iter().chain(
+            self.tokens
+                .into_inner()
+                .inspect(move |token| check(input, context, *token)),
+        );
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let token = tokens.next().unwrap_or_else(eof);
+            done = token.kind == TokenKind::Eof;
+            Some(token)
+        })
+    }
+
+    /// The source text of a token
+    /// # Examples
+    /// ```
+    /// # use libparser::lexer::*;
+    /// let context = Default::default();
+    /// let mut lexer = Lexer::new("234 + ident", &context);
+    /// let token = lexer.next();
+    /// assert_eq!(lexer.text(token), "234");
+    /// ```
+    pub fn text(&self, token: Token) -> &'a str {
+        &self.input[token.span.pos.0..token.span.pos.1]
     }
 
     /// Peek `n` tokens ahead
@@ -438,7 +715,8 @@ impl<'a> Lexer<'a> {
     /// assert_eq!(lexer.peek(2).kind, TokenKind::Identifier);
     /// ```
     pub fn peek(&self, n: usize) -> Token {
-        self.tokens.clone().nth(n).unwrap_or_else(eof)
+        self.fill(n);
+        self.lookahead.borrow().get(n).copied().unwrap_or_else(eof)
     }
 
     /// If next token is listed in `kind` return `Some(token)`, otherwise return
@@ -531,4 +809,156 @@ let ident = int;
         test_next!(OpenBrace);
         test_next!(CloseBrace);
     }
+
+    #[test]
+    fn test_tokens() {
+        use super::TokenKind::*;
+        use crate::parse_context::ParseContext;
+
+        static INPUT: &str = r#"
+fn main() {
+    // Comment
+    let a = 5 /* Block */
+}
+"#;
+        let ctx: ParseContext = ParseContext::new(INPUT);
+        let lexer = Lexer::new(INPUT, &ctx);
+        let kinds: Vec<TokenKind> = lexer.tokens().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                Fn,
+                Identifier,
+                OpenParen,
+                CloseParen,
+                OpenBrace,
+                Let,
+                Identifier,
+                Equal,
+                Literal(LiteralKind::Int),
+                CloseBrace,
+                Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trivia() {
+        use super::TokenKind::*;
+        use crate::parse_context::ParseContext;
+
+        static INPUT: &str = "// hi\nlet x = 1";
+        let ctx: ParseContext = ParseContext::new(INPUT);
+        let mut lexer = Lexer::new_with_trivia(INPUT, &ctx);
+        let comment = lexer.next();
+        assert_eq!(comment.kind, Comment);
+        assert_eq!(&INPUT[comment.span.pos.0..comment.span.pos.1], "// hi");
+        assert_eq!(lexer.next().kind, Let);
+
+        let mut lexer = Lexer::new(INPUT, &ctx);
+        assert_eq!(lexer.next().kind, Let);
+    }
+
+    #[test]
+    fn test_lookahead_linear() {
+        use crate::parse_context::ParseContext;
+
+        let input = "let a = 1\n".repeat(1000);
+        let ctx: ParseContext = ParseContext::new(&input);
+        let mut lexer = Lexer::new(&input, &ctx);
+        let mut count = 0;
+        while lexer.peek(0).kind != TokenKind::Eof {
+            lexer.peek(1);
+            lexer.peek(2);
+            lexer.until(vec![TokenKind::Semi]);
+            lexer.next();
+            count += 1;
+        }
+        assert_eq!(count, 4000);
+        assert_eq!(lexer.tokens.borrow().produced, 4000);
+    }
+
+    #[test]
+    fn test_invalid_number() {
+        use crate::parse_context::ParseContext;
+
+        static INPUT: &str = "let x = 123abc";
+        let ctx: ParseContext = ParseContext::new(INPUT);
+        let kinds: Vec<TokenKind> = Lexer::new(INPUT, &ctx).tokens().map(|t| t.kind).collect();
+        assert_eq!(kinds[3], TokenKind::Literal(LiteralKind::Int));
+        assert_eq!(kinds[4], TokenKind::Eof);
+        let errors = ctx.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, Span::new(8, 14));
+        assert_eq!(errors[0].message, "Invalid number literal");
+    }
+
+    #[test]
+    fn test_literal_suffix() {
+        use crate::parse_context::ParseContext;
+
+        static INPUT: &str = "0f32 5i32 7u32 1.5f32 1.5i32";
+        let ctx: ParseContext = ParseContext::new(INPUT);
+        let kinds: Vec<TokenKind> = Lexer::new(INPUT, &ctx).tokens().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds[..5],
+            [
+                TokenKind::Literal(LiteralKind::Float),
+                TokenKind::Literal(LiteralKind::Int),
+                TokenKind::Literal(LiteralKind::UInt),
+                TokenKind::Literal(LiteralKind::Float),
+                TokenKind::Literal(LiteralKind::Float),
+            ]
+        );
+        let errors = ctx.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, Span::new(22, 28));
+        assert_eq!(errors[0].message, "Integer suffix on a float literal");
+    }
+
+    #[test]
+    fn test_comment_at_eof() {
+        let ctx = Default::default();
+        let mut lexer = Lexer::new("let x = 1 // comment", &ctx);
+        for _ in 0..4 {
+            lexer.next();
+        }
+        assert_eq!(lexer.next().kind, TokenKind::Eof);
+
+        let mut lexer = Lexer::new("x /* comment", &ctx);
+        lexer.next();
+        assert_eq!(lexer.next().kind, TokenKind::Eof);
+
+        // A NUL inside a line comment doesn't end it
+        let mut lexer = Lexer::new("x // a \0 b\n y", &ctx);
+        lexer.next();
+        assert_eq!(lexer.next().kind, TokenKind::Identifier);
+        assert_eq!(lexer.next().kind, TokenKind::Eof);
+    }
+
+    #[test]
+    fn test_tokenize() {
+        use super::TokenKind::*;
+        let kinds: Vec<TokenKind> = tokenize("fn main() {}").iter().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![Fn, Identifier, OpenParen, CloseParen, OpenBrace, CloseBrace, Eof]
+        );
+    }
+
+    #[test]
+    fn test_lexer_at() {
+        use crate::parse_context::ParseContext;
+
+        static INPUT: &str = "let x = 1";
+        let context = ParseContext::new(INPUT);
+        let mut lexer = Lexer::at(INPUT, 4, &context);
+        assert_eq!(lexer.position(), 4);
+        let token = lexer.next();
+        assert_eq!(token.kind, TokenKind::Identifier);
+        assert_eq!(token.span, Span::new(4, 5));
+        assert_eq!(lexer.text(token), "x");
+        assert_eq!(lexer.position(), 5);
+        assert_eq!(lexer.next().span, Span::new(6, 7));
+    }
 }
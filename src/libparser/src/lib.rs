@@ -1,5 +1,8 @@
 pub mod ast;
+#[cfg(feature = "serde")]
+pub mod json;
 pub mod lexer;
 pub mod parse_context;
 pub mod parser;
+pub mod printer;
 pub mod span;
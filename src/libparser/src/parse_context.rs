@@ -1,9 +1,39 @@
 use crate::span::Span;
+use std::cell::RefCell;
 
-/// Parsing context.  Manages printing out errors.
+/// How serious a diagnostic is
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    /// The label printed in front of a diagnostic's message
+    pub fn label(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// An error or warning reported to the parse context
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+    pub severity: Severity,
+}
+
+/// Parsing context.  Collects diagnostics, and prints them out as they are
+/// reported if printing is turned on with
+/// [`with_printing`](#method.with_printing).
 #[derive(Default)]
 pub struct ParseContext<'a> {
     input: &'a str,
+    diagnostics: RefCell<Vec<Diagnostic>>,
+    print: bool,
 }
 
 impl ParseContext<'_> {
@@ -13,11 +43,60 @@ impl ParseContext<'_> {
     /// # use libparser::parse_context::*;
     /// let context = ParseContext::new("asd");
     /// ```
-    pub fn new(input: &str) -> ParseContext {
-        ParseContext { input }
+    pub fn new(input: &str) -> ParseContext<'_> {
+        ParseContext {
+            input,
+            diagnostics: Default::default(),
+            print: false,
+        }
+    }
+
+    /// Print each diagnostic to stderr as it is reported
+    /// # Examples
+    /// ```
+    /// # use libparser::parse_context::*;
+    /// let context = ParseContext::new("asd").with_printing();
+    /// ```
+    pub fn with_printing(mut self) -> Self {
+        self.print = true;
+        self
+    }
+
+    /// Returns every error reported so far
+    /// # Examples
+    /// ```
+    /// # use libparser::parse_context::*;
+    /// # use libparser::span::Span;
+    /// let context = ParseContext::new("asd");
+    /// context.error(Span::new(0, 3), "Error message");
+    /// let errors = context.errors();
+    /// assert_eq!(errors[0].span, Span::new(0, 3));
+    /// assert_eq!(errors[0].message, "Error message");
+    /// ```
+    pub fn errors(&self) -> Vec<Diagnostic> {
+        self.diagnostics
+            .borrow()
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .cloned()
+            .collect()
     }
 
-    /// Print an error for a span.
+    /// Returns every error and warning reported so far, in order
+    /// # Examples
+    /// ```
+    /// # use libparser::parse_context::*;
+    /// # use libparser::span::Span;
+    /// let context = ParseContext::new("asd");
+    /// context.warn(Span::new(0, 3), "Warning message");
+    /// assert!(context.errors().is_empty());
+    /// assert_eq!(context.diagnostics()[0].severity, Severity::Warning);
+    /// ```
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.borrow().clone()
+    }
+
+    /// Report an error for a span.
     /// # Examples
     /// ```
     /// # use libparser::parse_context::*;
@@ -26,26 +105,192 @@ impl ParseContext<'_> {
     /// context.error(Span::new(0, 3), "Error message");
     /// ```
     pub fn error(&self, span: Span, message: &str) {
-        // Count new lines
-        let mut num_lines = 0;
-        let mut covered = 0;
-        let mut iter = self.input.chars();
-        for i in 0..span.pos.0 {
-            let c = iter.next().unwrap();
-            if c == '\n' {
-                covered = i;
-                num_lines += 1;
-            }
+        self.report(span, message, Severity::Error);
+    }
+
+    /// Report a warning for a span.  Warnings don't count as errors.
+    /// # Examples
+    /// ```
+    /// # use libparser::parse_context::*;
+    /// # use libparser::span::Span;
+    /// let context = ParseContext::new("asd");
+    /// context.warn(Span::new(0, 3), "Warning message");
+    /// ```
+    pub fn warn(&self, span: Span, message: &str) {
+        self.report(span, message, Severity::Warning);
+    }
+
+    /// The 1-based line number a span starts on and the text of that line
+    /// # Examples
+    /// ```
+    /// # use libparser::parse_context::*;
+    /// # use libparser::span::Span;
+    /// let context = ParseContext::new("let a = 1\nlet b = a");
+    /// assert_eq!(context.line_of(Span::new(4, 5)), (1, "let a = 1"));
+    /// ```
+    pub fn line_of(&self, span: Span) -> (usize, &str) {
+        line_of(self.input, span)
+    }
+
+    /// The line a span starts on with the span underlined, as it is printed
+    /// under a diagnostic's message
+    /// # Examples
+    /// ```
+    /// # use libparser::parse_context::*;
+    /// # use libparser::span::Span;
+    /// let context = ParseContext::new("let a = 1");
+    /// assert!(context.snippet(Span::new(4, 5)).contains("let a = 1"));
+    /// ```
+    pub fn snippet(&self, span: Span) -> String {
+        snippet(self.input, span)
+    }
+
+    fn report(&self, span: Span, message: &str, severity: Severity) {
+        let diagnostic = Diagnostic {
+            span,
+            message: String::from(message),
+            severity,
+        };
+        if self.print {
+            eprint!("{}", render(self.input, &diagnostic));
         }
-        let line = self.input.split('\n').nth(num_lines).unwrap();
-        eprintln!("\u{001b}[33merror: {}\u{001b}[0m", message);
-        eprintln!("    \u{001b}[33m{} |\u{001b}[0m {}", num_lines + 1, line);
-        eprintln!(
-            "       \u{001b}[34m{}{}\u{001b}[0m",
-            (0..(span.pos.0 - covered)).map(|_| " ").collect::<String>(),
-            (0..(span.pos.1 - span.pos.0))
-                .map(|_| "^")
-                .collect::<String>()
-        );
+        self.diagnostics.borrow_mut().push(diagnostic);
+    }
+}
+
+/// Render a diagnostic against the input it was reported for, as it is printed
+/// by a context with printing turned on.  Only the first line of a span
+/// crossing a newline is shown and underlined, and none is shown for a dummy
+/// span.
+/// # Examples
+/// ```
+/// # use libparser::parse_context::*;
+/// # use libparser::span::Span;
+/// let diagnostic = Diagnostic {
+///     span: Span::new(0, 3),
+///     message: String::from("Error message"),
+///     severity: Severity::Error,
+/// };
+/// assert!(render("asd", &diagnostic).contains("error: Error message"));
+/// ```
+pub fn render(input: &str, diagnostic: &Diagnostic) -> String {
+    let color = match diagnostic.severity {
+        Severity::Error => 31,
+        Severity::Warning => 33,
+    };
+    let snippet = if diagnostic.span.is_dummy {
+        String::new()
+    } else {
+        snippet(input, diagnostic.span)
+    };
+    format!(
+        "\u{001b}[{}m{}: {}\u{001b}[0m\n{}",
+        color,
+        diagnostic.severity.label(),
+        diagnostic.message,
+        snippet
+    )
+}
+
+/// The 1-based line number of the start of a span and the offset that line
+/// starts at
+fn locate(input: &str, span: Span) -> (usize, usize) {
+    let start = span.pos.0.min(input.len());
+    let number = input[..start].matches('\n').count() + 1;
+    let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+    (number, line_start)
+}
+
+/// The 1-based line number of the start of a span and the text of that line
+fn line_of(input: &str, span: Span) -> (usize, &str) {
+    let (number, line_start) = locate(input, span);
+    let line = input[line_start..].split('\n').next().unwrap_or("");
+    (number, line)
+}
+
+/// The line a span starts on with the span underlined
+fn snippet(input: &str, span: Span) -> String {
+    let (number, line_start) = locate(input, span);
+    let (_, line) = line_of(input, span);
+    let start = span.pos.0.min(input.len());
+    let end = span.pos.1.min(line_start + line.len()).max(start + 1);
+    // Tabs are kept so the carets line up however wide the terminal shows them
+    let padding: String = input[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let carets = input
+        .get(start..end)
+        .map_or(1, |s| s.chars().count().max(1));
+    format!(
+        "    \u{001b}[33m{} |\u{001b}[0m {}\n    {}   \u{001b}[34m{}{}\u{001b}[0m\n",
+        number,
+        line,
+        " ".repeat(number.to_string().len()),
+        padding,
+        "^".repeat(carets)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_line_span() {
+        static INPUT: &str = "let x = \"ab\ncd\"\nlet y = 2";
+        let context = ParseContext::new(INPUT);
+        let diagnostic = Diagnostic {
+            span: Span::new(8, 15),
+            message: String::from("Error message"),
+            severity: Severity::Error,
+        };
+        let out = render(INPUT, &diagnostic);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].ends_with("let x = \"ab"));
+        assert_eq!(lines[2].matches('^').count(), 3);
+        context.error(Span::new(8, 15), "Error message");
+    }
+
+    #[test]
+    fn test_severity() {
+        static INPUT: &str = "return 1\nlet x = y";
+        let context = ParseContext::new(INPUT);
+        context.warn(Span::new(9, 18), "Unreachable code");
+        context.error(Span::new(17, 18), "Variable doesn't exist");
+        let diagnostics = context.diagnostics();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(context.errors(), vec![diagnostics[1].clone()]);
+
+        let warning = render(INPUT, &diagnostics[0]);
+        assert!(warning.contains("warning: Unreachable code"));
+        assert!(warning.contains("2 |"));
+        let error = render(INPUT, &diagnostics[1]);
+        assert!(error.contains("error: Variable doesn't exist"));
+        assert!(!error.contains("warning"));
+    }
+
+    #[test]
+    fn test_line_of() {
+        static INPUT: &str = "let a = 1\n    let bc = a\nlet d = 2";
+        let context = ParseContext::new(INPUT);
+        assert_eq!(context.line_of(Span::new(18, 20)), (2, "    let bc = a"));
+        let snippet = context.snippet(Span::new(18, 20));
+        let lines: Vec<&str> = snippet.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("2 |"));
+        assert!(lines[0].ends_with("    let bc = a"));
+        assert_eq!(lines[1].matches('^').count(), 2);
+    }
+
+    #[test]
+    fn test_tab_alignment() {
+        static INPUT: &str = "fn f() {\n\t\tlet x = y\n}";
+        let context = ParseContext::new(INPUT);
+        let snippet = context.snippet(Span::new(19, 20));
+        let carets = snippet.lines().nth(1).unwrap();
+        assert!(carets.contains("\t\t        ^"));
+        assert_eq!(carets.matches('^').count(), 1);
     }
 }
@@ -1,6 +1,8 @@
 use super::*;
 use crate::ast::*;
-use crate::lexer::TokenKind;
+use crate::lexer::{Token, TokenKind};
+use crate::span::Span;
+use std::convert::TryFrom;
 
 impl Parser<'_> {
     /// Parse an expression
@@ -18,10 +20,63 @@ impl Parser<'_> {
     /// }
     /// ```
     pub fn parse_expression(&mut self) -> Expression {
-        self.equality()
+        let expr = self.logic_or();
+        match self.lexer.until(vec![TokenKind::DotDot]) {
+            Some(op) => {
+                let end = self.logic_or();
+                Expression::Range(Box::new(expr), Box::new(end), op.span)
+            }
+            None => expr,
+        }
+    }
+
+    /// Build a binary expression whose lhs starts at `start`, reporting an
+    /// error if `op` isn't an operator.  The span runs to the end of the last
+    /// token so parentheses around either operand are included.
+    fn binary(&self, start: Span, lhs: Expression, op: Token, rhs: Expression) -> Expression {
+        match Op::try_from(op.kind) {
+            Ok(kind) => {
+                let span = Span::merge(start, self.end_span());
+                Expression::Binary(Box::new(lhs), kind, Box::new(rhs), span)
+            }
+            Err(_) => {
+                self.lexer.context.error(op.span, "Not an operator");
+                Expression::Dummy
+            }
+        }
+    }
+
+    /// An empty span at the end of the last consumed token
+    fn end_span(&self) -> Span {
+        Span::new(self.lexer.prev_end(), self.lexer.prev_end())
+    }
+
+    fn logic_or(&mut self) -> Expression {
+        let start = self.lexer.peek(0).span;
+        let mut expr = self.logic_and();
+
+        while let Some(op) = self.lexer.until(vec![TokenKind::OrOr]) {
+            let rhs = self.logic_and();
+            expr = self.binary(start, expr, op, rhs);
+        }
+
+        expr
+    }
+
+    fn logic_and(&mut self) -> Expression {
+        let start = self.lexer.peek(0).span;
+        let mut expr = self.equality();
+
+        while let Some(op) = self.lexer.until(vec![TokenKind::AndAnd]) {
+            let rhs = self.equality();
+            expr = self.binary(start, expr, op, rhs);
+        }
+
+        expr
     }
 
     fn equality(&mut self) -> Expression {
+        let start = self.lexer.peek(0).span;
         let mut expr = self.comparison();
 
         while let Some(op) = self
@@ -29,14 +84,16 @@ impl Parser<'_> {
             .until(vec![TokenKind::EqEqual, TokenKind::NotEqual])
         {
             let rhs = self.comparison();
-            expr = Expression::Binary(Box::new(expr), Op::from(op.kind), Box::new(rhs), op.span);
+            expr = self.binary(start, expr, op, rhs);
         }
 
         expr
     }
 
     fn comparison(&mut self) -> Expression {
-        let mut expr = self.addition();
+        let start = self.lexer.peek(0).span;
+        let mut expr = self.xor();
+        let mut chained = false;
 
         while let Some(op) = self.lexer.until(vec![
             TokenKind::Lt,
@@ -44,42 +101,106 @@ impl Parser<'_> {
             TokenKind::LtEqual,
             TokenKind::GtEqual,
         ]) {
+            // `a < b < c` would compare the bool `a < b` with `c`
+            if chained {
+                self.lexer
+                    .context
+                    .error(op.span, "chained comparison is not allowed; use && instead");
+            }
+            chained = true;
+            let rhs = self.xor();
+            expr = self.binary(start, expr, op, rhs);
+        }
+
+        expr
+    }
+
+    fn xor(&mut self) -> Expression {
+        let start = self.lexer.peek(0).span;
+        let mut expr = self.shift();
+
+        while let Some(op) = self.lexer.until(vec![TokenKind::Caret]) {
+            let rhs = self.shift();
+            expr = self.binary(start, expr, op, rhs);
+        }
+
+        expr
+    }
+
+    fn shift(&mut self) -> Expression {
+        let start = self.lexer.peek(0).span;
+        let mut expr = self.addition();
+
+        while let Some(op) = self.lexer.until(vec![TokenKind::LtLt, TokenKind::GtGt]) {
             let rhs = self.addition();
-            expr = Expression::Binary(Box::new(expr), Op::from(op.kind), Box::new(rhs), op.span);
+            expr = self.binary(start, expr, op, rhs);
         }
 
         expr
     }
 
     fn addition(&mut self) -> Expression {
+        let start = self.lexer.peek(0).span;
         let mut expr = self.multiplication();
 
         while let Some(op) = self.lexer.until(vec![TokenKind::Plus, TokenKind::Minus]) {
             let rhs = self.multiplication();
-            expr = Expression::Binary(Box::new(expr), Op::from(op.kind), Box::new(rhs), op.span);
+            expr = self.binary(start, expr, op, rhs);
         }
 
         expr
     }
 
     fn multiplication(&mut self) -> Expression {
+        let start = self.lexer.peek(0).span;
         let mut expr = self.unary();
 
         while let Some(op) = self.lexer.until(vec![TokenKind::Star, TokenKind::Slash]) {
             let rhs = self.multiplication();
-            expr = Expression::Binary(Box::new(expr), Op::from(op.kind), Box::new(rhs), op.span);
+            expr = self.binary(start, expr, op, rhs);
         }
 
         expr
     }
 
     fn unary(&mut self) -> Expression {
-        if let Some(op) = self.lexer.until(vec![TokenKind::Star, TokenKind::Slash]) {
-            let rhs = self.multiplication();
-            Expression::Unary(Op::from(op.kind), Box::new(rhs), op.span)
+        if let Some(op) = self.lexer.until(vec![TokenKind::Minus, TokenKind::Not]) {
+            let rhs = self.unary();
+            match Op::try_from(op.kind) {
+                Ok(kind) => {
+                    Expression::Unary(kind, Box::new(rhs), Span::merge(op.span, self.end_span()))
+                }
+                Err(_) => {
+                    self.lexer.context.error(op.span, "Not an operator");
+                    Expression::Dummy
+                }
+            }
         } else {
-            self.primary()
+            self.postfix()
+        }
+    }
+
+    /// Parse field accesses and method calls after a primary expression
+    fn postfix(&mut self) -> Expression {
+        let mut expr = self.primary();
+
+        while self.lexer.until(vec![TokenKind::Dot]).is_some() {
+            let name = match self
+                .lexer
+                .expect(TokenKind::Identifier, "Expected field name")
+            {
+                Some(name) => name,
+                None => return Expression::Dummy,
+            };
+            expr = if self.lexer.until(vec![TokenKind::OpenParen]).is_some() {
+                let args = self.parse_args();
+                Expression::MethodCall(Box::new(expr), name.span, args)
+            } else {
+                Expression::Field(Box::new(expr), name.span)
+            };
         }
+
+        expr
     }
 
     fn primary(&mut self) -> Expression {
@@ -93,6 +214,13 @@ impl Parser<'_> {
                     val: next.span,
                 }
             }
+            TokenKind::True | TokenKind::False => {
+                self.lexer.next();
+                Expression::Literal {
+                    kind: LiteralKind::Bool,
+                    val: next.span,
+                }
+            }
             TokenKind::Identifier => {
                 let peeked = self.lexer.peek(1);
                 match peeked.kind {
@@ -103,6 +231,22 @@ impl Parser<'_> {
                     }
                 }
             }
+            TokenKind::Loop => {
+                self.lexer.next(); // loop keyword
+                if self
+                    .lexer
+                    .expect(TokenKind::OpenBrace, "Expected open brace")
+                    .is_none()
+                {
+                    return Expression::Dummy;
+                }
+                Expression::Loop(self.parse_block(), next.span)
+            }
+            TokenKind::OpenParen if self.lexer.peek(1).kind == TokenKind::CloseParen => {
+                self.lexer.next();
+                let close = self.lexer.next();
+                Expression::Unit(Span::new(next.span.pos.0, close.span.pos.1))
+            }
             TokenKind::OpenParen => {
                 self.lexer.next();
                 let expr = self.parse_expression();
@@ -110,6 +254,12 @@ impl Parser<'_> {
                     .expect(TokenKind::CloseParen, "Expected Close Parentheses");
                 expr
             }
+            TokenKind::Unknown => {
+                self.lexer.next();
+                let message = format!("unexpected character '{}'", self.lexer.text(next));
+                self.context.error(next.span, message.as_str());
+                Expression::Dummy
+            }
             _ => {
                 self.lexer.next();
                 self.context.error(next.span, "Expected a value");
@@ -119,36 +269,210 @@ impl Parser<'_> {
     }
 
     fn parse_function_call(&mut self) -> Expression {
-        let next = self.lexer.next();
+        let ident = self.lexer.next();
         let paren = self.lexer.next();
         if paren.kind == TokenKind::OpenParen {
-            if self.lexer.peek(0).kind == TokenKind::CloseParen {
-                self.lexer.next();
-                Expression::FunctionCall(next.span, vec![])
-            } else {
-                // FIXME: ignore whitespace: print    (params)
-                let mut args: Vec<Expression> = Vec::new();
-                loop {
-                    let expression = self.parse_expression();
+            let args = self.parse_args();
+            let span = Span::new(ident.span.pos.0, self.lexer.prev_end());
+            Expression::FunctionCall(ident.span, args, span)
+        } else {
+            self.context
+                .error(paren.span, "Missing parentheses in function call");
+            Expression::Dummy
+        }
+    }
 
-                    args.push(expression);
+    /// Parse call arguments after the open paren up to and including the
+    /// close paren
+    fn parse_args(&mut self) -> Vec<(Expression, Span)> {
+        let mut args: Vec<(Expression, Span)> = Vec::new();
+        if self.lexer.until(vec![TokenKind::CloseParen]).is_some() {
+            return args;
+        }
+        loop {
+            let start = self.lexer.peek(0).span.pos.0;
+            let expression = self.parse_expression();
 
-                    let next = self.lexer.next();
+            args.push((expression, Span::new(start, self.lexer.prev_end())));
 
-                    match next.kind {
-                        TokenKind::CloseParen => break,
-                        TokenKind::Comma => continue,
-                        _ => self
-                            .context
-                            .error(next.span, "Expected close paren or comma"),
-                    }
+            let next = self.lexer.next();
+
+            match next.kind {
+                TokenKind::CloseParen => break,
+                TokenKind::Comma => continue,
+                _ => {
+                    self.context
+                        .error(next.span, "Expected close paren or comma");
+                    break;
                 }
-                Expression::FunctionCall(next.span, args)
             }
-        } else {
-            self.context
-                .error(paren.span, "Missing parentheses in function call");
-            Expression::Dummy
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::*;
+    use crate::parse_context::ParseContext;
+    use crate::parser::Parser;
+    use crate::span::Span;
+
+    #[test]
+    fn test_function_call_span() {
+        static INPUT: &str = "foo (1, 2)";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        match parser.parse_expression() {
+            Expression::FunctionCall(name, args, span) => {
+                assert_eq!(&INPUT[name.pos.0..name.pos.1], "foo");
+                assert_eq!(&INPUT[span.pos.0..span.pos.1], INPUT);
+                assert_eq!(args.len(), 2);
+            }
+            expr => panic!("Expected function call, found {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_argument_spans() {
+        static INPUT: &str = "foo(1,  a + b , (c))";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        match parser.parse_expression() {
+            Expression::FunctionCall(_, args, _) => {
+                let args: Vec<&str> = args
+                    .iter()
+                    .map(|(_, span)| &INPUT[span.pos.0..span.pos.1])
+                    .collect();
+                assert_eq!(args, vec!["1", "a + b", "(c)"]);
+            }
+            expr => panic!("Expected function call, found {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_not_an_operator() {
+        static INPUT: &str = "1, 2";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        let start = parser.lexer.peek(0).span;
+        let lhs = parser.parse_expression();
+        let comma = parser.lexer.next();
+        let rhs = parser.parse_expression();
+        match parser.binary(start, lhs, comma, rhs) {
+            Expression::Dummy => {}
+            expr => panic!("Expected dummy expression, found {:?}", expr),
+        }
+        let errors = ctx.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Not an operator");
+        assert_eq!(errors[0].span.pos, (1, 2));
+    }
+
+    #[test]
+    fn test_chained_comparison() {
+        static INPUT: &str = "1 < 2 < 3";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        parser.parse_expression();
+        let errors = ctx.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "chained comparison is not allowed; use && instead"
+        );
+        assert_eq!(errors[0].span.pos, (6, 7));
+
+        static AND: &str = "1 < 2 && 2 < 3";
+        let ctx = ParseContext::new(AND);
+        let mut parser = Parser::new(AND, &ctx);
+        parser.parse_expression();
+        assert!(ctx.errors().is_empty());
+    }
+
+    #[test]
+    fn test_field_access() {
+        static INPUT: &str = "x.y";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        match parser.parse_expression() {
+            Expression::Field(object, field) => {
+                match *object {
+                    Expression::Ident { val } => assert_eq!(val.pos, (0, 1)),
+                    expr => panic!("Expected identifier, found {:?}", expr),
+                }
+                assert_eq!(field.pos, (2, 3));
+            }
+            expr => panic!("Expected field access, found {:?}", expr),
+        }
+        assert!(ctx.errors().is_empty());
+
+        static METHOD: &str = "a.b.len(1, 2)";
+        let ctx = ParseContext::new(METHOD);
+        let mut parser = Parser::new(METHOD, &ctx);
+        match parser.parse_expression() {
+            Expression::MethodCall(object, name, args) => {
+                assert!(matches!(*object, Expression::Field(..)));
+                assert_eq!(&METHOD[name.pos.0..name.pos.1], "len");
+                assert_eq!(args.len(), 2);
+            }
+            expr => panic!("Expected method call, found {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_expression_spans() {
+        static INPUT: &str = " 1 + 2 ";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        match parser.parse_expression() {
+            Expression::Binary(lhs, Op::Plus, rhs, span) => {
+                assert_eq!(&INPUT[span.pos.0..span.pos.1], "1 + 2");
+                let merged = Span::merge(lhs.span().unwrap(), rhs.span().unwrap());
+                assert_eq!(merged, span);
+            }
+            expr => panic!("Expected binary expression, found {:?}", expr),
+        }
+
+        static UNARY: &str = "-f(1, 2)";
+        let ctx = ParseContext::new(UNARY);
+        let span = Parser::new(UNARY, &ctx).parse_expression().span().unwrap();
+        assert_eq!(&UNARY[span.pos.0..span.pos.1], UNARY);
+    }
+
+    #[test]
+    fn test_unexpected_character() {
+        static INPUT: &str = "let x = @";
+        let ctx = ParseContext::new(INPUT);
+        Parser::new(INPUT, &ctx).parse();
+        let errors = ctx.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unexpected character '@'");
+        assert_eq!(errors[0].span, Span::new(8, 9));
+    }
+
+    #[test]
+    fn test_parenthesized_spans() {
+        for input in [
+            "(1 + 2) * 3",
+            "3 * (1 + 2)",
+            "-(a + b)",
+            "(a) == (b)",
+            "!(x)",
+        ] {
+            let ctx = ParseContext::new(input);
+            let span = Parser::new(input, &ctx).parse_expression().span().unwrap();
+            assert_eq!(&input[span.pos.0..span.pos.1], input);
+        }
+
+        static INPUT: &str = "(1 + 2) * 3";
+        let ctx = ParseContext::new(INPUT);
+        match Parser::new(INPUT, &ctx).parse_expression() {
+            Expression::Binary(lhs, Op::Star, _, _) => {
+                let span = lhs.span().unwrap();
+                assert_eq!(&INPUT[span.pos.0..span.pos.1], "1 + 2");
+            }
+            expr => panic!("Expected binary expression, found {:?}", expr),
         }
     }
 }
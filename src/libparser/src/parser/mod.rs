@@ -3,7 +3,7 @@ mod statement;
 
 use crate::ast::*;
 use crate::lexer::{Lexer, TokenKind};
-use crate::parse_context::ParseContext;
+use crate::parse_context::{Diagnostic, ParseContext};
 
 /// Parser class containing a context (for error printing) and lexer
 pub struct Parser<'a> {
@@ -42,6 +42,28 @@ impl Parser<'_> {
         self.parse_block()
     }
 
+    /// Parse the input and return the errors reported while parsing instead of
+    /// the block if there were any
+    /// ```
+    /// # use libparser::parser::*;
+    /// # use libparser::parse_context::ParseContext;
+    /// static INPUT: &str = "let = 5";
+    /// let context = ParseContext::new(INPUT);
+    /// let mut parser = Parser::new(INPUT, &context);
+    /// let errors = parser.parse_checked().unwrap_err();
+    /// assert_eq!(errors[0].message, "Expected identifier");
+    /// ```
+    pub fn parse_checked(&mut self) -> Result<Block, Vec<Diagnostic>> {
+        let before = self.context.errors().len();
+        let block = self.parse();
+        let errors = self.context.errors().split_off(before);
+        if errors.is_empty() {
+            Ok(block)
+        } else {
+            Err(errors)
+        }
+    }
+
     /// Parse a block
     /// ```
     /// # use libparser::parser::*;
@@ -1,6 +1,7 @@
 use super::*;
 use crate::ast::*;
 use crate::lexer::TokenKind;
+use crate::span::Span;
 
 impl Parser<'_> {
     /// Parse a statement
@@ -18,41 +19,123 @@ impl Parser<'_> {
     /// }
     /// ```
     pub fn parse_statement(&mut self) -> Option<Statement> {
+        let stmt = self.parse_statement_kind();
+        if stmt.is_some() {
+            // A semicolon separates statements on the same line
+            self.lexer.until(vec![TokenKind::Semi]);
+        }
+        stmt
+    }
+
+    fn parse_statement_kind(&mut self) -> Option<Statement> {
         let next = self.lexer.peek(0);
         match next.kind {
-            TokenKind::Let => {
-                self.lexer.next(); // let keyword
+            TokenKind::Let | TokenKind::Const => {
+                self.lexer.next(); // let or const keyword
                 let ident = self
                     .lexer
                     .expect(TokenKind::Identifier, "Expected identifier");
                 let equal = self.lexer.expect(TokenKind::Equal, "Expected equal sign");
                 let expr = self.parse_expression();
-                if ident != None && equal != None {
-                    Some(Statement::Assign(ident.unwrap().span, expr))
-                } else {
-                    Some(Statement::Dummy)
+                match (ident, equal) {
+                    (Some(ident), Some(_)) if next.kind == TokenKind::Const => {
+                        Some(Statement::Const(ident.span, expr))
+                    }
+                    (Some(ident), Some(_)) => Some(Statement::Assign(ident.span, expr)),
+                    _ => Some(Statement::Dummy),
                 }
             }
             TokenKind::Return => {
                 let keyword = self.lexer.next(); // return keyword
-                let expr = self.parse_expression();
+                let expr = match self.lexer.peek(0).kind {
+                    TokenKind::CloseBrace | TokenKind::Semi | TokenKind::Eof => {
+                        Expression::Unit(keyword.span)
+                    }
+                    _ => self.parse_expression(),
+                };
                 Some(Statement::Return(expr, keyword.span))
             }
             TokenKind::Fn => self.parse_function_decl(),
             TokenKind::If => self.parse_if_statement(),
+            TokenKind::Label => {
+                self.lexer.next(); // label
+                let colon = self.lexer.expect(TokenKind::Colon, "Expected colon");
+                let keyword = self.lexer.expect(TokenKind::Loop, "Expected `loop`");
+                if colon.is_none() || keyword.is_none() {
+                    return Some(Statement::Dummy);
+                }
+                self.parse_loop(Some(next.span))
+            }
             TokenKind::Loop => {
                 self.lexer.next(); // loop keyword
+                self.parse_loop(None)
+            }
+            TokenKind::Do => {
+                self.lexer.next(); // do keyword
                 let open_brace = self
                     .lexer
                     .expect(TokenKind::OpenBrace, "Expected open brace");
-                if open_brace == None {
+                if open_brace.is_none() {
                     return Some(Statement::Dummy);
                 }
-                Some(Statement::Loop(self.parse_block()))
+                let block = self.parse_block();
+                if self
+                    .lexer
+                    .expect(TokenKind::While, "Expected `while`")
+                    .is_none()
+                {
+                    return Some(Statement::Dummy);
+                }
+                Some(Statement::DoWhile(block, self.parse_expression()))
+            }
+            TokenKind::For => {
+                self.lexer.next(); // for keyword
+                let ident = self
+                    .lexer
+                    .expect(TokenKind::Identifier, "Expected identifier");
+                let in_keyword = self.lexer.expect(TokenKind::In, "Expected `in`");
+                if ident.is_none() || in_keyword.is_none() {
+                    return Some(Statement::Dummy);
+                }
+                let range = self.parse_expression();
+                let open_brace = self
+                    .lexer
+                    .expect(TokenKind::OpenBrace, "Expected open brace");
+                if open_brace.is_none() {
+                    return Some(Statement::Dummy);
+                }
+                Some(Statement::For(
+                    ident.unwrap().span,
+                    range,
+                    self.parse_block(),
+                ))
+            }
+            TokenKind::OpenBrace => {
+                self.lexer.next(); // open brace
+                Some(Statement::Block(self.parse_block()))
             }
             TokenKind::Break => {
-                self.lexer.next();
-                Some(Statement::Break)
+                let keyword = self.lexer.next(); // break keyword
+                let label = match self.lexer.peek(0).kind {
+                    TokenKind::Label => Some(self.lexer.next().span),
+                    _ => None,
+                };
+                // Newlines aren't tokens, so a value has to start on the same
+                // line as the `break`
+                let value = match self.lexer.peek(0).kind {
+                    _ if self.lexer.newline_before_next() => None,
+                    TokenKind::Identifier if self.lexer.peek(1).kind == TokenKind::Equal => None,
+                    TokenKind::Identifier
+                    | TokenKind::Literal(_)
+                    | TokenKind::True
+                    | TokenKind::False
+                    | TokenKind::OpenParen
+                    | TokenKind::Minus
+                    | TokenKind::Not
+                    | TokenKind::Loop => Some(self.parse_expression()),
+                    _ => None,
+                };
+                Some(Statement::Break(label, value, keyword.span))
             }
             TokenKind::Identifier if self.lexer.peek(1).kind == TokenKind::Equal => {
                 let var = self.lexer.next();
@@ -60,9 +143,11 @@ impl Parser<'_> {
                 let expr = self.parse_expression();
                 Some(Statement::Mutate(var.span, expr))
             }
-            TokenKind::Identifier | TokenKind::Literal(_) => {
-                Some(Statement::Expression(self.parse_expression()))
-            }
+            TokenKind::Identifier
+            | TokenKind::Literal(_)
+            | TokenKind::OpenParen
+            | TokenKind::Minus
+            | TokenKind::Not => Some(Statement::Expression(self.parse_expression())),
             _ => None,
         }
     }
@@ -71,7 +156,16 @@ impl Parser<'_> {
         let next = self.lexer.peek(0);
         let out = match next.kind {
             TokenKind::I32 => Type::Int,
+            TokenKind::U32 => Type::UInt,
             TokenKind::F32 => Type::Float,
+            TokenKind::Str => Type::Str,
+            TokenKind::Bool => Type::Bool,
+            TokenKind::Void => Type::Void,
+            TokenKind::Identifier => {
+                let message = format!("Unknown type `{}`", self.lexer.text(next));
+                self.lexer.context.error(next.span, message.as_str());
+                Type::Void
+            }
             _ => {
                 self.lexer.context.error(next.span, "Expected type");
                 Type::Void
@@ -90,7 +184,7 @@ impl Parser<'_> {
             let open_paren = self
                 .lexer
                 .expect(TokenKind::OpenParen, "Expected open paren");
-            if open_paren == None {
+            if open_paren.is_none() {
                 return Some(Statement::Dummy);
             }
             let mut args = vec![];
@@ -103,7 +197,7 @@ impl Parser<'_> {
                     let colon = self
                         .lexer
                         .expect(TokenKind::Colon, "Expected colon and type");
-                    if colon == None {
+                    if colon.is_none() {
                         return Some(Statement::Dummy);
                     }
                     let arg_type = self.parse_type();
@@ -122,7 +216,7 @@ impl Parser<'_> {
             let close_paren = self
                 .lexer
                 .expect(TokenKind::CloseParen, "Expected close paren");
-            if close_paren == None {
+            if close_paren.is_none() {
                 return Some(Statement::Dummy);
             }
 
@@ -138,7 +232,7 @@ impl Parser<'_> {
             let open_brace = self
                 .lexer
                 .expect(TokenKind::OpenBrace, "Expected open brace");
-            if open_brace == None {
+            if open_brace.is_none() {
                 return Some(Statement::Dummy);
             }
 
@@ -156,32 +250,212 @@ impl Parser<'_> {
     }
 
     fn parse_if_statement(&mut self) -> Option<Statement> {
-        self.lexer.next(); // if keyword
+        let keyword = self.lexer.next().span;
+        self.parse_if_arm(keyword)
+    }
+
+    /// Parse an `if` arm after it's keyword, which spans `keyword`
+    fn parse_if_arm(&mut self, keyword: Span) -> Option<Statement> {
         let expr = self.parse_expression();
         let open_brace = self
             .lexer
             .expect(TokenKind::OpenBrace, "Expected open brace");
-        if open_brace == None {
-            return Some(Statement::Dummy);
-        }
+        let open_brace = match open_brace {
+            Some(open_brace) => open_brace.span,
+            None => return Some(Statement::Dummy),
+        };
         let block = self.parse_block();
+        let braces = Span::new(open_brace.pos.0, self.lexer.prev_end());
         let next = if self.lexer.peek(0).kind == TokenKind::Else {
-            self.lexer.next(); // consume else
+            let else_keyword = self.lexer.next().span;
             if self.lexer.peek(0).kind == TokenKind::If {
-                Some(Box::new(self.parse_if_statement()?))
+                let if_keyword = self.lexer.next().span;
+                let keyword = Span::new(else_keyword.pos.0, if_keyword.pos.1);
+                Some(Box::new(self.parse_if_arm(keyword)?))
             } else {
                 let open_brace = self
                     .lexer
                     .expect(TokenKind::OpenBrace, "Expected open brace");
-                if open_brace == None {
-                    return Some(Statement::Dummy);
-                }
+                let open_brace = match open_brace {
+                    Some(open_brace) => open_brace.span,
+                    None => return Some(Statement::Dummy),
+                };
                 let block = self.parse_block();
-                Some(Box::new(Statement::Else(block)))
+                let braces = Span::new(open_brace.pos.0, self.lexer.prev_end());
+                Some(Box::new(Statement::Else(block, else_keyword, braces)))
             }
         } else {
             None
         };
-        Some(Statement::If(expr, block, next))
+        Some(Statement::If(expr, block, next, keyword, braces))
+    }
+
+    /// Parse a `loop` statement after it's keyword
+    fn parse_loop(&mut self, label: Option<Span>) -> Option<Statement> {
+        let open_brace = self
+            .lexer
+            .expect(TokenKind::OpenBrace, "Expected open brace");
+        if open_brace.is_none() {
+            return Some(Statement::Dummy);
+        }
+        Some(Statement::Loop(label, self.parse_block()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::*;
+    use crate::parse_context::ParseContext;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_expression_statements() {
+        static INPUT: &str = "-compute()\n (1 + 2)\n !done\n x";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        let block = parser.parse_block();
+        assert!(ctx.errors().is_empty());
+        assert_eq!(block.body.len(), 4);
+        match &block.body[0] {
+            Statement::Expression(Expression::Unary(Op::Minus, _, _)) => {}
+            stmt => panic!("Expected expression statement, found {:?}", stmt),
+        }
+        match &block.body[1] {
+            Statement::Expression(Expression::Binary(_, Op::Plus, _, _)) => {}
+            stmt => panic!("Expected expression statement, found {:?}", stmt),
+        }
+        match &block.body[2] {
+            Statement::Expression(Expression::Unary(Op::Not, _, _)) => {}
+            stmt => panic!("Expected expression statement, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn test_parse_type() {
+        static INPUT: &str = "fn greet(name: str, loud: bool) -> void {}";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        let block = parser.parse();
+        assert!(ctx.errors().is_empty());
+        match &block.body[0] {
+            Statement::FnDecl {
+                args, return_type, ..
+            } => {
+                assert!(matches!(args[0], Ident::Typed(_, Type::Str)));
+                assert!(matches!(args[1], Ident::Typed(_, Type::Bool)));
+                assert!(matches!(return_type, Type::Void));
+            }
+            stmt => panic!("Expected function declaration, found {:?}", stmt),
+        }
+
+        static UNKNOWN: &str = "fn f(x: int) {}";
+        let ctx = ParseContext::new(UNKNOWN);
+        Parser::new(UNKNOWN, &ctx).parse();
+        assert_eq!(ctx.errors()[0].message, "Unknown type `int`");
+    }
+
+    #[test]
+    fn test_if_brace_spans() {
+        static INPUT: &str = "if a { b() } else if c { d() } else { e() }";
+        let ctx = ParseContext::new(INPUT);
+        let block = Parser::new(INPUT, &ctx).parse_block();
+        assert!(ctx.errors().is_empty());
+        let text = |span: &crate::span::Span| &INPUT[span.pos.0..span.pos.1];
+        let next = match &block.body[0] {
+            Statement::If(_, _, Some(next), keyword, braces) => {
+                assert_eq!(text(keyword), "if");
+                assert_eq!(text(braces), "{ b() }");
+                next
+            }
+            stmt => panic!("Expected if, found {:?}", stmt),
+        };
+        let next = match &**next {
+            Statement::If(_, _, Some(next), keyword, braces) => {
+                assert_eq!(text(keyword), "else if");
+                assert_eq!(text(braces), "{ d() }");
+                next
+            }
+            stmt => panic!("Expected else if, found {:?}", stmt),
+        };
+        match &**next {
+            Statement::Else(_, keyword, braces) => {
+                assert_eq!(text(keyword), "else");
+                assert_eq!(text(braces), "{ e() }");
+            }
+            stmt => panic!("Expected else, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn test_break_before_newline() {
+        static INPUT: &str = "loop {\n break\n print_int(1)\n }";
+        let ctx = ParseContext::new(INPUT);
+        let block = Parser::new(INPUT, &ctx).parse_block();
+        assert!(ctx.errors().is_empty());
+        match &block.body[0] {
+            Statement::Loop(None, block) => {
+                assert!(matches!(block.body[0], Statement::Break(None, None, _)));
+                assert!(matches!(block.body[1], Statement::Expression(_)));
+            }
+            stmt => panic!("Expected loop, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn test_break_value() {
+        static INPUT: &str = "let x = loop {\n break 5\n }\n loop {\n break\n x = 1\n }";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        let block = parser.parse_block();
+        assert!(ctx.errors().is_empty());
+        match &block.body[0] {
+            Statement::Assign(_, Expression::Loop(block, _)) => {
+                assert!(matches!(block.body[0], Statement::Break(None, Some(_), _)))
+            }
+            stmt => panic!("Expected loop expression, found {:?}", stmt),
+        }
+        match &block.body[1] {
+            Statement::Loop(None, block) => {
+                assert!(matches!(block.body[0], Statement::Break(None, None, _)));
+                assert!(matches!(block.body[1], Statement::Mutate(..)));
+            }
+            stmt => panic!("Expected loop, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn test_loop_label() {
+        static INPUT: &str = "'outer: loop {\n loop {\n break 'outer\n }\n }";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        let block = parser.parse_block();
+        assert!(ctx.errors().is_empty());
+        match &block.body[0] {
+            Statement::Loop(Some(label), block) => {
+                assert_eq!(&INPUT[label.pos.0..label.pos.1], "'outer");
+                match &block.body[0] {
+                    Statement::Loop(None, inner) => match &inner.body[0] {
+                        Statement::Break(Some(label), None, _) => {
+                            assert_eq!(&INPUT[label.pos.0..label.pos.1], "'outer")
+                        }
+                        stmt => panic!("Expected labeled break, found {:?}", stmt),
+                    },
+                    stmt => panic!("Expected loop, found {:?}", stmt),
+                }
+            }
+            stmt => panic!("Expected labeled loop, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn test_semicolons() {
+        static INPUT: &str = "let a = 1; let b = 2\n loop { break; }\n fn f() { return; }";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        let block = parser.parse_block();
+        assert!(ctx.errors().is_empty());
+        assert_eq!(block.body.len(), 4);
+        assert!(matches!(block.body[0], Statement::Assign(..)));
+        assert!(matches!(block.body[1], Statement::Assign(..)));
     }
 }
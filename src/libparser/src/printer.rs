@@ -0,0 +1,330 @@
+use crate::ast::*;
+use crate::span::Span;
+
+const INDENT: &str = "    ";
+
+fn text(span: &Span, input: &str) -> String {
+    String::from(&input[span.pos.0..span.pos.1])
+}
+
+fn symbol(op: &Op) -> &'static str {
+    match op {
+        Op::Star => "*",
+        Op::Slash => "/",
+        Op::Plus => "+",
+        Op::Minus => "-",
+        Op::Mod => "%",
+        Op::Eq => "==",
+        Op::NotEq => "!=",
+        Op::LtEq => "<=",
+        Op::GtEq => ">=",
+        Op::Lt => "<",
+        Op::Gt => ">",
+        Op::Not => "!",
+        Op::And => "&&",
+        Op::Or => "||",
+        Op::Shl => "<<",
+        Op::Shr => ">>",
+        Op::Xor => "^",
+    }
+}
+
+/// Binding strength of a binary operator, higher binds tighter
+fn precedence(op: &Op) -> u8 {
+    match op {
+        Op::Or => 1,
+        Op::And => 2,
+        Op::Eq | Op::NotEq => 3,
+        Op::Lt | Op::Gt | Op::LtEq | Op::GtEq => 4,
+        Op::Xor => 5,
+        Op::Shl | Op::Shr => 6,
+        Op::Plus | Op::Minus => 7,
+        Op::Star | Op::Slash | Op::Mod => 8,
+        Op::Not => 9,
+    }
+}
+
+fn type_name(t: &Type) -> &'static str {
+    match t {
+        Type::Str => "str",
+        Type::Int => "i32",
+        Type::UInt => "u32",
+        Type::Float => "f32",
+        Type::Bool => "bool",
+        Type::Void => "void",
+    }
+}
+
+impl Expression {
+    /// Reconstruct source text for this expression using the input its spans
+    /// point into.  Only the parentheses needed to keep precedence are added.
+    /// ```
+    /// # use libparser::parse_context::ParseContext;
+    /// # use libparser::parser::Parser;
+    /// static INPUT: &str = "(5 + 3) * f(2)";
+    /// let ctx = ParseContext::new(INPUT);
+    /// let mut parser = Parser::new(INPUT, &ctx);
+    /// let expr = parser.parse_expression();
+    /// assert_eq!(expr.to_source(INPUT), "(5 + 3) * f(2)");
+    /// ```
+    pub fn to_source(&self, input: &str) -> String {
+        self.to_source_at(input, 0)
+    }
+
+    /// Reconstruct source text for an expression inside a block `indent`
+    /// levels deep
+    fn to_source_at(&self, input: &str, indent: usize) -> String {
+        match self {
+            Expression::Literal { val, .. } => text(val, input),
+            Expression::Ident { val } => text(val, input),
+            Expression::Binary(lhs, op, rhs, _) => {
+                let prec = precedence(op);
+                let lhs = match **lhs {
+                    Expression::Binary(_, ref child, _, _) if precedence(child) < prec => {
+                        format!("({})", lhs.to_source_at(input, indent))
+                    }
+                    _ => lhs.to_source_at(input, indent),
+                };
+                let rhs = match **rhs {
+                    Expression::Binary(_, ref child, _, _) if precedence(child) <= prec => {
+                        format!("({})", rhs.to_source_at(input, indent))
+                    }
+                    _ => rhs.to_source_at(input, indent),
+                };
+                format!("{} {} {}", lhs, symbol(op), rhs)
+            }
+            Expression::Unary(op, expr, _) => match **expr {
+                Expression::Binary(..) => {
+                    format!("{}({})", symbol(op), expr.to_source_at(input, indent))
+                }
+                _ => format!("{}{}", symbol(op), expr.to_source_at(input, indent)),
+            },
+            Expression::FunctionCall(name, args, _) => format!(
+                "{}({})",
+                text(name, input),
+                args.iter()
+                    .map(|(arg, _)| arg.to_source_at(input, indent))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Expression::Range(start, end, _) => {
+                format!(
+                    "{}..{}",
+                    start.to_source_at(input, indent),
+                    end.to_source_at(input, indent)
+                )
+            }
+            Expression::Unit(_) => String::from("()"),
+            Expression::Loop(block, _) => format!("loop {}", block.to_source(input, indent)),
+            Expression::Field(object, field) => {
+                format!(
+                    "{}.{}",
+                    object.object_source(input, indent),
+                    text(field, input)
+                )
+            }
+            Expression::MethodCall(object, name, args) => format!(
+                "{}.{}({})",
+                object.object_source(input, indent),
+                text(name, input),
+                args.iter()
+                    .map(|(arg, _)| arg.to_source_at(input, indent))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Expression::Dummy => String::from("<error>"),
+        }
+    }
+
+    /// Source for the object of a field access or method call, which binds
+    /// tighter than any operator
+    fn object_source(&self, input: &str, indent: usize) -> String {
+        match self {
+            Expression::Binary(..) | Expression::Unary(..) => {
+                format!("({})", self.to_source_at(input, indent))
+            }
+            _ => self.to_source_at(input, indent),
+        }
+    }
+}
+
+impl Statement {
+    /// Reconstruct source text for this statement, indenting nested blocks
+    /// `indent` levels deep
+    pub fn to_source(&self, input: &str, indent: usize) -> String {
+        match self {
+            Statement::Assign(name, expr) => {
+                format!(
+                    "let {} = {}",
+                    text(name, input),
+                    expr.to_source_at(input, indent)
+                )
+            }
+            Statement::Const(name, expr) => {
+                format!(
+                    "const {} = {}",
+                    text(name, input),
+                    expr.to_source_at(input, indent)
+                )
+            }
+            Statement::FnDecl {
+                name,
+                return_type,
+                args,
+                block,
+            } => {
+                let args = args
+                    .iter()
+                    .map(|arg| match arg {
+                        Ident::Typed(name, t) => format!("{}: {}", text(name, input), type_name(t)),
+                        Ident::Untyped(name) => text(name, input),
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                let return_type = match return_type {
+                    Type::Void => String::new(),
+                    t => format!(" -> {}", type_name(t)),
+                };
+                format!(
+                    "fn {}({}){} {}",
+                    text(name, input),
+                    args,
+                    return_type,
+                    block.to_source(input, indent)
+                )
+            }
+            Statement::Return(Expression::Unit(_), _) => String::from("return"),
+            Statement::Return(expr, _) => format!("return {}", expr.to_source_at(input, indent)),
+            Statement::Mutate(name, expr) => {
+                format!(
+                    "{} = {}",
+                    text(name, input),
+                    expr.to_source_at(input, indent)
+                )
+            }
+            Statement::If(expr, block, next, ..) => {
+                let mut out = format!(
+                    "if {} {}",
+                    expr.to_source_at(input, indent),
+                    block.to_source(input, indent)
+                );
+                if let Some(next) = next {
+                    out.push_str(" else ");
+                    out.push_str(&next.to_source(input, indent));
+                }
+                out
+            }
+            Statement::Else(block, ..) => block.to_source(input, indent),
+            Statement::Loop(Some(label), block) => format!(
+                "{}: loop {}",
+                text(label, input),
+                block.to_source(input, indent)
+            ),
+            Statement::Loop(None, block) => format!("loop {}", block.to_source(input, indent)),
+            Statement::DoWhile(block, cond) => format!(
+                "do {} while {}",
+                block.to_source(input, indent),
+                cond.to_source_at(input, indent)
+            ),
+            Statement::For(name, range, block) => format!(
+                "for {} in {} {}",
+                text(name, input),
+                range.to_source_at(input, indent),
+                block.to_source(input, indent)
+            ),
+            Statement::Block(block) => block.to_source(input, indent),
+            Statement::Break(label, value, _) => {
+                let mut out = String::from("break");
+                if let Some(label) = label {
+                    out.push(' ');
+                    out.push_str(&text(label, input));
+                }
+                if let Some(value) = value {
+                    out.push(' ');
+                    out.push_str(&value.to_source_at(input, indent));
+                }
+                out
+            }
+            Statement::Expression(expr) => expr.to_source_at(input, indent),
+            Statement::Dummy => String::from("<error>"),
+        }
+    }
+}
+
+impl Block {
+    /// Reconstruct source text for a braced block whose closing brace sits at
+    /// `indent` levels deep
+    pub fn to_source(&self, input: &str, indent: usize) -> String {
+        let mut out = String::from("{\n");
+        for stmt in self.body.iter() {
+            out.push_str(&INDENT.repeat(indent + 1));
+            out.push_str(&stmt.to_source(input, indent + 1));
+            out.push('\n');
+        }
+        out.push_str(&INDENT.repeat(indent));
+        out.push('}');
+        out
+    }
+
+    /// Reconstruct source text for a whole program (a block without braces)
+    /// ```
+    /// # use libparser::parse_context::ParseContext;
+    /// # use libparser::parser::Parser;
+    /// static INPUT: &str = "fn main() { let a = 1 }";
+    /// let ctx = ParseContext::new(INPUT);
+    /// let mut parser = Parser::new(INPUT, &ctx);
+    /// let block = parser.parse();
+    /// assert_eq!(block.to_program_source(INPUT), "fn main() {\n    let a = 1\n}\n");
+    /// ```
+    pub fn to_program_source(&self, input: &str) -> String {
+        let mut out = String::new();
+        for stmt in self.body.iter() {
+            out.push_str(&stmt.to_source(input, 0));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_context::ParseContext;
+    use crate::parser::Parser;
+
+    fn print_expression(input: &str) -> String {
+        let ctx = ParseContext::new(input);
+        let mut parser = Parser::new(input, &ctx);
+        parser.parse_expression().to_source(input)
+    }
+
+    #[test]
+    fn test_print_expression() {
+        assert_eq!(print_expression("5 + 3 * 2"), "5 + 3 * 2");
+        assert_eq!(print_expression("(5 + 3) * 2"), "(5 + 3) * 2");
+        assert_eq!(print_expression("5 - (3 - 2)"), "5 - (3 - 2)");
+        assert_eq!(print_expression("((1)) == f(a, b + 1)"), "1 == f(a, b + 1)");
+        assert_eq!(print_expression("a || b && c < d"), "a || b && c < d");
+        assert_eq!(print_expression("(1 + 2).y"), "(1 + 2).y");
+        assert_eq!(print_expression("(a - b).len(1)"), "(a - b).len(1)");
+        assert_eq!(print_expression("(-a).abs()"), "(-a).abs()");
+        assert_eq!(print_expression("a.b.c(d + 1)"), "a.b.c(d + 1)");
+    }
+
+    #[test]
+    fn test_print_program() {
+        static INPUT: &str = r"
+fn fib(num: i32) -> i32 {
+    if num < 2 { return num }
+    return fib(num - 1) + fib(num - 2)
+}
+";
+        let ctx = ParseContext::new(INPUT);
+        let mut parser = Parser::new(INPUT, &ctx);
+        let block = parser.parse();
+        assert_eq!(
+            block.to_program_source(INPUT),
+            "fn fib(num: i32) -> i32 {\n    if num < 2 {\n        return num\n    }\n    \
+             return fib(num - 1) + fib(num - 2)\n}\n"
+        );
+    }
+}
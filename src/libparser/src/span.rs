@@ -1,5 +1,6 @@
 /// Span represents a chunk of code with its starting index and ending index.
 #[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Span {
     pub pos: (usize, usize),
     pub is_dummy: bool,
@@ -29,6 +30,16 @@ impl Span {
         }
     }
 
+    /// A span from the start of `a` to the end of `b`
+    /// ```
+    /// # use libparser::span::Span;
+    /// let span = Span::merge(Span::new(2, 3), Span::new(6, 9));
+    /// assert_eq!(span.pos, (2, 9));
+    /// ```
+    pub fn merge(a: Span, b: Span) -> Span {
+        Span::new(a.pos.0, b.pos.1)
+    }
+
     /// Create a new dummy span with pos `(0, 0)`
     /// ```
     /// # use libparser::span::Span;
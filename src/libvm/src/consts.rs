@@ -1,5 +1,6 @@
 pub const NOP: u8 = 0x00;
 pub const PUSH_I: u8 = 0x01;
+pub const PUSH_B: u8 = 0x03;
 
 pub const ADD_I: u8 = 0x0c;
 pub const SUB_I: u8 = 0x0d;
@@ -7,6 +8,10 @@ pub const MUL_I: u8 = 0x0e;
 pub const DIV_I: u8 = 0x0f;
 pub const MOD_I: u8 = 0x10;
 pub const NEG_I: u8 = 0x18;
+pub const MIN_I: u8 = 0x19;
+pub const MAX_I: u8 = 0x1a;
+/// Absolute value, `i32::MIN` wraps to itself
+pub const ABS_I: u8 = 0x1b;
 
 pub const ADD_F: u8 = 0x2c;
 pub const SUB_F: u8 = 0x2d;
@@ -14,6 +19,9 @@ pub const MUL_F: u8 = 0x2e;
 pub const DIV_F: u8 = 0x2f;
 pub const MOD_F: u8 = 0x30;
 pub const NEG_F: u8 = 0x28;
+pub const MIN_F: u8 = 0x45;
+pub const MAX_F: u8 = 0x46;
+pub const ABS_F: u8 = 0x47;
 
 pub const NE: u8 = 0x11;
 pub const EQ: u8 = 0x12;
@@ -25,10 +33,41 @@ pub const GT_F: u8 = 0x23;
 pub const LT_F: u8 = 0x24;
 pub const LE_F: u8 = 0x25;
 pub const GE_F: u8 = 0x26;
+pub const NE_F: u8 = 0x29;
+pub const EQ_F: u8 = 0x2a;
+pub const BNE: u8 = 0x31;
+pub const BEQ: u8 = 0x32;
+
+pub const CONCAT: u8 = 0x33;
+pub const I2S: u8 = 0x34;
+pub const F2S: u8 = 0x35;
+/// Compare two length-prefixed strings byte for byte
+pub const NE_V: u8 = 0x36;
+pub const EQ_V: u8 = 0x37;
+
+pub const ADD_U: u8 = 0x38;
+pub const SUB_U: u8 = 0x39;
+pub const MUL_U: u8 = 0x3a;
+pub const DIV_U: u8 = 0x3b;
+pub const MOD_U: u8 = 0x3c;
+pub const GT_U: u8 = 0x3d;
+pub const LT_U: u8 = 0x3e;
+pub const LE_U: u8 = 0x3f;
+pub const GE_U: u8 = 0x40;
+
+pub const SHL_I: u8 = 0x41;
+pub const SHR_I: u8 = 0x42;
+pub const SHR_U: u8 = 0x43;
+/// Bitwise exclusive or, the same for signed and unsigned integers
+pub const XOR_I: u8 = 0x44;
 
 pub const NOT: u8 = 0x17;
 
+pub const ASSERT: u8 = 0x04;
+
 pub const CMP_I: u8 = 0x20;
+pub const NEZ: u8 = 0x21;
+pub const EQZ: u8 = 0x22;
 
 pub const IF_T: u8 = 0xa0;
 pub const IF_F: u8 = 0xa1;
@@ -40,6 +79,8 @@ pub const IF_LT: u8 = 0xa5;
 pub const IF_LE: u8 = 0xa6;
 pub const IF_GE: u8 = 0xa7;
 
+pub const POP_B: u8 = 0xdd;
+pub const DUP_B: u8 = 0xde;
 pub const DUP_I: u8 = 0xdf;
 
 pub const GOTO: u8 = 0xc0;
@@ -48,15 +89,26 @@ pub const LDC: u8 = 0xfa;
 
 pub const LOAD_I: u8 = 0xfb;
 pub const LOAD_V: u8 = 0xf0;
+pub const LOAD_B: u8 = 0xf2;
+/// Same as `LOAD_I`, marks the register as holding an f32
+pub const LOAD_F: u8 = 0xf4;
 
 pub const STO_I: u8 = 0xfc;
 pub const STO_V: u8 = 0xf1;
+pub const STO_B: u8 = 0xf3;
+/// Same as `STO_I`, marks the register as holding an f32
+pub const STO_F: u8 = 0xf5;
 
 pub const CALL: u8 = 0xfd;
 
 pub const VIRTUAL: u8 = 0xfe;
 
+pub const RET_V: u8 = 0xf9;
 pub const RET_I: u8 = 0xff;
+/// Return the number of bytes given by the operand from the top of the stack
+pub const RET: u8 = 0xf8;
+/// Return a string in it's register layout: the 2 byte length then the bytes
+pub const RET_S: u8 = 0xf7;
 
 /// Convert each opcode into it's string variant and return none if unknown
 /// ```
@@ -68,19 +120,30 @@ pub fn disassemble_each(val: u8) -> Option<&'static str> {
     match val {
         NOP => Some("nop"),
         PUSH_I => Some("push_i"),
+        PUSH_B => Some("push_b"),
         ADD_I => Some("add_i"),
         SUB_I => Some("sub_i"),
         MUL_I => Some("mul_i"),
         DIV_I => Some("div_i"),
         MOD_I => Some("mod_i"),
         CMP_I => Some("cmp_i"),
+        NEZ => Some("nez"),
+        EQZ => Some("eqz"),
         ADD_F => Some("add_f"),
         SUB_F => Some("sub_f"),
         MUL_F => Some("mul_f"),
         DIV_F => Some("div_f"),
         MOD_F => Some("mod_f"),
         NOT => Some("not"),
+        ASSERT => Some("assert"),
         NEG_I => Some("neg_i"),
+        NEG_F => Some("neg_f"),
+        MIN_I => Some("min_i"),
+        MAX_I => Some("max_i"),
+        ABS_I => Some("abs_i"),
+        MIN_F => Some("min_f"),
+        MAX_F => Some("max_f"),
+        ABS_F => Some("abs_f"),
         NE => Some("ne"),
         EQ => Some("eq"),
         LT_I => Some("lt_i"),
@@ -91,6 +154,28 @@ pub fn disassemble_each(val: u8) -> Option<&'static str> {
         GT_F => Some("gt_f"),
         LE_F => Some("le_f"),
         GE_F => Some("ge_f"),
+        NE_F => Some("ne_f"),
+        EQ_F => Some("eq_f"),
+        BNE => Some("bne"),
+        BEQ => Some("beq"),
+        CONCAT => Some("concat"),
+        NE_V => Some("ne_v"),
+        EQ_V => Some("eq_v"),
+        I2S => Some("i2s"),
+        F2S => Some("f2s"),
+        ADD_U => Some("add_u"),
+        SUB_U => Some("sub_u"),
+        MUL_U => Some("mul_u"),
+        DIV_U => Some("div_u"),
+        MOD_U => Some("mod_u"),
+        GT_U => Some("gt_u"),
+        LT_U => Some("lt_u"),
+        LE_U => Some("le_u"),
+        GE_U => Some("ge_u"),
+        SHL_I => Some("shl_i"),
+        SHR_I => Some("shr_i"),
+        SHR_U => Some("shr_u"),
+        XOR_I => Some("xor_i"),
         IF_T => Some("if_t"),
         IF_F => Some("if_f"),
         IF_NE => Some("if_ne"),
@@ -99,20 +184,58 @@ pub fn disassemble_each(val: u8) -> Option<&'static str> {
         IF_LT => Some("if_lt"),
         IF_LE => Some("if_le"),
         IF_GE => Some("if_ge"),
+        POP_B => Some("pop_b"),
+        DUP_B => Some("dup_b"),
         DUP_I => Some("dup_i"),
         GOTO => Some("goto"),
         LDC => Some("ldc"),
         CALL => Some("call"),
         LOAD_I => Some("load_i"),
         LOAD_V => Some("load_v"),
+        LOAD_B => Some("load_b"),
+        LOAD_F => Some("load_f"),
         STO_I => Some("sto_i"),
         STO_V => Some("sto_v"),
+        STO_B => Some("sto_b"),
+        STO_F => Some("sto_f"),
         VIRTUAL => Some("virtual"),
+        RET_V => Some("ret_v"),
         RET_I => Some("ret_i"),
+        RET => Some("ret"),
+        RET_S => Some("ret_s"),
         _ => None,
     }
 }
 
+/// Number of operand bytes following an opcode
+/// ```
+/// # use libvm::consts::*;
+/// assert_eq!(operand_len(PUSH_I), 4);
+/// assert_eq!(operand_len(GOTO), 2);
+/// assert_eq!(operand_len(ADD_I), 0);
+/// ```
+pub fn operand_len(op: u8) -> usize {
+    match op {
+        PUSH_I => 4,
+        // Constant and function indices and jump targets are 2 byte
+        // big-endian numbers
+        LDC | CALL | GOTO | IF_T..=IF_GE => 2,
+        PUSH_B | VIRTUAL | STO_I | LOAD_I | STO_F | LOAD_F | STO_V | LOAD_V | STO_B | LOAD_B
+        | RET => 1,
+        _ => 0,
+    }
+}
+
+/// Is the opcode a jump whose operand is a program index
+/// ```
+/// # use libvm::consts::*;
+/// assert!(is_jump(IF_F));
+/// assert!(!is_jump(CALL));
+/// ```
+pub fn is_jump(op: u8) -> bool {
+    matches!(op, GOTO | IF_T..=IF_GE)
+}
+
 /// Disassemble a program of bytecode
 pub fn disassemble(program: &[u8]) -> String {
     let mut out = String::new();
@@ -141,14 +264,7 @@ pub fn disassemble(program: &[u8]) -> String {
             out.push(' ');
         }
         out.push_str("\u{001b}[0m"); // reset
-        match *v {
-            PUSH_I => push_n!(4),
-            VIRTUAL | GOTO | STO_I | LOAD_I | STO_V | LOAD_V | LDC | CALL | IF_T..=IF_GE => {
-                push_n!(1)
-            }
-
-            _ => {}
-        }
+        push_n!(operand_len(*v));
         out.push('\n');
     }
     out
@@ -1,9 +1,16 @@
 use crate::module::Module;
-use crate::vm::Vm;
-use crate::vm_type::Type;
+use crate::stack::Stack;
+use crate::vm::{Vm, VmError};
+use crate::vm_type::{Type, Value};
 use std::cell::RefCell;
+use std::io::Write;
 use std::rc::Rc;
 
+thread_local! {
+    /// Stack allocations of finished calls, reused by the next calls
+    static STACKS: RefCell<Vec<Box<dyn Stack>>> = const { RefCell::new(Vec::new()) };
+}
+
 /// A vm function.  A function contains a program which is a set of instructions
 /// run by the virtual machine.  A function also has typed params and a return
 /// type.
@@ -13,6 +20,10 @@ pub struct Function {
     params: Vec<Type>,
     return_type: Type,
     module: Rc<RefCell<Module>>,
+    /// Instruction offsets and the source span they were generated from
+    lines: Vec<(usize, (usize, usize))>,
+    /// Bytes of registers used by the params and locals, zeroed before a call
+    locals: usize,
 }
 
 impl Function {
@@ -37,9 +48,94 @@ impl Function {
             params,
             return_type,
             module,
+            lines: Vec::new(),
+            locals: 0,
         }
     }
 
+    /// Attach a line table mapping instruction offsets to the source spans
+    /// they were generated from.  Errors raised by those instructions are
+    /// wrapped in [`VmError::At`](../vm/enum.VmError.html), errors from
+    /// instructions without an entry are returned as they are.
+    ///
+    /// # Examples
+    /// ```
+    /// # use libvm::function::*;
+    /// # use libvm::consts::*;
+    /// # use libvm::vm::VmError;
+    /// let program = vec![
+    ///     PUSH_I, 0, 0, 0, 1,
+    ///     PUSH_I, 0, 0, 0, 0,
+    ///     DIV_I,
+    /// ];
+    /// let func = Function::new(program.clone(), vec![], Default::default(), Default::default())
+    ///     .with_lines(vec![(10, (4, 9))]);
+    /// assert_eq!(
+    ///     func.try_run(vec![]),
+    ///     Err(VmError::At(Box::new(VmError::DivisionByZero), (4, 9)))
+    /// );
+    /// let func = Function::new(program, vec![], Default::default(), Default::default())
+    ///     .with_lines(vec![(5, (0, 1))]);
+    /// assert_eq!(func.try_run(vec![]), Err(VmError::DivisionByZero));
+    /// ```
+    pub fn with_lines(mut self, lines: Vec<(usize, (usize, usize))>) -> Function {
+        self.lines = lines;
+        self
+    }
+
+    /// Set the number of register bytes used by the function's params and
+    /// locals.  Registers past the params are zeroed before the function runs
+    /// so reading a local before it is stored gives `0`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use libvm::function::*;
+    /// # use libvm::consts::*;
+    /// let func = Function::new(vec![LOAD_I, 4, RET_I], vec![], Default::default(), Default::default())
+    ///     .with_locals(8);
+    /// assert_eq!(func.try_run(vec![0, 0, 0, 1]), Ok(vec![0, 0, 0, 0]));
+    /// ```
+    pub fn with_locals(mut self, locals: usize) -> Function {
+        self.locals = locals;
+        self
+    }
+
+    /// Returns the number of register bytes used by the params and locals
+    pub fn locals(&self) -> usize {
+        self.locals
+    }
+
+    /// The register file for a call: the params followed by zeroed locals
+    fn registers(&self, mut params: Vec<u8>) -> Vec<u8> {
+        if params.len() < self.locals {
+            params.resize(self.locals, 0);
+        }
+        params
+    }
+
+    /// Copy the function with a new program and module, keeping it's signature
+    pub(crate) fn relink(&self, program: Vec<u8>, module: Rc<RefCell<Module>>) -> Function {
+        Function {
+            program,
+            module,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the line table of the function
+    pub fn lines(&self) -> &Vec<(usize, (usize, usize))> {
+        &self.lines
+    }
+
+    /// The source span of the instruction at `pc`, if it has one
+    fn span_at(&self, pc: usize) -> Option<(usize, usize)> {
+        self.lines
+            .iter()
+            .rev()
+            .find(|(offset, _)| *offset == pc)
+            .map(|(_, span)| *span)
+    }
+
     /// Returns a reference to the return type of the function
     pub fn return_type(&self) -> &Type {
         &self.return_type
@@ -69,10 +165,128 @@ impl Function {
     ///     RET_I
     /// ], vec![], Default::default(), Default::default());
     /// let out = func.run(vec![]);
-    /// assert_eq!(out, vec![5, 0, 0, 0]);
+    /// assert_eq!(out, vec![0, 0, 0, 5]);
     /// ```
     pub fn run(&self, params: Vec<u8>) -> Vec<u8> {
-        let mut vm = Vm::new(self.program.as_slice(), params, Rc::clone(&self.module));
+        let regs = self.registers(params);
+        let mut vm = Vm::new(self.program.as_slice(), regs, Rc::clone(&self.module));
         vm.run()
     }
+
+    /// Runs the program like `run` and decodes the result using the function's
+    /// return type
+    ///
+    /// # Examples
+    /// ```
+    /// # use libvm::function::*;
+    /// # use libvm::consts::*;
+    /// # use libvm::vm_type::*;
+    /// let func = Function::new(vec![
+    ///     PUSH_I, 0x40, 0x20, 0, 0,
+    ///     RET_I
+    /// ], vec![], Type::F32, Default::default());
+    /// assert_eq!(func.run_typed(vec![]), Value::Float(2.5));
+    /// ```
+    pub fn run_typed(&self, params: Vec<u8>) -> Value {
+        Value::decode(&self.return_type, &self.run(params))
+    }
+
+    /// Runs the program with typed arguments, checking them against the
+    /// function's params, and decodes the result using it's return type
+    ///
+    /// # Examples
+    /// ```
+    /// # use libvm::function::*;
+    /// # use libvm::consts::*;
+    /// # use libvm::vm::VmError;
+    /// # use libvm::vm_type::*;
+    /// let func = Function::new(vec![
+    ///     LOAD_I, 0, LOAD_I, 4, ADD_I,
+    ///     RET_I
+    /// ], vec![Type::I32, Type::I32], Type::I32, Default::default());
+    /// assert_eq!(func.run_args(&[Value::Int(2), Value::Int(3)]), Ok(Value::Int(5)));
+    /// assert_eq!(
+    ///     func.run_args(&[Value::Int(2), Value::Float(3.0)]),
+    ///     Err(VmError::ArgumentType { index: 1, expected: Type::I32 })
+    /// );
+    /// ```
+    pub fn run_args(&self, args: &[Value]) -> Result<Value, VmError> {
+        if args.len() != self.params.len() {
+            return Err(VmError::ArgumentCount {
+                expected: self.params.len(),
+                found: args.len(),
+            });
+        }
+        let mut regs = Vec::new();
+        for (index, (arg, param)) in args.iter().zip(self.params.iter()).enumerate() {
+            let bytes = arg.encode(param).ok_or_else(|| VmError::ArgumentType {
+                index,
+                expected: param.clone(),
+            })?;
+            regs.extend(bytes);
+        }
+        let out = self.try_run(regs)?;
+        Ok(Value::decode(&self.return_type, &out))
+    }
+
+    /// Runs the program like `run` but returns an error instead of panicking
+    pub fn try_run(&self, params: Vec<u8>) -> Result<Vec<u8>, VmError> {
+        self.try_run_with_output(params, &mut std::io::stdout())
+    }
+
+    /// Runs the program like `try_run`, printing to `output` instead of stdout
+    /// # Examples
+    /// ```
+    /// # use libvm::function::*;
+    /// # use libvm::consts::*;
+    /// let func = Function::new(vec![
+    ///     PUSH_I, 0, 0, 0, 5,
+    ///     VIRTUAL, 0x00,
+    ///     RET_V
+    /// ], vec![], Default::default(), Default::default());
+    /// let mut out = Vec::new();
+    /// assert_eq!(func.try_run_with_output(vec![], &mut out), Ok(vec![]));
+    /// assert_eq!(out, b"5\n");
+    /// ```
+    pub fn try_run_with_output(
+        &self,
+        params: Vec<u8>,
+        output: &mut dyn Write,
+    ) -> Result<Vec<u8>, VmError> {
+        self.try_run_at(params, 0, output)
+    }
+
+    /// Runs the program as a call nested `depth` calls deep
+    pub(crate) fn try_run_at(
+        &self,
+        params: Vec<u8>,
+        depth: usize,
+        output: &mut dyn Write,
+    ) -> Result<Vec<u8>, VmError> {
+        let stack = STACKS.with(|stacks| stacks.borrow_mut().pop());
+        let stack = stack.unwrap_or_else(|| Box::new(Vec::new()));
+        let regs = self.registers(params);
+        let mut vm = Vm::new(self.program.as_slice(), regs, Rc::clone(&self.module))
+            .with_call_depth(depth)
+            .with_stack(stack)
+            .with_output(Box::new(output));
+        let out = loop {
+            let pc = vm.pc();
+            match vm.step() {
+                Ok(Some(ret)) => break Ok(ret),
+                Ok(None) => {}
+                // Errors from a nested call are already located
+                Err(err @ VmError::At(..)) => break Err(err),
+                Err(err) => {
+                    break Err(match self.span_at(pc) {
+                        Some(span) => VmError::At(Box::new(err), span),
+                        None => err,
+                    })
+                }
+            }
+        };
+        let stack = vm.into_stack();
+        STACKS.with(|stacks| stacks.borrow_mut().push(stack));
+        out
+    }
 }
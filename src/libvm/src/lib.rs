@@ -1,7 +1,6 @@
-extern crate byteorder;
-
 pub mod consts;
 pub mod function;
 pub mod module;
+pub mod stack;
 pub mod vm;
 pub mod vm_type;
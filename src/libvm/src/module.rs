@@ -1,32 +1,227 @@
 use crate::consts;
 use crate::function::Function;
+use crate::stack::Stack;
+use crate::vm::VmError;
 use crate::vm_type::Type;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
 
-#[derive(Default, PartialEq, Debug)]
+/// Major version of the object format.  Objects with a different major
+/// version can't be loaded.
+pub const FORMAT_MAJOR: u8 = 3;
+/// Minor version of the object format.  Bumped for backwards compatible
+/// changes.
+pub const FORMAT_MINOR: u8 = 1;
+
+/// Error produced when loading a module from an object file
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    /// The object doesn't start with the magic bytes
+    BadMagic,
+    /// The object ended before the module was fully read
+    UnexpectedEof,
+    /// A type tag that doesn't correspond to any type
+    UnknownType(u8),
+    /// The object was written with an incompatible major version
+    UnsupportedVersion { found: u8, expected: u8 },
+}
+
+/// Error produced when merging two modules
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    /// Both modules define a function with this name
+    DuplicateSymbol(String),
+    /// A rebased constant index doesn't fit in a 2 byte operand
+    TooManyConstants,
+}
+
+/// A structural problem in a function's bytecode found by
+/// [`Module::verify`](struct.Module.html#method.verify).  `function` is the
+/// function's index and `at` the offset of the instruction in it's program.
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// A byte that doesn't correspond to any opcode
+    UnknownOpcode {
+        function: usize,
+        at: usize,
+        opcode: u8,
+    },
+    /// The program ends before the instruction's operand does
+    TruncatedOperand { function: usize, at: usize },
+    /// A jump to the middle of an instruction or past the end of the program
+    BadJump {
+        function: usize,
+        at: usize,
+        target: usize,
+    },
+    /// An `LDC` index that isn't the start of a constant
+    BadConstant {
+        function: usize,
+        at: usize,
+        index: usize,
+    },
+    /// A `CALL` index that isn't a function in the module
+    BadFunction {
+        function: usize,
+        at: usize,
+        index: usize,
+    },
+}
+
+/// Rewrite the operand of every `LDC` and `CALL` in a program with `relocate`
+fn relocate(program: &[u8], relocate: impl Fn(u8, usize) -> usize) -> Result<Vec<u8>, LinkError> {
+    let mut out = program.to_vec();
+    let mut i = 0;
+    while i < out.len() {
+        let op = out[i];
+        if (op == consts::LDC || op == consts::CALL) && i + 2 < out.len() {
+            let operand = u16::from_be_bytes([out[i + 1], out[i + 2]]);
+            let operand = relocate(op, operand as usize);
+            if operand > u16::MAX as usize {
+                return Err(LinkError::TooManyConstants);
+            }
+            out[i + 1..i + 3].copy_from_slice(&(operand as u16).to_be_bytes());
+        }
+        i += 1 + consts::operand_len(op);
+    }
+    Ok(out)
+}
+
+/// Summary of an object file read by [`Module::peek_header`](struct.Module.html#method.peek_header)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ModuleHeader {
+    pub major: u8,
+    pub minor: u8,
+    pub constants: usize,
+    pub functions: usize,
+}
+
+/// Size of a module, returned by [`Module::stats`](struct.Module.html#method.stats)
+#[derive(Debug, PartialEq, Clone)]
+pub struct ModuleStats {
+    pub functions: usize,
+    /// Bytes of bytecode in all functions
+    pub bytecode: usize,
+    /// Bytes in the constant pool
+    pub constants: usize,
+    /// Number of instructions in each function by function index, in index order
+    pub instructions: Vec<(usize, usize)>,
+}
+
+/// Reads the object format byte by byte
+struct ObjReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ObjReader<'a> {
+    fn is_eof(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        let byte = *self.bytes.get(self.pos).ok_or(LoadError::UnexpectedEof)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    /// Read a 4 byte big-endian length or index
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let out = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or(LoadError::UnexpectedEof)?;
+        self.pos += n;
+        Ok(out)
+    }
+
+    /// Check the magic bytes and read the version
+    fn header(&mut self) -> Result<(u8, u8), LoadError> {
+        if self.take(4).map_err(|_| LoadError::BadMagic)? != [0xBB; 4] {
+            return Err(LoadError::BadMagic);
+        }
+        Ok((self.byte()?, self.byte()?))
+    }
+
+    fn vm_type(&mut self) -> Result<Type, LoadError> {
+        let (out, len) = Type::deserialize(&self.bytes[self.pos.min(self.bytes.len())..])?;
+        self.pos += len;
+        Ok(out)
+    }
+}
+
+/// The deepest nesting of function calls allowed by default
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 256;
+
+#[derive(PartialEq, Debug)]
 pub struct Module {
     constants: Vec<u8>,
     functions: HashMap<usize, Function>,
+    max_call_depth: usize,
+}
+
+impl Default for Module {
+    fn default() -> Module {
+        Module {
+            constants: Vec::new(),
+            functions: HashMap::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        }
+    }
 }
 
 impl Module {
-    /// Creates a new string constant and returns it's index
+    /// Limit how deeply function calls may nest before
+    /// [`VmError::CallDepthExceeded`](../vm/enum.VmError.html) is returned
+    /// ```
+    /// # use libvm::module::*;
+    /// let mut module: Module = Default::default();
+    /// module.set_max_call_depth(16);
+    /// ```
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    /// Creates a new string constant and returns it's index.  Constants are
+    /// stored with a 2 byte big-endian length prefix.
     /// # Examples
     /// ```
     /// # use libvm::module::*;
     /// let mut module: Module = Default::default();
     /// let index = module.new_const("Hello, World!");
-    /// assert_eq!(module.constants()[index], 13);
-    /// assert_eq!(module.constants()[index + 1], 'H' as u8);
+    /// assert_eq!(module.constants()[index..index + 2], [0, 13]);
+    /// assert_eq!(module.constants()[index + 2], 'H' as u8);
     /// ```
     pub fn new_const(&mut self, val: &str) -> usize {
         let index = self.constants.len();
         let len = val.len();
-        self.constants.push(len as u8);
+        assert!(len <= u16::MAX as usize, "Constant is too long");
+        self.constants.extend((len as u16).to_be_bytes().iter());
         self.constants.extend(val.as_bytes().iter());
         index
     }
 
+    /// Returns the bytes of the constant at `index` without its length prefix
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// let mut module: Module = Default::default();
+    /// module.new_const("a");
+    /// let index = module.new_const("Hello");
+    /// assert_eq!(module.constant(index), b"Hello");
+    /// ```
+    pub fn constant(&self, index: usize) -> &[u8] {
+        let len = u16::from_be_bytes([self.constants[index], self.constants[index + 1]]);
+        &self.constants[index + 2..index + 2 + len as usize]
+    }
+
     /// Return the main function and panics if it doesn't exist
     /// # Examples
     /// ```
@@ -40,19 +235,47 @@ impl Module {
     /// assert_eq!(*main, func);
     /// ```
     pub fn get_main(&self) -> &Function {
+        self.get_fn_by_name("main").unwrap()
+    }
+
+    /// Returns the index of the function named `name`
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// let mut module: Module = Default::default();
+    /// module.new_const("not a function");
+    /// let index = module.new_const("f");
+    /// module.push_fn(index, Default::default());
+    /// assert_eq!(module.function_index("f"), Some(index));
+    /// assert_eq!(module.function_index("not a function"), None);
+    /// ```
+    pub fn function_index(&self, name: &str) -> Option<usize> {
+        self.find_fn(name.as_bytes()).map(|(index, _)| index)
+    }
+
+    /// Returns the function named `name`
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// # use libvm::function::Function;
+    /// # use libvm::vm_type::Type;
+    /// let mut module: Module = Default::default();
+    /// let func = Function::new(vec![], vec![Type::I32], Type::Void, Default::default());
+    /// let index = module.new_const("f");
+    /// module.push_fn(index, func.clone());
+    /// assert_eq!(module.get_fn_by_name("f"), Some(&func));
+    /// assert_eq!(module.get_fn_by_name("g"), None);
+    /// ```
+    pub fn get_fn_by_name(&self, name: &str) -> Option<&Function> {
+        self.find_fn(name.as_bytes()).map(|(_, func)| func)
+    }
+
+    /// Find a function by decoding the name constant of each function
+    fn find_fn(&self, name: &[u8]) -> Option<(usize, &Function)> {
         self.functions
             .iter()
-            .find(|(i, _)| {
-                let mut buffer = String::new();
-                let mut iter = self.constants.iter();
-                let len = iter.nth(**i).unwrap();
-                for _ in 0..*len {
-                    buffer.push(*iter.next().unwrap() as char);
-                }
-                buffer == "main"
-            })
-            .unwrap()
-            .1
+            .find(|(index, _)| self.constant(**index) == name)
+            .map(|(index, func)| (*index, func))
     }
 
     /// Disassembles the module and prints it out
@@ -64,23 +287,15 @@ impl Module {
     /// ```
     pub fn disassemble(&self) {
         println!("constants:");
-        let mut buffer = String::new();
-        let mut iter = self.constants.iter().enumerate();
-        while let Some((i, byte)) = iter.next() {
-            for _ in 0..*byte {
-                buffer.push(*iter.next().unwrap().1 as char);
-            }
-            println!("{}: {}", i, buffer);
-            buffer.clear();
+        let mut i = 0;
+        while i < self.constants.len() {
+            let constant = self.constant(i);
+            println!("{}: {}", i, String::from_utf8_lossy(constant));
+            i += constant.len() + 2;
         }
         println!();
         for (i, func) in self.functions.iter() {
-            let mut name = String::new();
-            let mut iter = self.constants.iter();
-            let len = iter.nth(*i).unwrap();
-            for _ in 0..*len {
-                name.push(*iter.next().unwrap() as char);
-            }
+            let name = String::from_utf8_lossy(self.constant(*i));
             println!(
                 "{}({:?}) -> {:?}:\n{}",
                 name,
@@ -103,7 +318,53 @@ impl Module {
     /// module.run_main();
     /// ```
     pub fn run_main(&self) {
-        self.get_main().run(Vec::new());
+        self.run_function("main", Vec::new()).unwrap();
+    }
+
+    /// Runs the function named `name` with `args` as it's parameter bytes
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// # use libvm::function::Function;
+    /// # use libvm::vm::VmError;
+    /// let mut module: Module = Default::default();
+    /// let func: Function = Default::default();
+    /// let index = module.new_const("f");
+    /// module.push_fn(index, func.clone());
+    /// assert_eq!(module.run_function("f", vec![]), Ok(vec![]));
+    /// assert_eq!(
+    ///     module.run_function("g", vec![]),
+    ///     Err(VmError::UnknownFunction(String::from("g")))
+    /// );
+    /// ```
+    pub fn run_function(&self, name: &str, args: Vec<u8>) -> Result<Vec<u8>, VmError> {
+        self.run_function_with_output(name, args, &mut std::io::stdout())
+    }
+
+    /// Runs the function named `name` like `run_function`, printing to
+    /// `output` instead of stdout
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// # use libvm::function::Function;
+    /// # use libvm::consts::*;
+    /// let mut module: Module = Default::default();
+    /// let index = module.new_const("f");
+    /// let program = vec![PUSH_B, 1, VIRTUAL, 0x04, RET_V];
+    /// module.push_fn(index, Function::new(program, vec![], Default::default(), Default::default()));
+    /// let mut out = Vec::new();
+    /// assert_eq!(module.run_function_with_output("f", vec![], &mut out), Ok(vec![]));
+    /// assert_eq!(out, b"true\n");
+    /// ```
+    pub fn run_function_with_output(
+        &self,
+        name: &str,
+        args: Vec<u8>,
+        output: &mut dyn Write,
+    ) -> Result<Vec<u8>, VmError> {
+        self.get_fn_by_name(name)
+            .ok_or_else(|| VmError::UnknownFunction(String::from(name)))?
+            .try_run_with_output(args, output)
     }
 
     /// Pushes a function to the module
@@ -139,7 +400,9 @@ impl Module {
     }
 
     /// Calls a function with a stack as parameters and return's its return
-    /// results
+    /// results.  `depth` is the number of calls already in progress and is
+    /// checked against the module's maximum call depth.  The function prints
+    /// to `output`.
     /// # Examples
     /// ```
     /// # use libvm::module::*;
@@ -149,25 +412,231 @@ impl Module {
     /// let index = module.new_const("main");
     /// module.push_fn(index, func.clone());
     /// let mut stack = vec![];
-    /// module.call(index, &mut stack);
+    /// module.call(index, &mut stack, 0, &mut std::io::stdout()).unwrap();
     /// ```
-    pub fn call(&self, function: usize, stack: &mut Vec<u8>) -> Vec<u8> {
+    pub fn call(
+        &self,
+        function: usize,
+        stack: &mut dyn Stack,
+        depth: usize,
+        output: &mut dyn Write,
+    ) -> Result<Vec<u8>, VmError> {
+        if depth > self.max_call_depth {
+            return Err(VmError::CallDepthExceeded);
+        }
         let func = self.get_fn(function);
         let mut params = Vec::new();
-        for param in func.params().iter() {
-            let len = match *param {
-                Type::I32 => 4,
-                Type::F32 => 4,
-                Type::Void => 0,
-                Type::String(len) => len,
+        // The last parameter is on top of the stack
+        for param_type in func.params().iter().rev() {
+            let param = match *param_type {
+                Type::String(_) => {
+                    // Popping a string yields it's register layout: the 2 byte
+                    // length followed by the bytes
+                    let hi = stack.pop().ok_or(VmError::StackUnderflow)?;
+                    let lo = stack.pop().ok_or(VmError::StackUnderflow)?;
+                    let len = u16::from_be_bytes([hi, lo]) as usize;
+                    let mut param = vec![hi, lo];
+                    for _ in 0..len {
+                        param.push(stack.pop().ok_or(VmError::StackUnderflow)?);
+                    }
+                    // A sized string param fills it's registers so the params
+                    // after it start at a fixed register
+                    if let Type::String(size @ 2..) = *param_type {
+                        if param.len() > size {
+                            return Err(VmError::StringTooLong {
+                                max: size - 2,
+                                found: len,
+                            });
+                        }
+                        param.resize(size, 0);
+                    }
+                    param
+                }
+                _ => {
+                    let len = match *param_type {
+                        Type::I32 | Type::U32 | Type::F32 => 4,
+                        Type::Bool => 1,
+                        _ => 0,
+                    };
+                    let mut param = (0..len)
+                        .map(|_| stack.pop().ok_or(VmError::StackUnderflow))
+                        .collect::<Result<Vec<u8>, _>>()?;
+                    param.reverse();
+                    param
+                }
             };
-            for _ in 0..len {
-                // params.push(0);
-                params.push(stack.pop().unwrap());
-            }
+            params.push(param);
         }
         params.reverse();
-        func.run(params)
+        let ret = func.try_run_at(params.concat(), depth, output)?;
+        if let Type::String(size @ 2..) = *func.return_type() {
+            if ret.len() > size {
+                return Err(VmError::StringTooLong {
+                    max: size - 2,
+                    found: ret.len() - 2,
+                });
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Loads a module from bytes produced by the object builder
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// let bytes = [0xBB, 0xBB, 0xBB, 0xBB, FORMAT_MAJOR, 0, 0, 0, 0, 0];
+    /// let module = Module::load(&bytes).unwrap();
+    /// assert!(module.borrow().functions().is_empty());
+    /// assert_eq!(Module::load(&[0, 0]), Err(LoadError::BadMagic));
+    /// ```
+    pub fn load(bytes: &[u8]) -> Result<Rc<RefCell<Module>>, LoadError> {
+        let mut reader = ObjReader { bytes, pos: 0 };
+        let (major, _minor) = reader.header()?;
+        if major != FORMAT_MAJOR {
+            return Err(LoadError::UnsupportedVersion {
+                found: major,
+                expected: FORMAT_MAJOR,
+            });
+        }
+
+        let module: Rc<RefCell<Module>> = Default::default();
+        let constants_len = reader.u32()?;
+        module
+            .borrow_mut()
+            .constants
+            .extend(reader.take(constants_len)?);
+
+        while !reader.is_eof() {
+            let index = reader.u32()?;
+            let params_len = reader.u32()?;
+            let mut params = Vec::with_capacity(params_len);
+            for _ in 0..params_len {
+                params.push(reader.vm_type()?);
+            }
+            let return_type = reader.vm_type()?;
+            let locals = reader.u32()?;
+            let program_len = reader.u32()?;
+            let program = reader.take(program_len)?.to_vec();
+            let func =
+                Function::new(program, params, return_type, Rc::clone(&module)).with_locals(locals);
+            module.borrow_mut().push_fn(index, func);
+        }
+        Ok(module)
+    }
+
+    /// Link `other` into `module`.  The constants of `other` are appended and
+    /// the `LDC` and `CALL` operands of it's functions are rebased.  A call in
+    /// either module to a name it doesn't define is resolved to the function
+    /// with that name in the other module.  This takes the module by `Rc`
+    /// because functions keep a handle to the module they are called from.
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// # use libvm::function::Function;
+    /// let module: std::rc::Rc<std::cell::RefCell<Module>> = Default::default();
+    /// let index = module.borrow_mut().new_const("main");
+    /// module.borrow_mut().push_fn(index, Default::default());
+    ///
+    /// let mut other: Module = Default::default();
+    /// let index = other.new_const("main");
+    /// other.push_fn(index, Default::default());
+    /// assert_eq!(
+    ///     Module::merge(&module, &other),
+    ///     Err(LinkError::DuplicateSymbol(String::from("main")))
+    /// );
+    /// ```
+    pub fn merge(module: &Rc<RefCell<Module>>, other: &Module) -> Result<(), LinkError> {
+        let this = module.borrow();
+        let offset = this.constants.len();
+        let duplicate = other
+            .functions
+            .keys()
+            .map(|index| other.constant(*index))
+            .find(|name| this.find_fn(name).is_some());
+        if let Some(name) = duplicate {
+            return Err(LinkError::DuplicateSymbol(
+                String::from_utf8_lossy(name).into_owned(),
+            ));
+        }
+        // Where a call to a function name goes after linking
+        let resolve = |name: &[u8]| {
+            this.find_fn(name)
+                .map(|(index, _)| index)
+                .or_else(|| other.find_fn(name).map(|(index, _)| index + offset))
+        };
+
+        let mut functions = Vec::new();
+        for (index, func) in this.functions.iter() {
+            let program = relocate(func.program(), |op, operand| match op {
+                consts::CALL if !this.functions.contains_key(&operand) => {
+                    resolve(this.constant(operand)).unwrap_or(operand)
+                }
+                _ => operand,
+            })?;
+            functions.push((*index, func.relink(program, Rc::clone(module))));
+        }
+        for (index, func) in other.functions.iter() {
+            let program = relocate(func.program(), |op, operand| match op {
+                consts::CALL => resolve(other.constant(operand)).unwrap_or(operand + offset),
+                _ => operand + offset,
+            })?;
+            functions.push((index + offset, func.relink(program, Rc::clone(module))));
+        }
+        drop(this);
+
+        let mut this = module.borrow_mut();
+        this.constants.extend(&other.constants);
+        for (index, func) in functions {
+            this.push_fn(index, func);
+        }
+        Ok(())
+    }
+
+    /// Reads the version and the number of constants and functions of an object
+    /// file without loading any of its functions
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// let bytes = [0xBB, 0xBB, 0xBB, 0xBB, 0, 0, 0, 0, 0, 4, 0, 2, b'h', b'i'];
+    /// let header = Module::peek_header(&bytes).unwrap();
+    /// assert_eq!(header.constants, 1);
+    /// assert_eq!(header.functions, 0);
+    /// ```
+    pub fn peek_header(bytes: &[u8]) -> Result<ModuleHeader, LoadError> {
+        let mut reader = ObjReader { bytes, pos: 0 };
+        let (major, minor) = reader.header()?;
+
+        let mut constants = 0;
+        let constants_len = reader.u32()?;
+        let mut pool = reader.take(constants_len)?;
+        while !pool.is_empty() {
+            let len = match pool {
+                [hi, lo, ..] => u16::from_be_bytes([*hi, *lo]) as usize,
+                _ => return Err(LoadError::UnexpectedEof),
+            };
+            pool = pool.get(len + 2..).ok_or(LoadError::UnexpectedEof)?;
+            constants += 1;
+        }
+
+        let mut functions = 0;
+        while !reader.is_eof() {
+            reader.u32()?; // Index
+            for _ in 0..reader.u32()? {
+                reader.vm_type()?;
+            }
+            reader.vm_type()?; // Return type
+            reader.u32()?; // Locals
+            let program_len = reader.u32()?;
+            reader.take(program_len)?;
+            functions += 1;
+        }
+
+        Ok(ModuleHeader {
+            major,
+            minor,
+            constants,
+            functions,
+        })
     }
 
     /// Returns this module's constants
@@ -179,4 +648,232 @@ impl Module {
     pub fn functions(&self) -> &HashMap<usize, Function> {
         &self.functions
     }
+
+    /// Check that every function's bytecode is well formed before running it:
+    /// each opcode is known and has all of it's operand bytes, jumps land on
+    /// an instruction or the end of the program, and `LDC` and `CALL` indices
+    /// refer to a constant and function of this module.  Functions are checked
+    /// in index order and the first problem found is returned.
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// # use libvm::function::Function;
+    /// # use libvm::consts::*;
+    /// let mut module: Module = Default::default();
+    /// let main = module.new_const("main");
+    /// let hello = module.new_const("hello");
+    /// let program = vec![LDC, 0, hello as u8, VIRTUAL, 0x02, GOTO, 0, 8, RET_V];
+    /// module.push_fn(main, Function::new(program, vec![], Default::default(), Default::default()));
+    /// assert_eq!(module.verify(), Ok(()));
+    ///
+    /// // Jump into the middle of the `LDC`
+    /// let program = vec![LDC, 0, hello as u8, GOTO, 0, 1];
+    /// module.push_fn(main, Function::new(program, vec![], Default::default(), Default::default()));
+    /// assert_eq!(
+    ///     module.verify(),
+    ///     Err(VerifyError::BadJump { function: main, at: 3, target: 1 })
+    /// );
+    ///
+    /// let program = vec![LDC, 0, 1, PUSH_I, 0, 0];
+    /// module.push_fn(main, Function::new(program, vec![], Default::default(), Default::default()));
+    /// assert_eq!(
+    ///     module.verify(),
+    ///     Err(VerifyError::BadConstant { function: main, at: 0, index: 1 })
+    /// );
+    /// ```
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let mut constants = std::collections::HashSet::new();
+        let mut i = 0;
+        while i + 1 < self.constants.len() {
+            constants.insert(i);
+            i += 2 + u16::from_be_bytes([self.constants[i], self.constants[i + 1]]) as usize;
+        }
+
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_unstable_by_key(|(index, _)| **index);
+        for (function, func) in functions {
+            let function = *function;
+            let program = func.program();
+            // Offsets of each instruction, checked against jump targets after
+            // the whole program is walked
+            let mut starts = std::collections::HashSet::new();
+            let mut jumps = Vec::new();
+            let mut at = 0;
+            while at < program.len() {
+                let opcode = program[at];
+                if consts::disassemble_each(opcode).is_none() {
+                    return Err(VerifyError::UnknownOpcode {
+                        function,
+                        at,
+                        opcode,
+                    });
+                }
+                let len = consts::operand_len(opcode);
+                if at + len >= program.len() {
+                    return Err(VerifyError::TruncatedOperand { function, at });
+                }
+                starts.insert(at);
+                if len == 2 {
+                    let index = u16::from_be_bytes([program[at + 1], program[at + 2]]) as usize;
+                    match opcode {
+                        consts::LDC if !constants.contains(&index) => {
+                            return Err(VerifyError::BadConstant {
+                                function,
+                                at,
+                                index,
+                            })
+                        }
+                        consts::CALL if !self.functions.contains_key(&index) => {
+                            return Err(VerifyError::BadFunction {
+                                function,
+                                at,
+                                index,
+                            })
+                        }
+                        op if consts::is_jump(op) => jumps.push((at, index)),
+                        _ => {}
+                    }
+                }
+                at += 1 + len;
+            }
+            for (at, target) in jumps {
+                if target != program.len() && !starts.contains(&target) {
+                    return Err(VerifyError::BadJump {
+                        function,
+                        at,
+                        target,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the functions, bytecode and constants in this module without
+    /// serializing it
+    /// # Examples
+    /// ```
+    /// # use libvm::module::*;
+    /// # use libvm::function::Function;
+    /// # use libvm::consts::*;
+    /// let mut module: Module = Default::default();
+    /// let main = module.new_const("main");
+    /// let double = module.new_const("double");
+    /// let program = vec![PUSH_I, 0, 0, 0, 2, CALL, 0, double as u8, RET_I];
+    /// module.push_fn(main, Function::new(program, vec![], Default::default(), Default::default()));
+    /// let program = vec![LOAD_I, 0, PUSH_I, 0, 0, 0, 2, MUL_I, RET_I];
+    /// module.push_fn(double, Function::new(program, vec![], Default::default(), Default::default()));
+    /// let stats = module.stats();
+    /// assert_eq!(stats.functions, 2);
+    /// assert_eq!(stats.bytecode, 18);
+    /// assert_eq!(stats.constants, module.constants().len());
+    /// assert_eq!(stats.instructions, vec![(main, 3), (double, 4)]);
+    /// ```
+    pub fn stats(&self) -> ModuleStats {
+        let mut instructions: Vec<(usize, usize)> = self
+            .functions
+            .iter()
+            .map(|(index, function)| {
+                let program = function.program();
+                let mut count = 0;
+                let mut i = 0;
+                while i < program.len() {
+                    i += 1 + consts::operand_len(program[i]);
+                    count += 1;
+                }
+                (*index, count)
+            })
+            .collect();
+        instructions.sort_unstable();
+        ModuleStats {
+            functions: self.functions.len(),
+            bytecode: self.functions.values().map(|f| f.program().len()).sum(),
+            constants: self.constants.len(),
+            instructions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::*;
+
+    /// Verify a module with a `hello` constant and a `main` function running
+    /// the program made from the indices of `main` and `hello`
+    fn verify(program: impl FnOnce(u8, u8) -> Vec<u8>) -> (Result<(), VerifyError>, usize) {
+        let mut module: Module = Default::default();
+        let main = module.new_const("main");
+        let hello = module.new_const("hello");
+        let program = program(main as u8, hello as u8);
+        let func = Function::new(program, vec![], Default::default(), Default::default());
+        module.push_fn(main, func);
+        (module.verify(), main)
+    }
+
+    #[test]
+    fn test_verify_truncated_operand() {
+        let (result, main) = verify(|_, _| vec![RET_V, PUSH_I, 0, 0]);
+        let expected = VerifyError::TruncatedOperand {
+            function: main,
+            at: 1,
+        };
+        assert_eq!(result, Err(expected));
+    }
+
+    #[test]
+    fn test_verify_unknown_opcode() {
+        let (result, main) = verify(|_, _| vec![PUSH_B, 1, 0x02, RET_V]);
+        let expected = VerifyError::UnknownOpcode {
+            function: main,
+            at: 2,
+            opcode: 0x02,
+        };
+        assert_eq!(result, Err(expected));
+    }
+
+    #[test]
+    fn test_verify_bad_function() {
+        let (result, _) = verify(|main, _| vec![CALL, 0, main, RET_V]);
+        assert_eq!(result, Ok(()));
+
+        // `hello` is a constant, not a function
+        let (result, main) = verify(|_, hello| vec![PUSH_B, 1, CALL, 0, hello, RET_V]);
+        let expected = VerifyError::BadFunction {
+            function: main,
+            at: 2,
+            index: 6,
+        };
+        assert_eq!(result, Err(expected));
+    }
+
+    #[test]
+    fn test_verify_bad_jump() {
+        // Jumping to the end of the program is fine
+        let (result, _) = verify(|_, _| vec![GOTO, 0, 4, RET_V]);
+        assert_eq!(result, Ok(()));
+
+        let (result, main) = verify(|_, _| vec![GOTO, 0, 5, RET_V]);
+        let expected = VerifyError::BadJump {
+            function: main,
+            at: 0,
+            target: 5,
+        };
+        assert_eq!(result, Err(expected));
+    }
+
+    #[test]
+    fn test_verify_bad_constant() {
+        let (result, _) = verify(|_, hello| vec![LDC, 0, hello, RET_V]);
+        assert_eq!(result, Ok(()));
+
+        // The middle of the `hello` constant
+        let (result, main) = verify(|_, hello| vec![LDC, 0, hello + 1, RET_V]);
+        let expected = VerifyError::BadConstant {
+            function: main,
+            at: 0,
+            index: 7,
+        };
+        assert_eq!(result, Err(expected));
+    }
 }
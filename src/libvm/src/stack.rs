@@ -0,0 +1,105 @@
+use crate::vm::VmError;
+
+/// The storage behind a vm's stack.  `Vec<u8>` grows without limit; embedders
+/// can supply a fixed capacity stack such as [`BoundedStack`](struct.BoundedStack.html)
+/// instead.
+pub trait Stack {
+    /// Push a byte, failing with `StackOverflow` if the stack is full
+    fn push(&mut self, val: u8) -> Result<(), VmError>;
+
+    /// Pop the top byte or `None` if the stack is empty
+    fn pop(&mut self) -> Option<u8>;
+
+    /// The bytes on the stack, bottom first
+    fn as_slice(&self) -> &[u8];
+
+    /// Shorten the stack to `len` bytes
+    fn truncate(&mut self, len: usize);
+
+    /// Push every byte of `bytes` in order
+    fn push_all(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        for val in bytes.iter() {
+            self.push(*val)?;
+        }
+        Ok(())
+    }
+
+    /// Remove every byte
+    fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Stack for Vec<u8> {
+    fn push(&mut self, val: u8) -> Result<(), VmError> {
+        Vec::push(self, val);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        Vec::pop(self)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len)
+    }
+}
+
+/// A stack holding at most `capacity` bytes
+/// # Examples
+/// ```
+/// # use libvm::stack::*;
+/// # use libvm::vm::VmError;
+/// let mut stack = BoundedStack::new(1);
+/// assert_eq!(stack.push(1), Ok(()));
+/// assert_eq!(stack.push(2), Err(VmError::StackOverflow));
+/// assert_eq!(stack.as_slice(), &[1]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct BoundedStack {
+    bytes: Vec<u8>,
+    capacity: usize,
+}
+
+impl BoundedStack {
+    pub fn new(capacity: usize) -> BoundedStack {
+        BoundedStack {
+            bytes: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+}
+
+impl Stack for BoundedStack {
+    fn push(&mut self, val: u8) -> Result<(), VmError> {
+        if self.bytes.len() >= self.capacity {
+            return Err(VmError::StackOverflow);
+        }
+        self.bytes.push(val);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        self.bytes.pop()
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.bytes.truncate(len)
+    }
+}
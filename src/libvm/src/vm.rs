@@ -1,23 +1,89 @@
 use crate::consts::*;
-use crate::module::Module;
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::module::{LoadError, Module};
+use crate::stack::Stack;
+use crate::vm_type::{decode_f32, decode_i32, encode_f32, encode_i32, Type};
 use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::io;
-use std::io::Cursor;
+use std::io::{BufRead, Write};
 use std::rc::Rc;
 
+/// Error produced while executing a program
+#[derive(Debug, PartialEq)]
+pub enum VmError {
+    /// An instruction needed more values than the stack holds
+    StackUnderflow,
+    /// A push didn't fit in a fixed capacity stack
+    StackOverflow,
+    /// The program ended in the middle of an instruction
+    EndOfProgram,
+    /// A byte that doesn't correspond to any opcode
+    UnknownOpcode(u8),
+    /// Integer division or modulo by zero
+    DivisionByZero,
+    /// No function with this name exists in the module
+    UnknownFunction(String),
+    /// Function calls nested deeper than the module allows
+    CallDepthExceeded,
+    /// An `ASSERT` popped false
+    AssertionFailed,
+    /// A `VIRTUAL` call with no built-in or registered handler
+    UnknownVirtual(u8),
+    /// A function was called with the wrong number of arguments
+    ArgumentCount { expected: usize, found: usize },
+    /// The argument at `index` isn't a value of the parameter's type
+    ArgumentType { index: usize, expected: Type },
+    /// An instruction read past the end of the registers
+    RegOutOfBounds { reg: usize },
+    /// A string argument is longer than the parameter's registers hold
+    StringTooLong { max: usize, found: usize },
+    /// A string printed by the program isn't valid UTF-8
+    InvalidUtf8,
+    /// An object file couldn't be loaded to run it
+    Load(LoadError),
+    /// An error raised by the instruction generated from this source span
+    At(Box<VmError>, (usize, usize)),
+}
+
+/// Why [`Vm::run_until_break`](struct.Vm.html#method.run_until_break) stopped
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    /// The program counter reached a breakpoint
+    Breakpoint(usize),
+    /// The program returned this value
+    Returned(Vec<u8>),
+}
+
+/// An instruction executed by a vm created `with_trace`
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub opcode: u8,
+    /// The stack right before the instruction was executed
+    pub stack: Vec<u8>,
+}
+
+/// A host function called by `VIRTUAL` with the vm's stack
+pub type VirtualHandler = Box<dyn FnMut(&mut dyn Stack) -> Result<(), VmError>>;
+
 /// A stack based interpreted virtual machine with registers
 pub struct Vm<'a> {
     program: &'a [u8],
     index: usize,
     regs: Vec<u8>,
-    stack: Vec<u8>,
+    stack: Box<dyn Stack>,
     module: Rc<RefCell<Module>>,
     is_debug: bool,
+    reader: Box<dyn BufRead>,
+    output: Box<dyn Write + 'a>,
+    breakpoints: HashSet<usize>,
+    call_depth: usize,
+    trace: Option<Vec<TraceEntry>>,
+    virtuals: HashMap<u8, VirtualHandler>,
 }
 
-impl Vm<'_> {
+impl<'a> Vm<'a> {
     /// Create a new vm with an empty state.  Regs are initialized with the
     /// values passed in the `regs` argument.  These are used to initialize
     /// parameter variables.
@@ -25,96 +91,279 @@ impl Vm<'_> {
     /// # use libvm::vm::Vm;
     /// let vm = Vm::new(&[], Vec::new(), Default::default());
     /// ```
-    pub fn new(program: &[u8], regs: Vec<u8>, module: Rc<RefCell<Module>>) -> Vm {
+    pub fn new(program: &'a [u8], regs: Vec<u8>, module: Rc<RefCell<Module>>) -> Vm<'a> {
         Vm {
             program,
             index: 0,
             regs,
-            stack: Vec::new(),
+            stack: Box::new(Vec::new()),
             module,
             is_debug: std::env::var("VIMIB_DEBUG").is_ok(),
+            reader: Box::new(io::BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+            breakpoints: HashSet::new(),
+            call_depth: 0,
+            trace: None,
+            virtuals: HashMap::new(),
+        }
+    }
+
+    /// Handle `VIRTUAL code` with `handler`.  The built-in codes can't be
+    /// replaced.
+    /// ```
+    /// # use libvm::vm::Vm;
+    /// # use libvm::consts::*;
+    /// let mut vm = Vm::new(&[PUSH_B, 1, VIRTUAL, 0x20], Vec::new(), Default::default());
+    /// vm.register_virtual(0x20, Box::new(|stack| {
+    ///     stack.clear();
+    ///     Ok(())
+    /// }));
+    /// vm.run();
+    /// assert!(vm.stack().is_empty());
+    /// ```
+    pub fn register_virtual(&mut self, code: u8, handler: VirtualHandler) {
+        self.virtuals.insert(code, handler);
+    }
+
+    /// Start over with a new program and registers, keeping the allocations of
+    /// this vm.  Everything a program can observe is the same as in a fresh vm.
+    /// ```
+    /// # use libvm::vm::Vm;
+    /// # use libvm::consts::*;
+    /// let mut vm = Vm::new(&[PUSH_I, 0, 0, 0, 1], Vec::new(), Default::default());
+    /// vm.run();
+    /// vm.reset(&[LOAD_I, 0, RET_I], vec![0, 0, 0, 2]);
+    /// assert_eq!(vm.run(), vec![0, 0, 0, 2]);
+    /// ```
+    pub fn reset(&mut self, program: &'a [u8], regs: Vec<u8>) {
+        self.program = program;
+        self.index = 0;
+        self.regs = regs;
+        self.stack.clear();
+        self.breakpoints.clear();
+        self.call_depth = 0;
+        if let Some(trace) = &mut self.trace {
+            trace.clear();
         }
     }
 
+    /// Use `stack` for the stack instead of a `Vec` that grows without limit.
+    /// It is cleared first.
+    /// ```
+    /// # use libvm::vm::*;
+    /// # use libvm::consts::*;
+    /// # use libvm::stack::BoundedStack;
+    /// let mut vm = Vm::new(&[PUSH_I, 0, 0, 0, 1], Vec::new(), Default::default())
+    ///     .with_stack(Box::new(BoundedStack::new(2)));
+    /// assert_eq!(vm.try_run(), Err(VmError::StackOverflow));
+    /// ```
+    pub fn with_stack(mut self, mut stack: Box<dyn Stack>) -> Self {
+        stack.clear();
+        self.stack = stack;
+        self
+    }
+
+    /// Take the stack to reuse it's allocation in another vm
+    pub(crate) fn into_stack(self) -> Box<dyn Stack> {
+        self.stack
+    }
+
+    /// Set the number of calls in progress when this vm is started.  Calls
+    /// made by the program are checked against the module's maximum depth.
+    /// ```
+    /// # use libvm::vm::Vm;
+    /// let vm = Vm::new(&[], Vec::new(), Default::default()).with_call_depth(3);
+    /// ```
+    pub fn with_call_depth(mut self, call_depth: usize) -> Self {
+        self.call_depth = call_depth;
+        self
+    }
+
+    /// Replace the reader used by input builtins (stdin by default).
+    /// ```
+    /// # use libvm::vm::Vm;
+    /// # use libvm::consts::*;
+    /// let program = &[
+    ///     VIRTUAL, 0x10,
+    ///     RET_I
+    /// ];
+    /// let mut vm = Vm::new(program, Vec::new(), Default::default())
+    ///     .with_reader(Box::new(std::io::Cursor::new("42\n")));
+    /// let out = vm.run();
+    /// assert_eq!(out, vec![0, 0, 0, 42]);
+    /// ```
+    pub fn with_reader(mut self, reader: Box<dyn BufRead>) -> Self {
+        self.reader = reader;
+        self
+    }
+
+    /// Replace the writer used by print builtins (stdout by default).  Calls
+    /// made by the program print to the same writer.
+    /// ```
+    /// # use libvm::vm::Vm;
+    /// # use libvm::consts::*;
+    /// let program = &[PUSH_I, 0, 0, 0, 42, VIRTUAL, 0x00];
+    /// let mut out = Vec::new();
+    /// Vm::new(program, Vec::new(), Default::default())
+    ///     .with_output(Box::new(&mut out))
+    ///     .run();
+    /// assert_eq!(out, b"42\n");
+    /// ```
+    pub fn with_output(mut self, output: Box<dyn Write + 'a>) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Record every executed instruction.  Unlike `VIMIB_DEBUG` this doesn't
+    /// print or wait for input; the log is read with `trace` afterwards.
+    /// ```
+    /// # use libvm::vm::Vm;
+    /// # use libvm::consts::*;
+    /// let mut vm = Vm::new(&[PUSH_B, 1, RET, 1], Vec::new(), Default::default()).with_trace();
+    /// vm.run();
+    /// assert_eq!(vm.trace()[1].stack, vec![1]);
+    /// ```
+    pub fn with_trace(mut self) -> Self {
+        self.trace = Some(Vec::new());
+        self
+    }
+
+    /// Returns the instructions executed so far, or nothing if the vm wasn't
+    /// created `with_trace`
+    pub fn trace(&self) -> &[TraceEntry] {
+        self.trace.as_deref().unwrap_or(&[])
+    }
+
+    /// Returns the current contents of the stack
+    pub fn stack(&self) -> &[u8] {
+        self.stack.as_slice()
+    }
+
+    /// Returns the current contents of the registers
+    pub fn regs(&self) -> &[u8] {
+        &self.regs
+    }
+
+    /// Returns the index of the next instruction to be executed
+    pub fn pc(&self) -> usize {
+        self.index
+    }
+
     /// Goto the next instruction / byte
-    fn next(&mut self) -> u8 {
-        let ret = self.program[self.index];
+    fn next(&mut self) -> Result<u8, VmError> {
+        let ret = *self.program.get(self.index).ok_or(VmError::EndOfProgram)?;
         self.index += 1;
-        ret
+        Ok(ret)
     }
 
-    /// Get the current instruction / byte
-    fn current(&self) -> u8 {
-        self.program[self.index]
+    /// Consumes a 2 byte big-endian index
+    fn next_u16(&mut self) -> Result<usize, VmError> {
+        Ok(u16::from_be_bytes([self.next()?, self.next()?]) as usize)
     }
 
     /// Consumes 4 bytes of instructions
-    fn next_int(&mut self) -> [u8; 4] {
-        let mut out = [self.next(), self.next(), self.next(), self.next()];
-        out.reverse();
-        out
+    fn next_int(&mut self) -> Result<[u8; 4], VmError> {
+        Ok([self.next()?, self.next()?, self.next()?, self.next()?])
     }
 
     /// Push a byte onto the stack
-    fn push(&mut self, v: u8) {
+    fn push(&mut self, v: u8) -> Result<(), VmError> {
         self.stack.push(v)
     }
 
     /// Push a 32 bit number as 4 bytes onto the stack
-    fn push_32(&mut self, v: [u8; 4]) {
-        for i in v.iter() {
-            self.stack.push(*i);
-        }
+    fn push_32(&mut self, v: [u8; 4]) -> Result<(), VmError> {
+        self.stack.push_all(&v)
     }
 
     /// Push an f32 onto the stack
-    fn push_f32(&mut self, v: f32) {
-        let mut a = vec![];
-        a.write_f32::<BigEndian>(v).unwrap();
-        self.stack.extend(a.iter());
+    fn push_f32(&mut self, v: f32) -> Result<(), VmError> {
+        self.push_32(encode_f32(v))
     }
 
     /// Push an i32 onto the stack
-    fn push_i32(&mut self, v: i32) {
-        let mut a = vec![];
-        a.write_i32::<LittleEndian>(v).unwrap();
-        self.stack.extend(a.iter());
+    fn push_i32(&mut self, v: i32) -> Result<(), VmError> {
+        self.push_32(encode_i32(v))
+    }
+
+    /// Push a u32 onto the stack
+    fn push_u32(&mut self, v: u32) -> Result<(), VmError> {
+        self.push_32(v.to_be_bytes())
     }
 
     /// Pop a byte from the stack
-    fn pop(&mut self) -> u8 {
-        self.stack.pop().unwrap()
+    fn pop(&mut self) -> Result<u8, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow)
+    }
+
+    /// Pop the 2 byte length prefix of a string off the stack
+    fn pop_len(&mut self) -> Result<usize, VmError> {
+        let hi = self.pop()?;
+        let lo = self.pop()?;
+        Ok(u16::from_be_bytes([hi, lo]) as usize)
+    }
+
+    /// Pop a length prefixed string off the stack
+    fn pop_str(&mut self) -> Result<Vec<u8>, VmError> {
+        let len = self.pop_len()?;
+        let mut val = Vec::with_capacity(len);
+        for _ in 0..len {
+            val.push(self.pop()?);
+        }
+        Ok(val)
+    }
+
+    /// Push a string onto the stack followed by it's length prefix
+    fn push_str(&mut self, val: &[u8]) -> Result<(), VmError> {
+        for v in val.iter().rev() {
+            self.push(*v)?;
+        }
+        let [hi, lo] = (val.len() as u16).to_be_bytes();
+        self.push(lo)?;
+        self.push(hi)
+    }
+
+    /// Write a line to the output
+    fn print(&mut self, line: impl std::fmt::Display) {
+        writeln!(self.output, "{}", line).expect("Couldn't write output");
     }
 
     /// Pop 4 bytes off the stack
-    fn pop_32(&mut self) -> [u8; 4] {
-        let mut out = [self.pop(), self.pop(), self.pop(), self.pop()];
+    fn pop_32(&mut self) -> Result<[u8; 4], VmError> {
+        let mut out = [self.pop()?, self.pop()?, self.pop()?, self.pop()?];
         out.reverse();
-        out
+        Ok(out)
     }
 
     /// Pop an 32 bit num in the form of an f32 off the stack
-    fn pop_f32(&mut self) -> f32 {
-        let array = self.pop_32();
-        let mut rdr = Cursor::new(Vec::from(&array as &[u8]));
-        rdr.read_f32::<BigEndian>().unwrap()
+    fn pop_f32(&mut self) -> Result<f32, VmError> {
+        Ok(decode_f32(self.pop_32()?))
     }
 
     /// Pop an int in the form of an i32 off the stack
-    fn pop_i32(&mut self) -> i32 {
-        let array = self.pop_32();
-        let mut rdr = Cursor::new(Vec::from(&array as &[u8]));
-        rdr.read_i32::<LittleEndian>().unwrap()
+    fn pop_i32(&mut self) -> Result<i32, VmError> {
+        Ok(decode_i32(self.pop_32()?))
+    }
+
+    /// Get `len` bytes of registers starting at `reg`
+    fn regs_at(&self, reg: usize, len: usize) -> Result<&[u8], VmError> {
+        self.regs
+            .get(reg..reg + len)
+            .ok_or(VmError::RegOutOfBounds { reg })
+    }
+
+    /// Pop an int in the form of a u32 off the stack
+    fn pop_u32(&mut self) -> Result<u32, VmError> {
+        Ok(u32::from_be_bytes(self.pop_32()?))
     }
 
     /// Get an int in the form of an array from the stack
-    fn get_int(&self) -> [u8; 4] {
+    fn get_int(&self) -> Result<[u8; 4], VmError> {
+        let stack = self.stack.as_slice();
+        let start = stack.len().checked_sub(4).ok_or(VmError::StackUnderflow)?;
         let mut out = [0; 4];
-        for (i, v) in out.iter_mut().enumerate() {
-            *v = *self.stack.get(self.stack.len() - i - 1).unwrap();
-        }
-        out.reverse();
-        out
+        out.copy_from_slice(&stack[start..]);
+        Ok(out)
     }
 
     /// Run the program and return a vector of bytes containing a returned
@@ -130,66 +379,169 @@ impl Vm<'_> {
     /// ];
     /// let mut vm = Vm::new(program, Vec::new(), Default::default());
     /// let out = vm.run();
-    /// assert_eq!(out, vec![11, 0, 0, 0]);
+    /// assert_eq!(out, vec![0, 0, 0, 11]);
     /// ```
     pub fn run(&mut self) -> Vec<u8> {
-        while self.index < self.program.len() {
-            if let Some(ret) = self.execute() {
-                return ret;
+        self.try_run().unwrap_or_else(|err| panic!("{:?}", err))
+    }
+
+    /// Run the program like `run` but return an error instead of panicking
+    /// ```
+    /// # use libvm::vm::*;
+    /// # use libvm::consts::*;
+    /// let mut vm = Vm::new(&[ADD_I], Vec::new(), Default::default());
+    /// assert_eq!(vm.try_run(), Err(VmError::StackUnderflow));
+    /// ```
+    pub fn try_run(&mut self) -> Result<Vec<u8>, VmError> {
+        loop {
+            if let Some(ret) = self.step()? {
+                return Ok(ret);
             }
         }
-        vec![]
     }
 
+    /// Stop `run_until_break` when the program counter reaches `pc`
+    pub fn set_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Remove a breakpoint set with `set_breakpoint`
+    pub fn clear_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// Run until the program counter reaches a breakpoint or the program
+    /// returns.  Breakpoints are checked after each instruction so calling
+    /// this again continues past the breakpoint that was hit.
+    /// ```
+    /// # use libvm::vm::*;
+    /// # use libvm::consts::*;
+    /// let program = &[PUSH_I, 0, 0, 0, 5, DUP_I, RET_I];
+    /// let mut vm = Vm::new(program, Vec::new(), Default::default());
+    /// vm.set_breakpoint(5);
+    /// assert_eq!(vm.run_until_break(), Ok(StopReason::Breakpoint(5)));
+    /// assert_eq!(vm.run_until_break(), Ok(StopReason::Returned(vec![0, 0, 0, 5])));
+    /// ```
+    pub fn run_until_break(&mut self) -> Result<StopReason, VmError> {
+        loop {
+            if let Some(ret) = self.step()? {
+                return Ok(StopReason::Returned(ret));
+            }
+            if self.breakpoints.contains(&self.index) {
+                return Ok(StopReason::Breakpoint(self.index));
+            }
+        }
+    }
+
+    /// Execute a single instruction.  Returns the returned value once the
+    /// program returns or runs off the end (empty in that case), otherwise
+    /// `None`.
+    /// ```
+    /// # use libvm::vm::Vm;
+    /// # use libvm::consts::*;
+    /// let program = &[PUSH_I, 0, 0, 0, 5, DUP_I, RET_I];
+    /// let mut vm = Vm::new(program, Vec::new(), Default::default());
+    /// assert_eq!(vm.step(), Ok(None));
+    /// assert_eq!((vm.pc(), vm.stack()), (5, &[0, 0, 0, 5][..]));
+    /// assert_eq!(vm.step(), Ok(None));
+    /// assert_eq!(vm.step(), Ok(Some(vec![0, 0, 0, 5])));
+    /// ```
     #[allow(clippy::cognitive_complexity)] // TODO: split this function up
-    fn execute(&mut self) -> Option<Vec<u8>> {
+    pub fn step(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        if self.index >= self.program.len() {
+            return Ok(Some(vec![]));
+        }
+
         macro_rules! ordering {
             ($a: expr) => {{
-                let location = self.next();
-                let v = self.pop();
+                let location = self.next_u16()?;
+                let v = self.pop()?;
                 if v == $a {
-                    self.index = location as usize;
+                    self.index = location;
                 }
             }};
             ($a: expr, $b: expr) => {{
-                let location = self.next();
-                let v = self.pop();
+                let location = self.next_u16()?;
+                let v = self.pop()?;
                 if v == $a || v == $b {
-                    self.index = location as usize;
+                    self.index = location;
                 }
             }};
         }
         macro_rules! binary_operator {
-			(i$op: tt) => {
+            // Integer arithmetic wraps on overflow
+			(i$op: ident) => {
+				{
+					let rhs = self.pop_i32()?;
+					let lhs = self.pop_i32()?;
+					self.push_i32(lhs.$op(rhs))?;
+				}
+            };
+			(idiv$op: ident) => {
 				{
-					let rhs = self.pop_i32();
-					let lhs = self.pop_i32();
-					self.push_i32(lhs $op rhs);
+					let rhs = self.pop_i32()?;
+					let lhs = self.pop_i32()?;
+					if rhs == 0 {
+						return Err(VmError::DivisionByZero);
+					}
+					self.push_i32(lhs.$op(rhs))?;
+				}
+            };
+			(u$op: ident) => {
+				{
+					let rhs = self.pop_u32()?;
+					let lhs = self.pop_u32()?;
+					self.push_u32(lhs.$op(rhs))?;
+				}
+            };
+			(udiv$op: ident) => {
+				{
+					let rhs = self.pop_u32()?;
+					let lhs = self.pop_u32()?;
+					if rhs == 0 {
+						return Err(VmError::DivisionByZero);
+					}
+					self.push_u32(lhs.$op(rhs))?;
 				}
             };
             (f$op: tt) => {
 				{
-					let rhs = self.pop_f32();
-                    let lhs = self.pop_f32();
-					self.push_f32(lhs $op rhs);
+					let rhs = self.pop_f32()?;
+                    let lhs = self.pop_f32()?;
+					self.push_f32(lhs $op rhs)?;
 				}
             };
 			(ib$op: tt) => {
 				{
-					let rhs = self.pop_i32();
-					let lhs = self.pop_i32();
-					self.push((lhs $op rhs) as u8);
+					let rhs = self.pop_i32()?;
+					let lhs = self.pop_i32()?;
+					self.push((lhs $op rhs) as u8)?;
+				}
+            };
+			(ub$op: tt) => {
+				{
+					let rhs = self.pop_u32()?;
+					let lhs = self.pop_u32()?;
+					self.push((lhs $op rhs) as u8)?;
 				}
             };
 			(fb$op: tt) => {
 				{
-					let rhs = self.pop_f32();
-					let lhs = self.pop_f32();
-					self.push((lhs $op rhs) as u8);
+					let rhs = self.pop_f32()?;
+					let lhs = self.pop_f32()?;
+					self.push((lhs $op rhs) as u8)?;
 				}
 			};
         }
 
+        if let Some(trace) = &mut self.trace {
+            trace.push(TraceEntry {
+                pc: self.index,
+                opcode: self.program[self.index],
+                stack: self.stack.as_slice().to_vec(),
+            });
+        }
+
         if self.is_debug {
             let mut out = String::new();
             let mut program = self.program.iter().enumerate();
@@ -217,54 +569,103 @@ impl Vm<'_> {
                     out.push(' ');
                 }
                 out.push_str("\u{001b}[0m"); // reset
-                match *v {
-                    PUSH_I => push_n!(4),
-                    VIRTUAL
-                    | GOTO
-                    | STO_I
-                    | LOAD_I
-                    | STO_V
-                    | LOAD_V
-                    | LDC
-                    | CALL
-                    | IF_T..=IF_GE => push_n!(1),
-
-                    _ => {}
-                }
+                push_n!(operand_len(*v));
                 out.push('\n');
             }
             println!("{}", out);
-            println!("STACK: {:?}", self.stack);
+            println!("STACK: {:?}", self.stack.as_slice());
             println!("REGS:  {:?}", self.regs);
             let mut input = String::new();
             io::stdin()
                 .read_line(&mut input)
                 .expect("Couldn't read line");
         }
-        match self.next() {
+        match self.next()? {
+            NOP => {}
             PUSH_I => {
-                let val = self.next_int();
-                self.push_32(val);
-            }
-            ADD_I => binary_operator!(i+),
-            SUB_I => binary_operator!(i-),
-            MUL_I => binary_operator!(i*),
-            DIV_I => binary_operator!(i/),
-            MOD_I => binary_operator!(i%),
+                let val = self.next_int()?;
+                self.push_32(val)?;
+            }
+            PUSH_B => {
+                let val = self.next()?;
+                self.push(val)?;
+            }
+            ADD_I => binary_operator!(i wrapping_add),
+            SUB_I => binary_operator!(i wrapping_sub),
+            MUL_I => binary_operator!(i wrapping_mul),
+            DIV_I => binary_operator!(idiv wrapping_div),
+            MOD_I => binary_operator!(idiv wrapping_rem),
+            ADD_U => binary_operator!(u wrapping_add),
+            SUB_U => binary_operator!(u wrapping_sub),
+            MUL_U => binary_operator!(u wrapping_mul),
+            DIV_U => binary_operator!(udiv wrapping_div),
+            MOD_U => binary_operator!(udiv wrapping_rem),
             ADD_F => binary_operator!(f+),
             SUB_F => binary_operator!(f-),
             MUL_F => binary_operator!(f*),
             DIV_F => binary_operator!(f/),
             MOD_F => binary_operator!(f%),
 
+            // Shift amounts are taken modulo 32
+            SHL_I => {
+                let rhs = self.pop_u32()?;
+                let lhs = self.pop_i32()?;
+                self.push_i32(lhs.wrapping_shl(rhs))?;
+            }
+            SHR_I => {
+                let rhs = self.pop_u32()?;
+                let lhs = self.pop_i32()?;
+                self.push_i32(lhs.wrapping_shr(rhs))?;
+            }
+            SHR_U => {
+                let rhs = self.pop_u32()?;
+                let lhs = self.pop_u32()?;
+                self.push_u32(lhs.wrapping_shr(rhs))?;
+            }
+            XOR_I => {
+                let rhs = self.pop_i32()?;
+                let lhs = self.pop_i32()?;
+                self.push_i32(lhs ^ rhs)?;
+            }
+
             NEG_I => {
-                let n = self.pop_i32();
-                self.push_i32(-n);
+                let n = self.pop_i32()?;
+                self.push_i32(n.wrapping_neg())?;
+            }
+            NEG_F => {
+                let n = self.pop_f32()?;
+                self.push_f32(-n)?;
+            }
+            MIN_I => binary_operator!(i min),
+            MAX_I => binary_operator!(i max),
+            ABS_I => {
+                let n = self.pop_i32()?;
+                self.push_i32(n.wrapping_abs())?;
+            }
+            MIN_F => {
+                let rhs = self.pop_f32()?;
+                let lhs = self.pop_f32()?;
+                self.push_f32(lhs.min(rhs))?;
+            }
+            MAX_F => {
+                let rhs = self.pop_f32()?;
+                let lhs = self.pop_f32()?;
+                self.push_f32(lhs.max(rhs))?;
+            }
+            ABS_F => {
+                let n = self.pop_f32()?;
+                self.push_f32(n.abs())?;
+            }
+
+            ASSERT => {
+                if self.pop()? == 0 {
+                    return Err(VmError::AssertionFailed);
+                }
             }
 
             NOT => {
-                let n = self.pop() != 0;
-                self.push((!n) as u8);
+                let n = self.pop()? != 0;
+                self.push((!n) as u8)?;
             }
 
             NE => binary_operator!(ib!=),
@@ -273,21 +674,80 @@ impl Vm<'_> {
             LT_I => binary_operator!(ib<),
             GE_I => binary_operator!(ib>=),
             LE_I => binary_operator!(ib<=),
+            GT_U => binary_operator!(ub>),
+            LT_U => binary_operator!(ub<),
+            GE_U => binary_operator!(ub>=),
+            LE_U => binary_operator!(ub<=),
             GT_F => binary_operator!(fb>),
             LT_F => binary_operator!(fb<),
             GE_F => binary_operator!(fb>=),
             LE_F => binary_operator!(fb<=),
+            NE_F => binary_operator!(fb!=),
+            EQ_F => binary_operator!(fb==),
+            NEZ => {
+                let n = self.pop_i32()?;
+                self.push((n != 0) as u8)?;
+            }
+            EQZ => {
+                let n = self.pop_i32()?;
+                self.push((n == 0) as u8)?;
+            }
+            BNE => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                self.push((lhs != rhs) as u8)?;
+            }
+            BEQ => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                self.push((lhs == rhs) as u8)?;
+            }
+
+            EQ_V => {
+                let rhs = self.pop_str()?;
+                let lhs = self.pop_str()?;
+                self.push((lhs == rhs) as u8)?;
+            }
+            NE_V => {
+                let rhs = self.pop_str()?;
+                let lhs = self.pop_str()?;
+                self.push((lhs != rhs) as u8)?;
+            }
+            CONCAT => {
+                let rhs = self.pop_str()?;
+                let mut lhs = self.pop_str()?;
+                lhs.extend(rhs);
+                self.push_str(&lhs)?;
+            }
+            I2S => {
+                let val = self.pop_i32()?.to_string();
+                self.push_str(val.as_bytes())?;
+            }
+            F2S => {
+                let val = self.pop_f32()?.to_string();
+                self.push_str(val.as_bytes())?;
+            }
 
             DUP_I => {
-                self.push_32(self.get_int());
+                self.push_32(self.get_int()?)?;
+            }
+            DUP_B => {
+                let val = *self
+                    .stack
+                    .as_slice()
+                    .last()
+                    .ok_or(VmError::StackUnderflow)?;
+                self.push(val)?;
+            }
+            POP_B => {
+                self.pop()?;
             }
             GOTO => {
-                let location = self.next();
-                self.index = location as usize;
+                self.index = self.next_u16()?;
             }
-            STO_I => {
-                let reg = self.next() as usize;
-                let val = self.pop_32();
+            STO_I | STO_F => {
+                let reg = self.next()? as usize;
+                let val = self.pop_32()?;
                 if self.regs.len() <= reg + 3 {
                     for v in val.iter() {
                         self.regs.push(*v);
@@ -298,77 +758,138 @@ impl Vm<'_> {
                     }
                 }
             }
-            LOAD_I => {
-                let reg = self.next() as usize;
-                for i in 0..4 {
-                    self.push(self.regs[reg + i]);
+            LOAD_I | LOAD_F => {
+                let reg = self.next()? as usize;
+                let val = self.regs_at(reg, 4)?.to_vec();
+                self.stack.push_all(&val)?;
+            }
+            STO_B => {
+                let reg = self.next()? as usize;
+                let val = self.pop()?;
+                if self.regs.len() <= reg {
+                    self.regs.push(val);
+                } else {
+                    *self.regs.get_mut(reg).unwrap() = val;
                 }
             }
+            LOAD_B => {
+                let reg = self.next()? as usize;
+                let val = self.regs_at(reg, 1)?[0];
+                self.push(val)?;
+            }
             STO_V => {
-                let reg = self.next() as usize;
-                let len = self.pop() as usize;
-                if self.regs.len() <= reg + len + 1 {
-                    self.regs.push(len as u8);
-                    for _ in 0..len {
-                        let v = self.pop();
-                        self.regs.push(v);
-                    }
-                } else {
-                    *self.regs.get_mut(reg).unwrap() = len as u8;
-                    for i in 0..len {
-                        let v = self.pop();
-                        *self.regs.get_mut(reg + i + 1).unwrap() = v;
-                    }
+                let reg = self.next()? as usize;
+                let len = self.pop_len()?;
+                if self.regs.len() < reg + len + 2 {
+                    self.regs.resize(reg + len + 2, 0);
+                }
+                self.regs[reg..reg + 2].copy_from_slice(&(len as u16).to_be_bytes());
+                for i in 0..len {
+                    self.regs[reg + i + 2] = self.pop()?;
                 }
             }
             LOAD_V => {
-                let reg = self.next() as usize;
-                let len = self.regs[reg] as usize;
-                for i in 0..=len {
-                    self.push(self.regs[reg + len - i]);
-                }
+                let reg = self.next()? as usize;
+                let len = self.regs_at(reg, 2)?;
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                let val: Vec<u8> = self.regs_at(reg, len + 2)?.iter().rev().copied().collect();
+                self.stack.push_all(&val)?;
             }
             CALL => {
-                let index = self.next() as usize;
-                let ret = self.module.borrow().call(index, &mut self.stack);
-                self.stack.extend(ret.iter());
+                let index = self.next_u16()?;
+                let module = self.module.borrow();
+                let depth = self.call_depth + 1;
+                let ret = module.call(index, self.stack.as_mut(), depth, self.output.as_mut())?;
+                let is_str = matches!(module.get_fn(index).return_type(), Type::String(_));
+                drop(module);
+                // Strings are returned in their register layout
+                if is_str {
+                    self.push_str(ret.get(2..).ok_or(VmError::StackUnderflow)?)?;
+                } else {
+                    self.stack.push_all(&ret)?;
+                }
             }
             VIRTUAL => {
-                let call = self.next();
+                let call = self.next()?;
                 match call {
-                    0x00 => println!("{}", self.pop_i32()),
-                    0x01 => println!("STACK: {:?}\nREGS: {:?}", self.stack, self.regs),
+                    0x00 => {
+                        let val = self.pop_i32()?;
+                        self.print(val);
+                    }
+                    0x01 => {
+                        let (stack, regs) = (self.stack.as_slice(), &self.regs);
+                        let dump = format!("STACK: {:?}\nREGS: {:?}", stack, regs);
+                        self.print(dump);
+                    }
                     0x02 => {
-                        let len = self.pop();
-                        let mut val = Vec::with_capacity(len as usize);
-                        for _ in 0..len {
-                            val.push(self.pop());
-                        }
-                        println!("{}", std::str::from_utf8(val.as_slice()).unwrap());
+                        let val = self.pop_str()?;
+                        let val = std::str::from_utf8(&val).map_err(|_| VmError::InvalidUtf8)?;
+                        self.print(val);
+                    }
+                    0x03 => {
+                        let val = self.pop_f32()?;
+                        self.print(val);
+                    }
+                    0x04 => {
+                        let val = self.pop()? != 0;
+                        self.print(val);
                     }
-                    0x03 => println!("{}", self.pop_f32()),
-                    _ => {}
+                    0x05 => {
+                        let val = self.pop_u32()?;
+                        self.print(val);
+                    }
+                    0x10 => {
+                        let mut input = String::new();
+                        self.reader
+                            .read_line(&mut input)
+                            .expect("Couldn't read line");
+                        let val = input.trim().parse::<i32>().expect("Expected an integer");
+                        self.push_i32(val)?;
+                    }
+                    code => match self.virtuals.get_mut(&code) {
+                        Some(handler) => handler(self.stack.as_mut())?,
+                        None => return Err(VmError::UnknownVirtual(code)),
+                    },
                 }
             }
             LDC => {
-                let index = self.next() as usize;
-                let len = self.module.borrow().constants()[index];
-                let mut constant = Vec::with_capacity(len as usize + 1);
-                for i in 0..=len {
-                    constant.push(self.module.borrow().constants()[index + i as usize])
-                }
-                constant.reverse();
-                self.stack.extend(constant.iter());
+                let index = self.next_u16()?;
+                let module = self.module.borrow();
+                let len = module.constant(index).len();
+                let val: Vec<u8> = module.constants()[index..index + len + 2]
+                    .iter()
+                    .rev()
+                    .copied()
+                    .collect();
+                self.stack.push_all(&val)?;
+            }
+            RET_V => return Ok(Some(vec![])),
+            RET_I => return Ok(Some(Vec::from(&self.pop_32()? as &[u8]))),
+            RET => {
+                let len = self.next()? as usize;
+                let at = self
+                    .stack
+                    .len()
+                    .checked_sub(len)
+                    .ok_or(VmError::StackUnderflow)?;
+                let out = self.stack.as_slice()[at..].to_vec();
+                self.stack.truncate(at);
+                return Ok(Some(out));
+            }
+            RET_S => {
+                let val = self.pop_str()?;
+                let mut out = (val.len() as u16).to_be_bytes().to_vec();
+                out.extend(val);
+                return Ok(Some(out));
             }
-            RET_I => return Some(Vec::from(&self.pop_32() as &[u8])), // TODO: fix return values
             CMP_I => {
-                let a = self.pop_i32();
-                let b = self.pop_i32();
-                self.push(match a.cmp(&b) {
+                let rhs = self.pop_i32()?;
+                let lhs = self.pop_i32()?;
+                self.push(match lhs.cmp(&rhs) {
                     Ordering::Equal => 0x00,
                     Ordering::Greater => 0x01,
                     Ordering::Less => 0x02,
-                })
+                })?
             }
             IF_T => ordering!(0x01),
             IF_F => ordering!(0x00),
@@ -378,8 +899,491 @@ impl Vm<'_> {
             IF_LT => ordering!(0x02),
             IF_LE => ordering!(0x02, 0x00),
             IF_GE => ordering!(0x01, 0x00),
-            _ => panic!("Unknown opcode: {}", self.current()),
+            op => return Err(VmError::UnknownOpcode(op)),
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_constant() {
+        let val = "abcdefghij".repeat(30);
+        let mut module: Module = Default::default();
+        let index = module.new_const(&val);
+        assert_eq!(module.constant(index), val.as_bytes());
+
+        let program = &[LDC, 0, index as u8, STO_V, 0, LOAD_V, 0];
+        let mut vm = Vm::new(program, Vec::new(), Rc::new(RefCell::new(module)));
+        vm.run();
+        let mut expected: Vec<u8> = val.bytes().rev().collect();
+        expected.extend(&[44, 1]); // 300 as big-endian, high byte on top
+        assert_eq!(vm.stack(), expected.as_slice());
+        assert_eq!(vm.pop_len().unwrap(), 300);
+    }
+
+    #[test]
+    fn test_concat() {
+        let mut module: Module = Default::default();
+        let foo = module.new_const("foo");
+        let bar = module.new_const("bar");
+
+        let module = Rc::new(RefCell::new(module));
+
+        let program = &[LDC, 0, foo as u8, LDC, 0, bar as u8, CONCAT];
+        let mut vm = Vm::new(program, Vec::new(), Rc::clone(&module));
+        vm.run();
+        assert_eq!(vm.pop_str().unwrap(), b"foobar");
+        assert!(vm.stack().is_empty());
+
+        let program = &[LDC, 0, foo as u8, LDC, 0, bar as u8, CONCAT, VIRTUAL, 0x02];
+        let mut out = Vec::new();
+        Vm::new(program, Vec::new(), module)
+            .with_output(Box::new(&mut out))
+            .run();
+        assert_eq!(out, b"foobar\n");
+    }
+
+    #[test]
+    fn test_number_to_str() {
+        let program = &[PUSH_I, 0, 0, 0, 42, I2S];
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        vm.run();
+        assert_eq!(vm.stack(), &[b'2', b'4', 2, 0]);
+        assert_eq!(vm.pop_str().unwrap(), b"42");
+
+        let mut program = vec![PUSH_I];
+        program.extend(&encode_f32(-2.5));
+        program.push(F2S);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        vm.run();
+        assert_eq!(vm.pop_str().unwrap(), b"-2.5");
+    }
+
+    #[test]
+    fn test_shift() {
+        let program = &[PUSH_I, 0x80, 0, 0, 0, PUSH_I, 0, 0, 0, 4, SHR_U];
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        vm.run();
+        assert_eq!(vm.stack(), &[0x08, 0, 0, 0]);
+
+        let program = &[PUSH_I, 0x80, 0, 0, 0, PUSH_I, 0, 0, 0, 4, SHR_I];
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        vm.run();
+        assert_eq!(vm.stack(), &[0xf8, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_xor() {
+        let program = &[PUSH_I, 0, 0, 0, 6, PUSH_I, 0, 0, 0, 3, XOR_I];
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        vm.run();
+        assert_eq!(vm.stack(), &[0, 0, 0, 5]);
+    }
+
+    #[test]
+    fn test_reg_out_of_bounds() {
+        let mut vm = Vm::new(&[LOAD_I, 2], vec![0, 0, 0, 1], Default::default());
+        assert_eq!(vm.try_run(), Err(VmError::RegOutOfBounds { reg: 2 }));
+
+        let mut vm = Vm::new(&[LOAD_B, 4], vec![0, 0, 0, 1], Default::default());
+        assert_eq!(vm.try_run(), Err(VmError::RegOutOfBounds { reg: 4 }));
+
+        // A string register whose length runs past the end
+        let mut vm = Vm::new(&[LOAD_V, 0], vec![0, 5, b'a'], Default::default());
+        assert_eq!(vm.try_run(), Err(VmError::RegOutOfBounds { reg: 0 }));
+    }
+
+    #[test]
+    fn test_step() {
+        let program = &[PUSH_I, 0, 0, 0, 2, PUSH_I, 0, 0, 0, 3, ADD_I, POP_B];
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.stack(), &[0, 0, 0, 2]);
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.stack(), &[0, 0, 0, 2, 0, 0, 0, 3]);
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.stack(), &[0, 0, 0, 5]);
+        assert_eq!(vm.pc(), 11);
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.step(), Ok(Some(vec![])));
+
+        let mut vm = Vm::new(&[ADD_I], Vec::new(), Default::default());
+        assert_eq!(vm.step(), Err(VmError::StackUnderflow));
+        let mut vm = Vm::new(&[PUSH_I, 0], Vec::new(), Default::default());
+        assert_eq!(vm.step(), Err(VmError::EndOfProgram));
+        let mut vm = Vm::new(&[0x02], Vec::new(), Default::default());
+        assert_eq!(vm.step(), Err(VmError::UnknownOpcode(0x02)));
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let program = &[
+            PUSH_I, 0, 0, 0, 1, STO_I, 0, // x = 1
+            LOAD_I, 0, PUSH_I, 0, 0, 0, 1, ADD_I, STO_I, 0, // x = x + 1
+            GOTO, 0, 7,
+        ];
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        vm.set_breakpoint(9);
+        assert_eq!(vm.run_until_break(), Ok(StopReason::Breakpoint(9)));
+        assert_eq!(vm.pc(), 9);
+        assert_eq!(vm.stack(), &[0, 0, 0, 1]);
+        assert_eq!(vm.run_until_break(), Ok(StopReason::Breakpoint(9)));
+        assert_eq!(vm.stack(), &[0, 0, 0, 2]);
+
+        vm.clear_breakpoint(9);
+        vm.set_breakpoint(17);
+        assert_eq!(vm.run_until_break(), Ok(StopReason::Breakpoint(17)));
+        assert_eq!(vm.regs(), &[0, 0, 0, 3]);
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn test_float_equality() {
+        let mut program = vec![PUSH_I];
+        program.extend(&encode_f32(0.0));
+        program.push(PUSH_I);
+        program.extend(&encode_f32(-0.0));
+        program.push(EQ_F);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        vm.run();
+        assert_eq!(vm.stack(), &[1]);
+
+        // NaN is not equal to itself
+        let mut program = vec![PUSH_I];
+        program.extend(&encode_f32(0.0));
+        program.push(DUP_I);
+        program.push(DIV_F);
+        program.push(DUP_I);
+        program.push(EQ_F);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        vm.run();
+        assert_eq!(vm.stack(), &[0]);
+    }
+
+    #[test]
+    fn test_int_overflow() {
+        let mut program = vec![PUSH_I];
+        program.extend(&encode_i32(2_000_000_000));
+        program.push(DUP_I);
+        program.push(ADD_I);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        vm.run();
+        assert_eq!(
+            vm.stack(),
+            &encode_i32(2_000_000_000i32.wrapping_add(2_000_000_000))
+        );
+
+        let mut program = vec![PUSH_I];
+        program.extend(&encode_i32(i32::MIN));
+        program.push(PUSH_I);
+        program.extend(&encode_i32(-1));
+        program.push(DIV_I);
+        let mut vm = Vm::new(&program, Vec::new(), Default::default());
+        vm.run();
+        assert_eq!(vm.stack(), &encode_i32(i32::MIN));
+
+        let program = &[PUSH_I, 0, 0, 0, 1, PUSH_I, 0, 0, 0, 0, MOD_I];
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.step(), Err(VmError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_call_mixed_params() {
+        use crate::function::Function;
+        use crate::vm_type::Type;
+
+        let module: Rc<RefCell<Module>> = Default::default();
+        let index = module.borrow_mut().new_const("f");
+        let func = Function::new(
+            vec![LOAD_I, 0, RET_I],
+            vec![Type::I32, Type::Bool],
+            Type::I32,
+            Rc::clone(&module),
+        );
+        module.borrow_mut().push_fn(index, func);
+
+        let mut stack = vec![9, 0, 0, 0, 7, 1];
+        let out = module
+            .borrow()
+            .call(index, &mut stack, 0, &mut std::io::stdout())
+            .unwrap();
+        assert_eq!(out, vec![0, 0, 0, 7]);
+        assert_eq!(stack, vec![9]);
+    }
+
+    #[test]
+    fn test_call_float_and_string_params() {
+        use crate::function::Function;
+        use crate::vm_type::Type;
+
+        let module: Rc<RefCell<Module>> = Default::default();
+        let f = module.borrow_mut().new_const("f");
+        let g = module.borrow_mut().new_const("g");
+        let ab = module.borrow_mut().new_const("ab");
+        let func = Function::new(
+            vec![LOAD_I, 0, RET_I],
+            vec![Type::F32],
+            Type::F32,
+            Rc::clone(&module),
+        );
+        module.borrow_mut().push_fn(f, func);
+        // g(s: str, x: i32) -> i32 { return x }
+        let func = Function::new(
+            vec![LOAD_I, 4, RET_I],
+            vec![Type::String(0), Type::I32],
+            Type::I32,
+            Rc::clone(&module),
+        );
+        module.borrow_mut().push_fn(g, func);
+
+        let program = &[PUSH_I, 0x40, 0x20, 0, 0, CALL, 0, f as u8];
+        let mut vm = Vm::new(program, Vec::new(), Rc::clone(&module));
+        vm.run();
+        assert_eq!(vm.stack(), &encode_f32(2.5));
+
+        let program = &[LDC, 0, ab as u8, PUSH_I, 0, 0, 0, 7, CALL, 0, g as u8];
+        let mut vm = Vm::new(program, Vec::new(), Rc::clone(&module));
+        vm.run();
+        assert_eq!(vm.stack(), &[0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn test_merge() {
+        use crate::function::Function;
+        use crate::vm_type::Type;
+
+        // main() -> i32 { return helper() + 1 } with helper defined elsewhere
+        let module: Rc<RefCell<Module>> = Default::default();
+        let main = module.borrow_mut().new_const("main");
+        let helper = module.borrow_mut().new_const("helper");
+        let func = Function::new(
+            vec![CALL, 0, helper as u8, PUSH_I, 0, 0, 0, 1, ADD_I, RET_I],
+            vec![],
+            Type::I32,
+            Rc::clone(&module),
+        );
+        module.borrow_mut().push_fn(main, func);
+
+        // helper() -> i32 { print_str("hi") return 41 }
+        let other: Rc<RefCell<Module>> = Default::default();
+        let hi = other.borrow_mut().new_const("hi");
+        let helper = other.borrow_mut().new_const("helper");
+        let func = Function::new(
+            vec![LDC, 0, hi as u8, VIRTUAL, 2, PUSH_I, 0, 0, 0, 41, RET_I],
+            vec![],
+            Type::I32,
+            Rc::clone(&other),
+        );
+        other.borrow_mut().push_fn(helper, func);
+
+        let offset = module.borrow().constants().len();
+        Module::merge(&module, &other.borrow()).unwrap();
+        let module = module.borrow();
+        assert_eq!(
+            module.get_fn(helper + offset).program()[2],
+            (hi + offset) as u8
+        );
+        assert_eq!(module.run_function("main", vec![]), Ok(vec![0, 0, 0, 42]));
+    }
+
+    #[test]
+    fn test_call_depth() {
+        use crate::function::Function;
+        use crate::vm_type::Type;
+
+        let module: Rc<RefCell<Module>> = Default::default();
+        module.borrow_mut().set_max_call_depth(8);
+        let index = module.borrow_mut().new_const("f");
+        // f() -> i32 { return f() }
+        let func = Function::new(
+            vec![CALL, 0, index as u8, RET_I],
+            vec![],
+            Type::I32,
+            Rc::clone(&module),
+        );
+        module.borrow_mut().push_fn(index, func);
+
+        let out = module.borrow().run_function("f", vec![]);
+        assert_eq!(out, Err(VmError::CallDepthExceeded));
+    }
+
+    #[test]
+    fn test_wide_return() {
+        use crate::function::Function;
+        use crate::vm_type::Value;
+
+        let module: Rc<RefCell<Module>> = Default::default();
+        let hello = module.borrow_mut().new_const("hello");
+        let greet = module.borrow_mut().new_const("greet");
+        let yes = module.borrow_mut().new_const("yes");
+        let main = module.borrow_mut().new_const("main");
+
+        // greet() -> str { return "hello" }
+        let func = Function::new(
+            vec![LDC, 0, hello as u8, RET_S],
+            vec![],
+            Type::String(7),
+            Rc::clone(&module),
+        );
+        assert_eq!(func.run(vec![]), vec![0, 5, b'h', b'e', b'l', b'l', b'o']);
+        assert_eq!(func.run_typed(vec![]), Value::Str("hello".into()));
+        module.borrow_mut().push_fn(greet, func);
+
+        // yes() -> bool { return true }
+        let func = Function::new(
+            vec![PUSH_I, 0, 0, 0, 9, PUSH_B, 1, RET, 1],
+            vec![],
+            Type::Bool,
+            Rc::clone(&module),
+        );
+        assert_eq!(func.run(vec![]), vec![1]);
+        module.borrow_mut().push_fn(yes, func);
+
+        // main() -> str { return greet() + greet() }
+        let func = Function::new(
+            vec![CALL, 0, greet as u8, CALL, 0, greet as u8, CONCAT, RET_S],
+            vec![],
+            Type::String(12),
+            Rc::clone(&module),
+        );
+        module.borrow_mut().push_fn(main, func);
+        let out = module.borrow().get_fn(main).run_typed(vec![]);
+        assert_eq!(out, Value::Str("hellohello".into()));
+    }
+
+    #[test]
+    fn test_short_string_return() {
+        use crate::function::Function;
+
+        let module: Rc<RefCell<Module>> = Default::default();
+        let empty = module.borrow_mut().new_const("empty");
+        let main = module.borrow_mut().new_const("main");
+
+        // A str function that returns nothing
+        let func = Function::new(vec![RET_V], vec![], Type::String(0), Rc::clone(&module));
+        module.borrow_mut().push_fn(empty, func);
+        let func = Function::new(
+            vec![CALL, 0, empty as u8, RET_S],
+            vec![],
+            Type::String(0),
+            Rc::clone(&module),
+        );
+        module.borrow_mut().push_fn(main, func);
+        let out = module.borrow().get_fn(main).try_run(vec![]);
+        assert_eq!(out, Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_trace() {
+        let program = &[PUSH_I, 0, 0, 0, 2, PUSH_I, 0, 0, 0, 3, ADD_I, RET_I];
+        let mut vm = Vm::new(program, Vec::new(), Default::default()).with_trace();
+        assert_eq!(vm.run(), vec![0, 0, 0, 5]);
+        let trace = vm.trace();
+        let ops: Vec<u8> = trace.iter().map(|entry| entry.opcode).collect();
+        assert_eq!(ops, vec![PUSH_I, PUSH_I, ADD_I, RET_I]);
+        assert_eq!(trace[2].pc, 10);
+        assert_eq!(trace[2].stack, vec![0, 0, 0, 2, 0, 0, 0, 3]);
+
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        vm.run();
+        assert!(vm.trace().is_empty());
+    }
+
+    #[test]
+    fn test_wide_constant_index() {
+        use crate::function::Function;
+        use crate::vm_type::Value;
+
+        let module: Rc<RefCell<Module>> = Default::default();
+        let mut last = 0;
+        for i in 0..300 {
+            last = module.borrow_mut().new_const(&format!("c{}", i));
         }
-        None
+        assert!(last > u8::MAX as usize);
+        let [hi, lo] = (last as u16).to_be_bytes();
+        let func = Function::new(
+            vec![LDC, hi, lo, RET_S],
+            vec![],
+            Type::String(6),
+            Rc::clone(&module),
+        );
+        assert_eq!(func.run_typed(vec![]), Value::Str("c299".into()));
+    }
+
+    #[test]
+    fn test_reset() {
+        let first = &[PUSH_I, 0, 0, 0, 9, PUSH_I, 0, 0, 0, 1, GOTO, 0, 0];
+        let program = &[LOAD_I, 0, PUSH_I, 0, 0, 0, 3, MUL_I, RET_I];
+        let regs = vec![0, 0, 0, 7];
+
+        let mut fresh = Vm::new(program, regs.clone(), Default::default()).with_trace();
+        let expected = fresh.run();
+
+        let mut vm = Vm::new(first, vec![1, 2], Default::default()).with_trace();
+        vm.set_breakpoint(5);
+        vm.run_until_break().unwrap();
+        vm.reset(program, regs);
+        assert!(vm.stack().is_empty());
+        assert!(vm.trace().is_empty());
+        assert_eq!(vm.run(), expected);
+        assert_eq!(vm.trace(), fresh.trace());
+    }
+
+    #[test]
+    fn test_bounded_stack() {
+        use crate::stack::BoundedStack;
+
+        let program = &[PUSH_I, 0, 0, 0, 2, PUSH_I, 0, 0, 0, 3, ADD_I, RET_I];
+        let mut vm = Vm::new(program, Vec::new(), Default::default())
+            .with_stack(Box::new(BoundedStack::new(8)));
+        assert_eq!(vm.try_run(), Ok(vec![0, 0, 0, 5]));
+
+        let mut vm = Vm::new(program, Vec::new(), Default::default())
+            .with_stack(Box::new(BoundedStack::new(7)));
+        assert_eq!(vm.try_run(), Err(VmError::StackOverflow));
+        assert_eq!(vm.stack(), &[0, 0, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_register_virtual() {
+        let program = &[PUSH_I, 0, 0, 0, 21, VIRTUAL, 0x30, RET_I];
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        vm.register_virtual(
+            0x30,
+            Box::new(|stack| {
+                let at = stack.len().checked_sub(4).ok_or(VmError::StackUnderflow)?;
+                let top = stack.as_slice()[at..].to_vec();
+                let val = decode_i32([top[0], top[1], top[2], top[3]]);
+                stack.truncate(at);
+                stack.push_all(&encode_i32(val * 2))
+            }),
+        );
+        assert_eq!(vm.try_run(), Ok(encode_i32(42).to_vec()));
+
+        let mut vm = Vm::new(&[VIRTUAL, 0x31], Vec::new(), Default::default());
+        assert_eq!(vm.try_run(), Err(VmError::UnknownVirtual(0x31)));
+    }
+
+    #[test]
+    fn test_print_utf8() {
+        let mut module: Module = Default::default();
+        let hello = module.new_const("héllo");
+        assert_eq!(module.constant(hello).len(), 6);
+
+        let program = &[LDC, 0, hello as u8, VIRTUAL, 0x02];
+        let mut vm = Vm::new(program, Vec::new(), Rc::new(RefCell::new(module)));
+        assert_eq!(vm.try_run(), Ok(vec![]));
+        assert!(vm.stack().is_empty());
+
+        // Half of the two byte `é`
+        let program = &[PUSH_B, 0xc3, PUSH_B, 1, PUSH_B, 0, VIRTUAL, 0x02];
+        let mut vm = Vm::new(program, Vec::new(), Default::default());
+        assert_eq!(vm.try_run(), Err(VmError::InvalidUtf8));
     }
 }
@@ -1,17 +1,128 @@
+use crate::module::LoadError;
+
+/// Encode an i32 the way it is laid out on the stack and in `PUSH_I` operands
+/// ```
+/// # use libvm::vm_type::*;
+/// assert_eq!(encode_i32(5), [0, 0, 0, 5]);
+/// assert_eq!(decode_i32(encode_i32(-7)), -7);
+/// ```
+pub fn encode_i32(val: i32) -> [u8; 4] {
+    val.to_be_bytes()
+}
+
+/// Decode an i32 encoded with `encode_i32`
+pub fn decode_i32(bytes: [u8; 4]) -> i32 {
+    i32::from_be_bytes(bytes)
+}
+
+/// Encode an f32 the way it is laid out on the stack and in `PUSH_I` operands
+/// ```
+/// # use libvm::vm_type::*;
+/// assert_eq!(decode_f32(encode_f32(2.5)), 2.5);
+/// ```
+pub fn encode_f32(val: f32) -> [u8; 4] {
+    val.to_be_bytes()
+}
+
+/// Decode an f32 encoded with `encode_f32`
+pub fn decode_f32(bytes: [u8; 4]) -> f32 {
+    f32::from_be_bytes(bytes)
+}
+
+/// A value returned by the vm decoded using it's type
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Int(i32),
+    UInt(u32),
+    Float(f32),
+    Bool(bool),
+    Str(String),
+    Void,
+}
+
+impl Value {
+    /// Decode bytes returned by the vm as a value of type `t`.  Numbers use the
+    /// last 4 bytes and strings use their register layout (a 2 byte length
+    /// followed by the bytes), keeping whatever bytes are there if the input is
+    /// shorter than the length says.
+    /// ```
+    /// # use libvm::vm_type::*;
+    /// assert_eq!(Value::decode(&Type::I32, &[0, 0, 0, 5]), Value::Int(5));
+    /// assert_eq!(Value::decode(&Type::String(4), &[0, 2, b'h', b'i']), Value::Str("hi".into()));
+    /// assert_eq!(Value::decode(&Type::String(4), &[0, 5, b'h', b'i']), Value::Str("hi".into()));
+    /// assert_eq!(Value::decode(&Type::String(0), &[0]), Value::Str("".into()));
+    /// assert_eq!(Value::decode(&Type::Void, &[]), Value::Void);
+    /// ```
+    pub fn decode(t: &Type, bytes: &[u8]) -> Value {
+        let word = || {
+            let mut word = [0; 4];
+            let len = bytes.len().min(4);
+            word[4 - len..].copy_from_slice(&bytes[bytes.len() - len..]);
+            word
+        };
+        match t {
+            Type::I32 => Value::Int(decode_i32(word())),
+            Type::U32 => Value::UInt(u32::from_be_bytes(word())),
+            Type::F32 => Value::Float(decode_f32(word())),
+            Type::Bool => Value::Bool(bytes.last().is_some_and(|b| *b != 0)),
+            Type::String(_) => {
+                let len = match bytes.get(..2) {
+                    Some(&[hi, lo]) => u16::from_be_bytes([hi, lo]) as usize,
+                    _ => 0,
+                };
+                let body = bytes.get(2..).unwrap_or(&[]);
+                let body = body.get(..len).unwrap_or(body);
+                Value::Str(String::from_utf8_lossy(body).into_owned())
+            }
+            Type::Void => Value::Void,
+        }
+    }
+
+    /// Encode the value the way it is laid out in registers, returning `None`
+    /// if it isn't a value of type `t`.  A string fits any string type it
+    /// isn't longer than and is zero padded to the type's size.
+    /// ```
+    /// # use libvm::vm_type::*;
+    /// assert_eq!(Value::Int(5).encode(&Type::I32), Some(vec![0, 0, 0, 5]));
+    /// assert_eq!(Value::Str("hi".into()).encode(&Type::String(0)), Some(vec![0, 2, b'h', b'i']));
+    /// assert_eq!(Value::Str("hi".into()).encode(&Type::String(5)), Some(vec![0, 2, b'h', b'i', 0]));
+    /// assert_eq!(Value::Str("hello".into()).encode(&Type::String(5)), None);
+    /// assert_eq!(Value::Int(5).encode(&Type::F32), None);
+    /// ```
+    pub fn encode(&self, t: &Type) -> Option<Vec<u8>> {
+        match (self, t) {
+            (Value::Int(val), Type::I32) => Some(encode_i32(*val).to_vec()),
+            (Value::UInt(val), Type::U32) => Some(val.to_be_bytes().to_vec()),
+            (Value::Float(val), Type::F32) => Some(encode_f32(*val).to_vec()),
+            (Value::Bool(val), Type::Bool) => Some(vec![*val as u8]),
+            (Value::Str(val), Type::String(size)) => {
+                let mut out = (val.len() as u16).to_be_bytes().to_vec();
+                out.extend(val.as_bytes());
+                if *size > 0 {
+                    if out.len() > *size {
+                        return None;
+                    }
+                    out.resize(*size, 0);
+                }
+                Some(out)
+            }
+            (Value::Void, Type::Void) => Some(vec![]),
+            _ => None,
+        }
+    }
+}
+
 /// Vm type is used by the virtual machine to call functions and determine the
 /// length of types.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum Type {
     I32,
+    #[default]
     Void,
     String(usize),
     F32,
-}
-
-impl std::default::Default for Type {
-    fn default() -> Type {
-        Type::Void
-    }
+    Bool,
+    U32,
 }
 
 impl Type {
@@ -22,8 +133,43 @@ impl Type {
     /// assert_eq!(t.serialize(), vec![0x00]);
     /// ```
     pub fn serialize(&self) -> Vec<u8> {
-        let mut out = Vec::new();
-        out.push(0x00); // TODO: Make this right
-        out
+        match self {
+            Type::I32 => vec![0x00],
+            Type::F32 => vec![0x01],
+            Type::Bool => vec![0x02],
+            Type::Void => vec![0x03],
+            Type::U32 => vec![0x05],
+            Type::String(len) => {
+                let mut out = vec![0x04];
+                out.extend((*len as u32).to_be_bytes().iter());
+                out
+            }
+        }
+    }
+
+    /// Read a type from the start of `bytes` and return it along with the
+    /// number of bytes it took up
+    /// ```
+    /// # use libvm::vm_type::Type;
+    /// let t = Type::String(5);
+    /// assert_eq!(Type::deserialize(&t.serialize()), Ok((t, 5)));
+    /// ```
+    pub fn deserialize(bytes: &[u8]) -> Result<(Type, usize), LoadError> {
+        match bytes.first() {
+            Some(0x00) => Ok((Type::I32, 1)),
+            Some(0x01) => Ok((Type::F32, 1)),
+            Some(0x02) => Ok((Type::Bool, 1)),
+            Some(0x03) => Ok((Type::Void, 1)),
+            Some(0x05) => Ok((Type::U32, 1)),
+            Some(0x04) => match bytes.get(1..5) {
+                Some(len) => {
+                    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+                    Ok((Type::String(len as usize), 5))
+                }
+                None => Err(LoadError::UnexpectedEof),
+            },
+            Some(tag) => Err(LoadError::UnknownType(*tag)),
+            None => Err(LoadError::UnexpectedEof),
+        }
     }
 }
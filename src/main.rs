@@ -3,13 +3,20 @@ use libparser::*;
 
 fn main() {
     static INPUT: &str = include_str!("../example.vimib");
-    let mut gen = OpcodeGenerator::new(INPUT);
-    let ctx = &parse_context::ParseContext::new(INPUT);
+    let mut gen = OpcodeGenerator::new(INPUT).with_printing();
+    let ctx = &parse_context::ParseContext::new(INPUT).with_printing();
     let mut parser = parser::Parser::new(INPUT, ctx);
     let body = parser.parse();
+    if std::env::args().any(|arg| arg == "--dump-ast") {
+        println!("{}", json::ast_to_json(INPUT, &body));
+        return;
+    }
     gen.gen_module(&body);
 
     let module = gen.gen();
     module.borrow().disassemble();
-    module.borrow().run_main();
+    let result = module.borrow().run_function("main", Vec::new());
+    if let Err(err) = result {
+        report_runtime_error(ctx, &err);
+    }
 }